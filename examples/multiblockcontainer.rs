@@ -0,0 +1,38 @@
+use std::path::Path;
+
+extern crate xxl_container as xxl;
+use xxl::io::multiblockcontainer::MultiBlockContainer;
+use xxl::container::{Container, CloneContainer};
+
+fn main() {
+    // The path + prefix of the new multiblockcontainers files
+    let path = Path::new("./mbc");
+
+    // Create a new MultiBlockContainer with block_size = 32. Records longer than a block are stored as a chain of blocks.
+    let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(path, 32).unwrap();
+    println!("Each block stores {} bytes of a record", mbc.payload_size());
+
+    // insert records of different sizes. Each record gets a single id.
+    for i in 1 .. 10 {
+        let record = vec![i as u8; i * 10];
+        let id = mbc.insert(record).unwrap();
+        println!("Inserted a record of {} bytes -> ID = {}", i * 10, id);
+    }
+
+    // get the ids of the records
+    let ids: Vec<u64> = mbc.ids().collect();
+    println!("IDs used by the container: {:?}", ids);
+
+    // records can be read back as a whole
+    let record = mbc.get_clone(ids[4]).unwrap();
+    println!("Record for ID {}: {:?}", ids[4], record);
+
+    // removing a record frees every block of its chain
+    for id in ids {
+        let record = mbc.remove(id).unwrap();
+        println!("Removed {} bytes for ID {}", record.map_or(0, |r| r.len()), id);
+    }
+
+    // clear the container
+    let _ = mbc.clear();
+}
@@ -0,0 +1,146 @@
+use bincode::{self, Options};
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+
+#[cfg(feature = "msgpack")]
+use rmp_serde;
+
+#[cfg(feature = "cbor")]
+use ciborium;
+
+#[cfg(feature = "json")]
+use serde_json;
+
+use super::super::error::{Result};
+
+/// Turns elements into bytes and back. A `SerdeAdapter` stores its elements with a codec, so the format
+/// can be chosen per container.
+///
+/// Blocks read from a `BlockFileContainer` are padded with zeros, so `decode` has to ignore bytes behind
+/// the encoded element.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// bincode with fixed size big endian integers, the format the serde 0.6 based adapter wrote. Existing
+/// containers can be read with it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Bincode;
+
+impl Bincode {
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new().with_big_endian().with_fixint_encoding().allow_trailing_bytes()
+    }
+}
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>> {
+        Ok(try!(Bincode::options().serialize(element)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(try!(Bincode::options().deserialize(bytes)))
+    }
+}
+
+/// MessagePack with named struct fields, so fields can be reordered without breaking stored elements.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>> {
+        Ok(try!(rmp_serde::to_vec_named(element)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(try!(rmp_serde::from_slice(bytes)))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        try!(ciborium::ser::into_writer(element, &mut bytes));
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(try!(ciborium::de::from_reader(bytes)))
+    }
+}
+
+/// JSON text. It never contains a zero byte, so the padding of a block is cut off before parsing.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>> {
+        Ok(try!(serde_json::to_vec(element)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let len = bytes.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+        Ok(try!(serde_json::from_slice(&bytes[..len])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bincode, Codec};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        id: u64,
+        name: String,
+        tags: Vec<Option<u8>>,
+    }
+
+    fn record() -> Record {
+        Record { id: 7, name: String::from("seven"), tags: vec![Some(1), None] }
+    }
+
+    fn check_codec<K: Codec>(codec: K) {
+        let mut bytes = codec.encode(&record()).unwrap();
+        assert_eq!(codec.decode::<Record>(&bytes).unwrap(), record());
+        bytes.extend_from_slice(&[0; 16]);
+        assert_eq!(codec.decode::<Record>(&bytes).unwrap(), record());
+        assert!(codec.decode::<Record>(&bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn bincode() {
+        check_codec(Bincode);
+        // The layout of the serde 0.6 based adapter: big endian integers and u64 lengths.
+        assert_eq!(Bincode.encode(&(1u32, String::from("ab"))).unwrap(), vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2, b'a', b'b']);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        check_codec(super::MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        check_codec(super::Cbor);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        check_codec(super::Json);
+        assert_eq!(super::Json.encode(&record()).unwrap(), br#"{"id":7,"name":"seven","tags":[1,null]}"#.to_vec());
+    }
+}
@@ -0,0 +1,197 @@
+use lz4_flex;
+use miniz_oxide;
+
+use std::io::{Cursor};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use container::{Container, CloneContainer};
+use super::super::error::{ContainerError, Result};
+
+/// Every payload starts with the tag of the codec that produced it followed by the number of stored bytes.
+static PAYLOAD_HEADER_BYTE_SIZE: usize = 1 + 8;
+
+/// The codecs supported by the `CompressionAdapter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionCodec {
+    /// Stores the bytes as they are.
+    Raw,
+    /// DEFLATE with a compression level between 0 and 10.
+    Deflate(u8),
+    /// LZ4 block compression.
+    Lz4,
+}
+
+impl CompressionCodec {
+    fn tag(&self) -> u8 {
+        match *self {
+            CompressionCodec::Raw => 0,
+            CompressionCodec::Deflate(_) => 1,
+            CompressionCodec::Lz4 => 2,
+        }
+    }
+
+    fn compress(&self, element: &[u8]) -> Vec<u8> {
+        match *self {
+            CompressionCodec::Raw => element.to_vec(),
+            CompressionCodec::Deflate(level) => miniz_oxide::deflate::compress_to_vec(element, level),
+            CompressionCodec::Lz4 => lz4_flex::block::compress_prepend_size(element),
+        }
+    }
+}
+
+/// Compresses `element` with `codec`. Falls back to `CompressionCodec::Raw` if the compressed payload would be larger.
+fn encode(codec: CompressionCodec, element: Vec<u8>) -> Vec<u8> {
+    let compressed = codec.compress(&element);
+    let (tag, data) = if compressed.len() < element.len() {
+        (codec.tag(), compressed)
+    }
+    else {
+        (CompressionCodec::Raw.tag(), element)
+    };
+    let mut payload = Vec::with_capacity(PAYLOAD_HEADER_BYTE_SIZE + data.len());
+    payload.push(tag);
+    payload.write_u64::<LittleEndian>(data.len() as u64).expect("can not write to a Vec");
+    payload.extend_from_slice(&data);
+    payload
+}
+
+/// Decompresses a payload written by `encode`. Trailing bytes, e.g. the padding of a block, are ignored.
+fn decode(payload: Vec<u8>) -> Result<Vec<u8>> {
+    let length;
+    {
+        let mut cursor = Cursor::new(&payload);
+        let _ = try!(cursor.read_u8());
+        length = try!(cursor.read_u64::<LittleEndian>()) as usize;
+    }
+    if PAYLOAD_HEADER_BYTE_SIZE + length > payload.len() {
+        return Err(ContainerError::InvalidMetadata);
+    }
+    let data = &payload[PAYLOAD_HEADER_BYTE_SIZE .. PAYLOAD_HEADER_BYTE_SIZE + length];
+    match payload[0] {
+        0 => Ok(data.to_vec()),
+        1 => Ok(try!(miniz_oxide::inflate::decompress_to_vec(data))),
+        2 => Ok(try!(lz4_flex::block::decompress_size_prepended(data))),
+        tag => Err(ContainerError::InvalidCodec(tag)),
+    }
+}
+
+/// Compresses the elements stored in a byte container. Each payload records the codec that produced it,
+/// so containers with payloads of different codecs stay readable.
+pub struct CompressionAdapter<C> {
+    codec: CompressionCodec,
+    container: C,
+}
+
+impl <C> CompressionAdapter<C> {
+    pub fn new(codec: CompressionCodec, container: C) -> CompressionAdapter<C> {
+        CompressionAdapter{codec: codec, container: container}
+    }
+
+    pub fn codec(&self) -> CompressionCodec {
+        self.codec
+    }
+}
+
+impl<'a, C > Container<'a, Vec<u8>> for CompressionAdapter<C> where C: Container<'a, Vec<u8> > {
+    type I = C::I;
+    type IdIterator = C::IdIterator;
+
+    fn ids(&'a mut self) -> Self::IdIterator {
+        self.container.ids()
+    }
+
+    fn reserve(&mut self) -> Result<Self::I> {
+        self.container.reserve()
+    }
+
+    fn clear(&mut self) -> Result<()>{
+        self.container.clear()
+    }
+
+    fn contains(&mut self, id: Self::I) -> Result<bool>{
+        self.container.contains(id)
+    }
+
+    fn update(&mut self, id: Self::I, new_element: Vec<u8>) -> Result<Option<Vec<u8>>>{
+        let new_compressed = encode(self.codec, new_element);
+        if let Some(old) = try!(self.container.update(id, new_compressed)){
+            Ok(Some(try!(decode(old))))
+        }
+        else {
+            Ok(None)
+        }
+    }
+
+    fn remove(&mut self, id: Self::I ) -> Result<Option<Vec<u8>>> {
+        if let Some(old) = try!(self.container.remove(id)){
+            Ok(Some(try!(decode(old))))
+        }
+        else{
+            Ok(None)
+        }
+    }
+}
+
+impl<'a, C > CloneContainer<'a, Vec<u8>> for CompressionAdapter<C> where C: CloneContainer<'a, Vec<u8> > {
+    fn get_clone(&mut self, id: Self::I) -> Result<Option<Vec<u8>>> {
+        if let Some(old) = try!(self.container.get_clone(id)){
+            Ok(Some(try!(decode(old))))
+        }
+        else{
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use container::{Container, CloneContainer};
+    use mem::veccontainer::VecContainer;
+
+    #[test]
+    fn roundtrip() {
+        let repetitive = vec![42u8; 1000];
+        for codec in vec!(CompressionCodec::Raw, CompressionCodec::Deflate(6), CompressionCodec::Lz4) {
+            let mut adapter = CompressionAdapter::new(codec, VecContainer::new());
+            let id = adapter.insert(repetitive.clone()).unwrap();
+            assert_eq!(adapter.get_clone(id).unwrap(), Some(repetitive.clone()));
+            assert_eq!(adapter.remove(id).unwrap(), Some(repetitive.clone()));
+        }
+    }
+
+    #[test]
+    fn compresses() {
+        let repetitive = vec![42u8; 1000];
+        let payload = encode(CompressionCodec::Lz4, repetitive.clone());
+        assert_eq!(payload[0], CompressionCodec::Lz4.tag());
+        assert!(payload.len() < repetitive.len());
+    }
+
+    #[test]
+    fn falls_back_to_raw() {
+        let short = vec!(1u8, 2, 3);
+        let payload = encode(CompressionCodec::Deflate(10), short.clone());
+        assert_eq!(payload[0], CompressionCodec::Raw.tag());
+        assert_eq!(payload.len(), PAYLOAD_HEADER_BYTE_SIZE + short.len());
+        assert_eq!(decode(payload).unwrap(), short);
+    }
+
+    #[test]
+    fn ignores_padding() {
+        let mut payload = encode(CompressionCodec::Lz4, vec![7u8; 100]);
+        payload.extend_from_slice(&[0u8; 16]);
+        assert_eq!(decode(payload).unwrap(), vec![7u8; 100]);
+    }
+
+    #[test]
+    fn mixed_codecs() {
+        let repetitive = vec![42u8; 1000];
+        let mut adapter = CompressionAdapter::new(CompressionCodec::Deflate(6), VecContainer::new());
+        let id = adapter.insert(repetitive.clone()).unwrap();
+        let mut adapter = CompressionAdapter::new(CompressionCodec::Lz4, adapter.container);
+        assert_eq!(adapter.update(id, repetitive.clone()).unwrap(), Some(repetitive.clone()));
+        assert_eq!(adapter.get_clone(id).unwrap(), Some(repetitive));
+    }
+}
@@ -1,4 +1,4 @@
-use container::{Container, CloneContainer};
+use container::{Container, CloneContainer, AsyncContainer, AsyncCloneContainer, ContainerFuture, map};
 use super::super::error::{Result};
 
 pub struct ConverterAdapter<A, B, C>{
@@ -53,6 +53,21 @@ impl<'a, A, B, AE, BE, C > Container<'a, AE> for ConverterAdapter<A, B, C> where
         }
     }
 
+    fn insert_many(&mut self, elements: Vec<AE>) -> Result<Vec<Self::I>> {
+        let converted = elements.into_iter().map(|element| (self.into_fn)(element)).collect();
+        self.container.insert_many(converted)
+    }
+
+    fn update_many(&mut self, updates: Vec<(Self::I, AE)>) -> Result<Vec<Option<AE>>> {
+        let converted = updates.into_iter().map(|(id, element)| (id, (self.into_fn)(element))).collect();
+        let old_elements = try!(self.container.update_many(converted));
+        Ok(old_elements.into_iter().map(|old_element| old_element.map(|be| (self.from_fn)(be))).collect())
+    }
+
+    fn remove_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<AE>>> {
+        let old_elements = try!(self.container.remove_many(ids));
+        Ok(old_elements.into_iter().map(|old_element| old_element.map(|be| (self.from_fn)(be))).collect())
+    }
 }
 
 impl <'a, A, B, AE, BE, C> CloneContainer<'a, AE> for ConverterAdapter<A, B, C> where A: Fn(AE) -> BE, B: Fn(BE) -> AE, C: CloneContainer<'a, BE> {
@@ -65,4 +80,49 @@ impl <'a, A, B, AE, BE, C> CloneContainer<'a, AE> for ConverterAdapter<A, B, C>
             Ok(None)
         }
     }
+
+    fn get_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<AE>>> {
+        let elements = try!(self.container.get_many(ids));
+        Ok(elements.into_iter().map(|element| element.map(|be| (self.from_fn)(be))).collect())
+    }
+}
+
+// The futures outlive the borrow of the adapter, so `from_fn` is cloned into them.
+impl<A, B, AE, BE, C> AsyncContainer<AE> for ConverterAdapter<A, B, C>
+    where A: Fn(AE) -> BE, B: Fn(BE) -> AE + Clone + Send + Unpin + 'static, AE: 'static, BE: 'static, C: AsyncContainer<BE> {
+    type I = C::I;
+
+    fn insert(&self, element: AE) -> ContainerFuture<Self::I> {
+        self.container.insert((self.into_fn)(element))
+    }
+
+    fn reserve(&self) -> ContainerFuture<Self::I> {
+        self.container.reserve()
+    }
+
+    fn clear(&self) -> ContainerFuture<()> {
+        self.container.clear()
+    }
+
+    fn contains(&self, id: Self::I) -> ContainerFuture<bool> {
+        self.container.contains(id)
+    }
+
+    fn remove(&self, id: Self::I) -> ContainerFuture<Option<AE>> {
+        let from_fn = self.from_fn.clone();
+        map(self.container.remove(id), move |be| Ok(be.map(from_fn)))
+    }
+
+    fn update(&self, id: Self::I, new_element: AE) -> ContainerFuture<Option<AE>> {
+        let from_fn = self.from_fn.clone();
+        map(self.container.update(id, (self.into_fn)(new_element)), move |be| Ok(be.map(from_fn)))
+    }
+}
+
+impl<A, B, AE, BE, C> AsyncCloneContainer<AE> for ConverterAdapter<A, B, C>
+    where A: Fn(AE) -> BE, B: Fn(BE) -> AE + Clone + Send + Unpin + 'static, AE: 'static, BE: 'static, C: AsyncCloneContainer<BE> {
+    fn get_clone(&self, id: Self::I) -> ContainerFuture<Option<AE>> {
+        let from_fn = self.from_fn.clone();
+        map(self.container.get_clone(id), move |be| Ok(be.map(from_fn)))
+    }
 }
@@ -1,25 +1,80 @@
-use bincode;
 use serde;
 
+use std::future::ready;
 use std::marker::{PhantomData};
 
-use container::{Container, CloneContainer};
+use container::{Container, CloneContainer, AsyncContainer, AsyncCloneContainer, ContainerFuture, map};
+use super::codec::{Bincode, Codec};
+use super::versioned::Versioned;
 use super::super::error::{Result};
 
 
-
-pub struct SerdeAdapter<E, C> {
+/// Stores serde serializable elements in a container of byte vectors. The elements are encoded with a
+/// `Codec`, `Bincode` unless another one is chosen with `with_codec`.
+pub struct SerdeAdapter<E, C, K = Bincode> {
     container: C,
+    codec: K,
     phantom: PhantomData<E>,
 }
 
 impl <E, C> SerdeAdapter<E, C> {
     pub fn new(container: C) -> SerdeAdapter<E, C> {
-        SerdeAdapter{container: container, phantom: PhantomData}
+        SerdeAdapter::with_codec(container, Bincode)
+    }
+}
+
+impl <E, C, K> SerdeAdapter<E, C, K> {
+    pub fn with_codec(container: C, codec: K) -> SerdeAdapter<E, C, K> {
+        SerdeAdapter{container: container, codec: codec, phantom: PhantomData}
+    }
+
+    pub fn codec(&self) -> &K {
+        &self.codec
     }
 }
 
-impl<'a, AE, C > Container<'a, AE> for SerdeAdapter<AE, C> where AE: serde::ser::Serialize + serde::de::Deserialize,  C: Container<'a, Vec<u8> > {
+/// Number of records `migrate` reads and rewrites at once.
+const MIGRATE_BATCH_SIZE: usize = 256;
+
+impl <E, C, K: Codec> SerdeAdapter<E, C, Versioned<K>> {
+    /// Rewrites every record in `ids()` that was written with an older schema version, so reading it no
+    /// longer runs the upgrades. Returns the number of rewritten records.
+    pub fn migrate<I>(&mut self) -> Result<u64> where I: Copy, C: for<'a> CloneContainer<'a, Vec<u8>, I=I> {
+        let ids: Vec<I> = self.container.ids().collect();
+        let mut migrated = 0;
+        for batch in ids.chunks(MIGRATE_BATCH_SIZE) {
+            let records = try!(self.container.get_many(batch));
+            let mut updates = Vec::new();
+            for (&id, record) in batch.iter().zip(records) {
+                if let Some(record) = record {
+                    if let Some(upgraded) = try!(self.codec.upgrade_record(&record)) {
+                        updates.push((id, upgraded));
+                    }
+                }
+            }
+            migrated += updates.len() as u64;
+            try!(self.container.update_many(updates));
+        }
+        Ok(migrated)
+    }
+}
+
+fn decode_option<AE: serde::de::DeserializeOwned, K: Codec>(codec: &K, element: Option<Vec<u8>>) -> Result<Option<AE>> {
+    match element {
+        Some(element) => Ok(Some(try!(codec.decode(&element)))),
+        None => Ok(None),
+    }
+}
+
+fn decode_all<AE: serde::de::DeserializeOwned, K: Codec>(codec: &K, elements: Vec<Option<Vec<u8>>>) -> Result<Vec<Option<AE>>> {
+    let mut decoded = Vec::with_capacity(elements.len());
+    for element in elements {
+        decoded.push(try!(decode_option(codec, element)));
+    }
+    Ok(decoded)
+}
+
+impl<'a, AE, C, K> Container<'a, AE> for SerdeAdapter<AE, C, K> where AE: serde::ser::Serialize + serde::de::DeserializeOwned, C: Container<'a, Vec<u8> >, K: Codec {
     type I = C::I;
     type IdIterator = C::IdIterator;
 
@@ -41,35 +96,202 @@ impl<'a, AE, C > Container<'a, AE> for SerdeAdapter<AE, C> where AE: serde::ser:
     }
 
     fn update(&mut self, id: Self::I, new_element: AE) -> Result<Option<AE>>{
-        let new_serialized = try!(bincode::serde::serialize(&new_element, bincode::SizeLimit::Infinite));
-        if let Some(old) = try!(self.container.update(id, new_serialized)){
-            let old_deserialized = try!(bincode::serde::deserialize(&old));
-            Ok(Some(old_deserialized))
-        }
-        else {
-            Ok(None)
-        }
+        let new_serialized = try!(self.codec.encode(&new_element));
+        let old = try!(self.container.update(id, new_serialized));
+        decode_option(&self.codec, old)
     }
 
     fn remove(&mut self, id: Self::I ) -> Result<Option<AE>> {
-        if let Some(old_element) = try!(self.container.remove(id)){
-            let old_deserialized = try!(bincode::serde::deserialize(&old_element));
-            Ok(Some(old_deserialized))
+        let old_element = try!(self.container.remove(id));
+        decode_option(&self.codec, old_element)
+    }
+
+    fn insert_many(&mut self, elements: Vec<AE>) -> Result<Vec<Self::I>> {
+        let mut serialized = Vec::with_capacity(elements.len());
+        for element in &elements {
+            serialized.push(try!(self.codec.encode(element)));
         }
-        else{
-            Ok(None)
+        self.container.insert_many(serialized)
+    }
+
+    fn update_many(&mut self, updates: Vec<(Self::I, AE)>) -> Result<Vec<Option<AE>>> {
+        let mut serialized = Vec::with_capacity(updates.len());
+        for &(id, ref element) in &updates {
+            serialized.push((id, try!(self.codec.encode(element))));
         }
+        let old_elements = try!(self.container.update_many(serialized));
+        decode_all(&self.codec, old_elements)
+    }
+
+    fn remove_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<AE>>> {
+        let old_elements = try!(self.container.remove_many(ids));
+        decode_all(&self.codec, old_elements)
     }
 }
 
-impl<'a, AE, C > CloneContainer<'a, AE> for SerdeAdapter<AE, C> where AE: serde::ser::Serialize + serde::de::Deserialize,  C: CloneContainer<'a, Vec<u8> > {
+impl<'a, AE, C, K> CloneContainer<'a, AE> for SerdeAdapter<AE, C, K> where AE: serde::ser::Serialize + serde::de::DeserializeOwned, C: CloneContainer<'a, Vec<u8> >, K: Codec {
     fn get_clone(&mut self, id: Self::I) -> Result<Option<AE>> {
-        if let Some(old_element) = try!(self.container.get_clone(id)){
-            let old_deserialized = try!(bincode::serde::deserialize(&old_element));
-            Ok(Some(old_deserialized))
+        let element = try!(self.container.get_clone(id));
+        decode_option(&self.codec, element)
+    }
+
+    fn get_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<AE>>> {
+        let elements = try!(self.container.get_many(ids));
+        decode_all(&self.codec, elements)
+    }
+}
+
+// The futures outlive the borrow of the adapter, so the codec is cloned into them.
+impl<AE, C, K> AsyncContainer<AE> for SerdeAdapter<AE, C, K>
+    where AE: serde::ser::Serialize + serde::de::DeserializeOwned + Send + 'static, C: AsyncContainer<Vec<u8>>, K: Codec + Clone + Send + Unpin + 'static {
+    type I = C::I;
+
+    fn insert(&self, element: AE) -> ContainerFuture<Self::I> {
+        match self.codec.encode(&element) {
+            Ok(serialized) => self.container.insert(serialized),
+            Err(err) => Box::pin(ready(Err(err))),
+        }
+    }
+
+    fn reserve(&self) -> ContainerFuture<Self::I> {
+        self.container.reserve()
+    }
+
+    fn clear(&self) -> ContainerFuture<()> {
+        self.container.clear()
+    }
+
+    fn contains(&self, id: Self::I) -> ContainerFuture<bool> {
+        self.container.contains(id)
+    }
+
+    fn remove(&self, id: Self::I) -> ContainerFuture<Option<AE>> {
+        let codec = self.codec.clone();
+        map(self.container.remove(id), move |old| decode_option(&codec, old))
+    }
+
+    fn update(&self, id: Self::I, new_element: AE) -> ContainerFuture<Option<AE>> {
+        match self.codec.encode(&new_element) {
+            Ok(serialized) => {
+                let codec = self.codec.clone();
+                map(self.container.update(id, serialized), move |old| decode_option(&codec, old))
+            },
+            Err(err) => Box::pin(ready(Err(err))),
         }
-        else{
-            Ok(None)
+    }
+}
+
+impl<AE, C, K> AsyncCloneContainer<AE> for SerdeAdapter<AE, C, K>
+    where AE: serde::ser::Serialize + serde::de::DeserializeOwned + Send + 'static, C: AsyncCloneContainer<Vec<u8>>, K: Codec + Clone + Send + Unpin + 'static {
+    fn get_clone(&self, id: Self::I) -> ContainerFuture<Option<AE>> {
+        let codec = self.codec.clone();
+        map(self.container.get_clone(id), move |element| decode_option(&codec, element))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::SerdeAdapter;
+    use adapter::codec::{Bincode, Codec};
+    use adapter::versioned::Versioned;
+    use container::{Container, CloneContainer};
+    use io::blockfilecontainer::BlockFileContainer;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    enum Kind {
+        Point,
+        Area { width: u32, height: u32 },
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Shape {
+        name: String,
+        kind: Kind,
+        tags: Vec<String>,
+    }
+
+    fn shapes() -> Vec<Shape> {
+        vec!(Shape { name: String::from("dot"), kind: Kind::Point, tags: Vec::new() },
+             Shape { name: String::from("field"), kind: Kind::Area { width: 3, height: 4 }, tags: vec!(String::from("green")) })
+    }
+
+    fn check_codec<K: Codec>(name: &str, codec: K) {
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(&Path::new("./test_output").join(name), 128).unwrap();
+        let mut adapter = SerdeAdapter::with_codec(bfc, codec);
+        let ids = adapter.insert_many(shapes()).unwrap();
+        assert_eq!(adapter.get_many(&ids).unwrap(), shapes().into_iter().map(Some).collect::<Vec<_>>());
+        let mut renamed = shapes()[0].clone();
+        renamed.name = String::from("spot");
+        assert_eq!(adapter.update(ids[0], renamed.clone()).unwrap(), Some(shapes()[0].clone()));
+        assert_eq!(adapter.get_clone(ids[0]).unwrap(), Some(renamed));
+        assert_eq!(adapter.remove(ids[1]).unwrap(), Some(shapes()[1].clone()));
+        assert_eq!(adapter.get_clone(ids[1]).unwrap(), None);
+    }
+
+    #[test]
+    fn bincode() {
+        check_codec("serde_bincode", ::adapter::codec::Bincode);
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(Path::new("./test_output/serde_default"), 128).unwrap();
+        let mut adapter = SerdeAdapter::new(bfc);
+        let id = adapter.insert(shapes()[1].clone()).unwrap();
+        assert_eq!(adapter.get_clone(id).unwrap(), Some(shapes()[1].clone()));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Named {
+        name: String,
+    }
+
+    #[test]
+    fn versioned() {
+        let path = Path::new("./test_output/serde_versioned");
+        let (ids, reserved) = {
+            let bfc = BlockFileContainer::new_from_prefix_and_block_size(path, 128).unwrap();
+            let mut old = SerdeAdapter::with_codec(bfc, Versioned::new(Bincode));
+            let ids = old.insert_many(vec!(Named { name: String::from("dot") }, Named { name: String::from("field") })).unwrap();
+            (ids, old.reserve().unwrap())
+        };
+
+        // The struct gained fields, the records written before are upgraded when they are read.
+        let bfc = BlockFileContainer::open(path).unwrap();
+        let codec = Versioned::new(Bincode).upgrade(|old: Named| Shape { name: old.name, kind: Kind::Point, tags: Vec::new() });
+        let mut new = SerdeAdapter::with_codec(bfc, codec);
+        assert_eq!(new.get_clone(ids[0]).unwrap(), Some(shapes()[0].clone()));
+
+        let id = new.insert(shapes()[1].clone()).unwrap();
+        assert_eq!(new.migrate().unwrap(), 2);
+        assert_eq!(new.migrate().unwrap(), 0);
+        for &id in &ids {
+            let record = CloneContainer::<Vec<u8>>::get_clone(&mut new.container, id).unwrap().unwrap();
+            assert_eq!(new.codec().record_version(&record).unwrap(), 2);
         }
+        assert_eq!(new.get_clone(ids[1]).unwrap().map(|shape| shape.name), Some(String::from("field")));
+        assert_eq!(new.get_clone(id).unwrap(), Some(shapes()[1].clone()));
+        assert!(new.contains(reserved).unwrap());
+    }
+
+    #[test]
+    fn versioned_latest() {
+        check_codec("serde_versioned_latest", Versioned::new(Bincode));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack() {
+        check_codec("serde_msgpack", ::adapter::codec::MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        check_codec("serde_cbor", ::adapter::codec::Cbor);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        check_codec("serde_json", ::adapter::codec::Json);
     }
 }
@@ -1,4 +1,4 @@
-use bincode;
+use bincode_legacy as bincode;
 use rustc_serialize;
 
 use std::marker::{PhantomData};
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+use std::io;
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::ser::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::codec::Codec;
+use super::super::error::{ContainerError, Result};
+
+/// Size of the schema version in front of every record. It is written little endian, like the version in
+/// the file headers.
+pub const VERSION_SIZE: usize = 4;
+
+type Upgrade<K> = Arc<Fn(&K, &[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// A `Codec` that prefixes every record with the schema version it was written with.
+///
+/// Records are written with the latest version. `upgrade` registers the function that turns a record of
+/// the current latest version into the next one; reading an older record runs it through all upgrades
+/// up to the latest version before it is decoded. The first version is 1, a version of 0 marks a block
+/// that never held a record, e.g. a reserved id of a `BlockFileContainer`.
+///
+/// Records written without the envelope cannot be read by it.
+pub struct Versioned<K> {
+    codec: K,
+    upgrades: Vec<Upgrade<K>>,
+}
+
+impl<K: Clone> Clone for Versioned<K> {
+    fn clone(&self) -> Self {
+        Versioned { codec: self.codec.clone(), upgrades: self.upgrades.clone() }
+    }
+}
+
+impl<K: Codec> Versioned<K> {
+    /// Wraps `codec`, records are written with version 1 until an upgrade is registered.
+    pub fn new(codec: K) -> Versioned<K> {
+        Versioned { codec: codec, upgrades: Vec::new() }
+    }
+
+    /// Registers the upgrade from the current latest version `N` to `N + 1`, which becomes the version
+    /// new records are written with. `f` turns a record decoded as `Old` into its `New` form.
+    pub fn upgrade<Old, New, F>(mut self, f: F) -> Versioned<K>
+        where Old: DeserializeOwned, New: Serialize, F: Fn(Old) -> New + Send + Sync + 'static {
+        self.upgrades.push(Arc::new(move |codec: &K, payload: &[u8]| {
+            let old: Old = try!(codec.decode(payload));
+            codec.encode(&f(old))
+        }));
+        self
+    }
+
+    pub fn codec(&self) -> &K {
+        &self.codec
+    }
+
+    /// The version records are written with.
+    pub fn version(&self) -> u32 {
+        self.upgrades.len() as u32 + 1
+    }
+
+    /// Returns the version of a record, 0 if the block never held one.
+    pub fn record_version(&self, bytes: &[u8]) -> Result<u32> {
+        if bytes.len() < VERSION_SIZE {
+            return Err(ContainerError::Serializer(Box::new(io::Error::new(io::ErrorKind::UnexpectedEof, "record is shorter than its schema version"))));
+        }
+        let mut version = &bytes[..VERSION_SIZE];
+        Ok(try!(version.read_u32::<LittleEndian>()))
+    }
+
+    /// Brings a record to the latest version and returns its payload.
+    fn latest_payload<'b>(&self, bytes: &'b [u8]) -> Result<Cow<'b, [u8]>> {
+        let version = try!(self.record_version(bytes));
+        if version == 0 || version > self.version() {
+            return Err(ContainerError::UnsupportedVersion(version));
+        }
+        let mut payload = Cow::Borrowed(&bytes[VERSION_SIZE..]);
+        for upgrade in &self.upgrades[version as usize - 1..] {
+            payload = Cow::Owned(try!(upgrade(&self.codec, &payload)));
+        }
+        Ok(payload)
+    }
+
+    fn envelope(&self, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(VERSION_SIZE + payload.len());
+        bytes.write_u32::<LittleEndian>(self.version()).expect("can not write to a Vec");
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Rewrites an older record with the latest version. Returns `None` if the record is already up to
+    /// date or the block never held one.
+    pub fn upgrade_record(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        let version = try!(self.record_version(bytes));
+        if version == 0 || version == self.version() {
+            return Ok(None);
+        }
+        let payload = try!(self.latest_payload(bytes));
+        Ok(Some(self.envelope(&payload)))
+    }
+}
+
+impl<K: Codec> Codec for Versioned<K> {
+    fn encode<T: Serialize>(&self, element: &T) -> Result<Vec<u8>> {
+        let payload = try!(self.codec.encode(element));
+        Ok(self.envelope(&payload))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        let payload = try!(self.latest_payload(bytes));
+        self.codec.decode(&payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Versioned;
+    use adapter::codec::{Bincode, Codec};
+    use error::ContainerError;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V1 {
+        name: String,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V2 {
+        name: String,
+        age: u8,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct V3 {
+        name: String,
+        age: u8,
+        email: Option<String>,
+    }
+
+    fn v1() -> Versioned<Bincode> {
+        Versioned::new(Bincode)
+    }
+
+    fn v3() -> Versioned<Bincode> {
+        v1().upgrade(|old: V1| V2 { name: old.name, age: 0 })
+            .upgrade(|old: V2| V3 { name: old.name, age: old.age, email: None })
+    }
+
+    #[test]
+    fn upgrades_on_read() {
+        let old = v1().encode(&V1 { name: String::from("ada") }).unwrap();
+        assert_eq!(&old[..4], &[1, 0, 0, 0]);
+        let mut padded = old.clone();
+        padded.extend_from_slice(&[0; 8]);
+        let expected = V3 { name: String::from("ada"), age: 0, email: None };
+        assert_eq!(v3().decode::<V3>(&padded).unwrap(), expected);
+        assert_eq!(v3().version(), 3);
+
+        let upgraded = v3().upgrade_record(&padded).unwrap().unwrap();
+        assert_eq!(v3().record_version(&upgraded).unwrap(), 3);
+        assert_eq!(v3().decode::<V3>(&upgraded).unwrap(), expected);
+        assert_eq!(v3().upgrade_record(&upgraded).unwrap(), None);
+        assert_eq!(v3().upgrade_record(&[0; 16]).unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_versions() {
+        let newer = v3().encode(&V3 { name: String::from("ada"), age: 36, email: None }).unwrap();
+        match v1().decode::<V1>(&newer) {
+            Err(ContainerError::UnsupportedVersion(3)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match v1().decode::<V1>(&[0; 16]) {
+            Err(ContainerError::UnsupportedVersion(0)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(v1().decode::<V1>(&[0, 1]).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+use container::{Container, CloneContainer, BufferContainer};
+use error::{ContainerError, Result};
+use super::replacement::ReplacementStrategy;
+
+/// Counts the accesses answered by the buffer (hits) and the accesses that had to load a page (misses).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Statistics {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Statistics {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_ratio(&self) -> f64 {
+        if self.accesses() == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / self.accesses() as f64
+    }
+}
+
+#[derive(Debug)]
+struct Page<E> {
+    element: Option<E>,
+    dirty: bool,
+    fix_count: usize,
+}
+
+/// A fixed-capacity page cache in front of a `CloneContainer`. Modified pages are written back to the
+/// underlying container on `flush` and when they are evicted. Pages pinned with `fix` are never evicted.
+///
+/// Dirty pages are not written back on drop. Call `flush_all` or `into_inner` before dropping the buffer.
+pub struct BufferedContainer<I, E, C, S> {
+    container: C,
+    strategy: S,
+    capacity: usize,
+    pages: HashMap<I, Page<E>>,
+    statistics: Statistics,
+}
+
+impl<I, E, C, S> BufferedContainer<I, E, C, S> where I: Copy + Eq + Hash {
+    pub fn new(capacity: usize, strategy: S, container: C) -> BufferedContainer<I, E, C, S> {
+        assert!(capacity > 0, "a buffer needs a capacity of at least one page");
+        BufferedContainer {
+            container: container,
+            strategy: strategy,
+            capacity: capacity,
+            pages: HashMap::with_capacity(capacity),
+            statistics: Statistics::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of pages currently held in the buffer.
+    pub fn buffered(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        self.statistics
+    }
+
+    pub fn reset_statistics(&mut self) {
+        self.statistics = Statistics::default();
+    }
+
+    pub fn strategy(&self) -> &S {
+        &self.strategy
+    }
+
+    pub fn is_buffered(&self, id: I) -> bool {
+        self.pages.contains_key(&id)
+    }
+
+    /// Returns a reference to a buffered element. Use `fix` to make sure the element is buffered.
+    pub fn get(&self, id: I) -> Option<&E> {
+        self.pages.get(&id).and_then(|page| page.element.as_ref())
+    }
+
+    /// Returns a mutable reference to a buffered element and marks its page as modified.
+    pub fn get_mut(&mut self, id: I) -> Option<&mut E> {
+        if let Some(page) = self.pages.get_mut(&id) {
+            if page.element.is_some() {
+                page.dirty = true;
+            }
+            return page.element.as_mut();
+        }
+        None
+    }
+}
+
+impl<'a, I, E, C, S> BufferedContainer<I, E, C, S> where I: Copy + Eq + Hash, E: Clone, C: CloneContainer<'a, E, I=I>, S: ReplacementStrategy<I> {
+
+    /// Writes all modified pages back to the underlying container.
+    pub fn flush_all(&mut self) -> Result<()> {
+        let ids: Vec<I> = self.pages.keys().cloned().collect();
+        for id in ids {
+            try!(self.write_back(id));
+        }
+        Ok(())
+    }
+
+    /// Writes all modified pages back and returns the underlying container.
+    pub fn into_inner(mut self) -> Result<C> {
+        try!(self.flush_all());
+        Ok(self.container)
+    }
+
+    /// Makes sure the page of `id` is buffered. Evicts a page if the buffer is full.
+    fn load(&mut self, id: I) -> Result<()> {
+        if self.pages.contains_key(&id) {
+            self.statistics.hits += 1;
+            self.strategy.access(id);
+            return Ok(());
+        }
+        if !try!(self.container.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        self.statistics.misses += 1;
+        if self.pages.len() >= self.capacity {
+            try!(self.evict());
+        }
+        let element = try!(self.container.get_clone(id));
+        self.pages.insert(id, Page{element: element, dirty: false, fix_count: 0});
+        self.strategy.insert(id);
+        Ok(())
+    }
+
+    fn evict(&mut self) -> Result<()> {
+        let victim = {
+            let pages = &self.pages;
+            self.strategy.victim(|id| pages.get(&id).map_or(false, |page| page.fix_count > 0))
+        };
+        match victim {
+            Some(id) => {
+                try!(self.write_back(id));
+                self.pages.remove(&id);
+                self.strategy.remove(id);
+                Ok(())
+            },
+            None => Err(ContainerError::BufferFull),
+        }
+    }
+
+    fn write_back(&mut self, id: I) -> Result<()> {
+        if let Some(page) = self.pages.get_mut(&id) {
+            if page.dirty {
+                if let Some(ref element) = page.element {
+                    let _ = try!(self.container.update(id, element.clone()));
+                }
+                page.dirty = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, I, E, C, S> Container<'a, E> for BufferedContainer<I, E, C, S> where I: Copy + Eq + Hash, E: Clone, C: CloneContainer<'a, E, I=I>, S: ReplacementStrategy<I> {
+    type I = I;
+    type IdIterator = C::IdIterator;
+
+    fn reserve(&mut self) -> Result<I> {
+        self.container.reserve()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.pages.clear();
+        self.strategy.clear();
+        self.container.clear()
+    }
+
+    fn contains(&mut self, id: I) -> Result<bool> {
+        if self.pages.contains_key(&id) {
+            return Ok(true);
+        }
+        self.container.contains(id)
+    }
+
+    fn ids(&'a mut self) -> Self::IdIterator {
+        self.container.ids()
+    }
+
+    fn update(&mut self, id: I, new_element: E) -> Result<Option<E>> {
+        match self.load(id) {
+            Err(ContainerError::BufferFull) => return self.container.update(id, new_element),
+            result => try!(result),
+        }
+        let page = self.pages.get_mut(&id).expect("page was loaded");
+        page.dirty = true;
+        Ok(mem::replace(&mut page.element, Some(new_element)))
+    }
+
+    fn remove(&mut self, id: I) -> Result<Option<E>> {
+        if self.pages.get(&id).map_or(false, |page| page.fix_count > 0) {
+            return Err(ContainerError::Fixed);
+        }
+        let page = self.pages.remove(&id);
+        self.strategy.remove(id);
+        let removed = try!(self.container.remove(id));
+        match page {
+            Some(page) => Ok(page.element),
+            None => Ok(removed),
+        }
+    }
+}
+
+impl<'a, I, E, C, S> CloneContainer<'a, E> for BufferedContainer<I, E, C, S> where I: Copy + Eq + Hash, E: Clone, C: CloneContainer<'a, E, I=I>, S: ReplacementStrategy<I> {
+    fn get_clone(&mut self, id: I) -> Result<Option<E>> {
+        match self.load(id) {
+            Err(ContainerError::BufferFull) => return self.container.get_clone(id),
+            result => try!(result),
+        }
+        Ok(self.get(id).cloned())
+    }
+}
+
+impl<'a, I, E, C, S> BufferContainer<'a, E> for BufferedContainer<I, E, C, S> where I: Copy + Eq + Hash, E: Clone, C: CloneContainer<'a, E, I=I>, S: ReplacementStrategy<I> {
+    fn flush(&mut self, id: I) -> Result<()> {
+        self.write_back(id)
+    }
+
+    fn unfix(&mut self, id: I) -> Result<()> {
+        match self.pages.get_mut(&id) {
+            Some(ref mut page) if page.fix_count > 0 => {
+                page.fix_count -= 1;
+                Ok(())
+            },
+            _ => Err(ContainerError::InvalidId),
+        }
+    }
+
+    fn fix(&mut self, id: I) -> Result<()> {
+        try!(self.load(id));
+        self.pages.get_mut(&id).expect("page was loaded").fix_count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use container::{Container, CloneContainer, BufferContainer};
+    use buffer::replacement::{ReplacementStrategy, Lru, Fifo, Clock, LruK};
+    use mem::veccontainer::VecContainer;
+    use error::ContainerError;
+
+    #[test]
+    fn get_clone() {
+        let mut vec_c = VecContainer::new();
+        let id = vec_c.insert(1.1).unwrap();
+        let mut buffer = BufferedContainer::new(2, Lru::new(), vec_c);
+        assert_eq!(buffer.get_clone(id).unwrap(), Some(1.1));
+        assert!(buffer.is_buffered(id));
+        assert_eq!(buffer.get(id), Some(&1.1));
+    }
+
+    #[test]
+    fn write_back_on_eviction() {
+        let mut buffer = BufferedContainer::new(2, Lru::new(), VecContainer::new());
+        let one = buffer.insert(1.1).unwrap();
+        let two = buffer.insert(2.2).unwrap();
+        assert_eq!(buffer.buffered(), 2);
+        assert_eq!(buffer.container.get(one), None);
+
+        let three = buffer.insert(3.3).unwrap();
+        assert_eq!(buffer.buffered(), 2);
+        assert!(!buffer.is_buffered(one));
+        assert_eq!(buffer.container.get(one), Some(&1.1));
+
+        let vec_c = buffer.into_inner().unwrap();
+        assert_eq!(vec_c.get(two), Some(&2.2));
+        assert_eq!(vec_c.get(three), Some(&3.3));
+    }
+
+    #[test]
+    fn flush() {
+        let mut buffer = BufferedContainer::new(2, Lru::new(), VecContainer::new());
+        let one = buffer.insert(1.1).unwrap();
+        assert_eq!(buffer.container.get(one), None);
+        buffer.flush(one).unwrap();
+        assert_eq!(buffer.container.get(one), Some(&1.1));
+    }
+
+    #[test]
+    fn fix() {
+        let mut buffer = BufferedContainer::new(1, Lru::new(), VecContainer::new());
+        let one = buffer.insert(1.1).unwrap();
+        let two = buffer.insert(2.2).unwrap();
+        buffer.fix(one).unwrap();
+        *buffer.get_mut(one).unwrap() = 1.5;
+
+        match buffer.fix(two) {
+            Err(ContainerError::BufferFull) => {},
+            _ => panic!("fixed a page in a full buffer"),
+        }
+        match buffer.remove(one) {
+            Err(ContainerError::Fixed) => {},
+            _ => panic!("removed a fixed page"),
+        }
+        assert_eq!(buffer.get_clone(two).unwrap(), Some(2.2));
+        assert!(buffer.is_buffered(one));
+
+        buffer.unfix(one).unwrap();
+        assert!(buffer.unfix(one).is_err());
+        assert_eq!(buffer.get_clone(two).unwrap(), Some(2.2));
+        assert!(!buffer.is_buffered(one));
+        assert_eq!(buffer.container.get(one), Some(&1.5));
+    }
+
+    #[test]
+    fn remove() {
+        let mut buffer = BufferedContainer::new(2, Lru::new(), VecContainer::new());
+        let one = buffer.insert(1.1).unwrap();
+        assert_eq!(buffer.update(one, 1.2).unwrap(), Some(1.1));
+        assert_eq!(buffer.remove(one).unwrap(), Some(1.2));
+        assert!(!buffer.is_buffered(one));
+        assert_eq!(buffer.contains(one).unwrap(), false);
+    }
+
+    #[test]
+    fn blockfilecontainer() {
+        use std::path::Path;
+        use io::blockfilecontainer::BlockFileContainer;
+
+        let prefix = Path::new("./test_output/buffered_bfc_test");
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        let mut buffer = BufferedContainer::new(4, Lru::new(), bfc);
+        let ids: Vec<u64> = (0u8 .. 16).map(|i| buffer.insert(vec!(i; 4)).unwrap()).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(buffer.get_clone(id).unwrap(), Some(vec!(i as u8; 4)));
+        }
+        let bfc = buffer.into_inner().unwrap();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(bfc.get(id).unwrap(), Some(vec!(i as u8; 4)));
+        }
+    }
+
+    fn scan_and_lookup<S>(strategy: S) -> Statistics where S: ReplacementStrategy<usize> {
+        let mut buffer = BufferedContainer::new(4, strategy, VecContainer::new());
+        let ids: Vec<usize> = (0 .. 16).map(|i| buffer.insert(i).unwrap()).collect();
+        buffer.reset_statistics();
+        for _ in 0 .. 4 {
+            // a hot page and a scan over all pages
+            for &id in &ids {
+                assert_eq!(buffer.get_clone(ids[0]).unwrap(), Some(0));
+                assert_eq!(buffer.get_clone(id).unwrap(), Some(id));
+            }
+        }
+        buffer.statistics()
+    }
+
+    #[test]
+    fn statistics() {
+        let lru = scan_and_lookup(Lru::new());
+        let fifo = scan_and_lookup(Fifo::new());
+        let clock = scan_and_lookup(Clock::new());
+        let lru_k = scan_and_lookup(LruK::new(2));
+        for statistics in vec!(lru, fifo, clock, lru_k) {
+            assert_eq!(statistics.accesses(), 128);
+        }
+        // the hot page stays buffered with recency based strategies but not with FIFO.
+        assert!(lru.hits > fifo.hits);
+        assert!(lru_k.hits >= lru.hits);
+        assert!(clock.hits > fifo.hits);
+    }
+}
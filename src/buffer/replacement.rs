@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Decides which page of a buffer is evicted when a new page has to be loaded.
+pub trait ReplacementStrategy<I> {
+    /// Called when a page is loaded into the buffer.
+    fn insert(&mut self, id: I);
+
+    /// Called on every access of a buffered page.
+    fn access(&mut self, id: I);
+
+    /// Called when a page leaves the buffer.
+    fn remove(&mut self, id: I);
+
+    /// Chooses the page to evict. Pages for which `is_fixed` returns `true` must not be chosen.
+    fn victim<F>(&mut self, is_fixed: F) -> Option<I> where F: Fn(I) -> bool;
+
+    fn clear(&mut self);
+}
+
+/// Evicts the least recently used page.
+#[derive(Debug)]
+pub struct Lru<I> {
+    tick: u64,
+    last_access: HashMap<I, u64>,
+    order: BTreeMap<u64, I>,
+}
+
+impl<I> Lru<I> where I: Copy + Eq + Hash {
+    pub fn new() -> Self {
+        Lru {
+            tick: 0,
+            last_access: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<I> ReplacementStrategy<I> for Lru<I> where I: Copy + Eq + Hash {
+    fn insert(&mut self, id: I) {
+        self.access(id);
+    }
+
+    fn access(&mut self, id: I) {
+        self.tick += 1;
+        if let Some(old_tick) = self.last_access.insert(id, self.tick) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(self.tick, id);
+    }
+
+    fn remove(&mut self, id: I) {
+        if let Some(old_tick) = self.last_access.remove(&id) {
+            self.order.remove(&old_tick);
+        }
+    }
+
+    fn victim<F>(&mut self, is_fixed: F) -> Option<I> where F: Fn(I) -> bool {
+        self.order.values().cloned().find(|&id| !is_fixed(id))
+    }
+
+    fn clear(&mut self) {
+        self.last_access.clear();
+        self.order.clear();
+    }
+}
+
+/// Evicts the page that was loaded first.
+#[derive(Debug)]
+pub struct Fifo<I> {
+    queue: VecDeque<I>,
+}
+
+impl<I> Fifo<I> where I: Copy + Eq {
+    pub fn new() -> Self {
+        Fifo { queue: VecDeque::new() }
+    }
+}
+
+impl<I> ReplacementStrategy<I> for Fifo<I> where I: Copy + Eq {
+    fn insert(&mut self, id: I) {
+        self.queue.push_back(id);
+    }
+
+    fn access(&mut self, _: I) {}
+
+    fn remove(&mut self, id: I) {
+        if let Some(position) = self.queue.iter().position(|&queued| queued == id) {
+            self.queue.remove(position);
+        }
+    }
+
+    fn victim<F>(&mut self, is_fixed: F) -> Option<I> where F: Fn(I) -> bool {
+        self.queue.iter().cloned().find(|&id| !is_fixed(id))
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
+
+/// Second chance: a clock hand sweeps over the pages and evicts the first page that was not referenced
+/// since the last sweep. Referenced pages lose their reference bit instead.
+#[derive(Debug)]
+pub struct Clock<I> {
+    frames: Vec<Option<(I, bool)>>,
+    positions: HashMap<I, usize>,
+    free_frames: Vec<usize>,
+    hand: usize,
+}
+
+impl<I> Clock<I> where I: Copy + Eq + Hash {
+    pub fn new() -> Self {
+        Clock {
+            frames: Vec::new(),
+            positions: HashMap::new(),
+            free_frames: Vec::new(),
+            hand: 0,
+        }
+    }
+}
+
+impl<I> ReplacementStrategy<I> for Clock<I> where I: Copy + Eq + Hash {
+    fn insert(&mut self, id: I) {
+        let position = match self.free_frames.pop() {
+            Some(position) => {
+                self.frames[position] = Some((id, true));
+                position
+            },
+            None => {
+                self.frames.push(Some((id, true)));
+                self.frames.len() - 1
+            }
+        };
+        self.positions.insert(id, position);
+    }
+
+    fn access(&mut self, id: I) {
+        if let Some(&position) = self.positions.get(&id) {
+            if let Some((_, ref mut referenced)) = self.frames[position] {
+                *referenced = true;
+            }
+        }
+    }
+
+    fn remove(&mut self, id: I) {
+        if let Some(position) = self.positions.remove(&id) {
+            self.frames[position] = None;
+            self.free_frames.push(position);
+        }
+    }
+
+    fn victim<F>(&mut self, is_fixed: F) -> Option<I> where F: Fn(I) -> bool {
+        // every page is visited at most twice: once to clear its reference bit and once to evict it.
+        for _ in 0 .. 2 * self.frames.len() {
+            let position = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            if let Some((id, ref mut referenced)) = self.frames[position] {
+                if is_fixed(id) {
+                    continue;
+                }
+                if *referenced {
+                    *referenced = false;
+                }
+                else {
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+        self.positions.clear();
+        self.free_frames.clear();
+        self.hand = 0;
+    }
+}
+
+/// Evicts the page with the largest backward distance to its k-th most recent access. Pages with less
+/// than k accesses are evicted first, in least recently used order.
+#[derive(Debug)]
+pub struct LruK<I> {
+    k: usize,
+    tick: u64,
+    history: HashMap<I, VecDeque<u64>>,
+}
+
+impl<I> LruK<I> where I: Copy + Eq + Hash {
+    pub fn new(k: usize) -> Self {
+        assert!(k > 0, "LRU-K needs k of at least one");
+        LruK {
+            k: k,
+            tick: 0,
+            history: HashMap::new(),
+        }
+    }
+}
+
+impl<I> ReplacementStrategy<I> for LruK<I> where I: Copy + Eq + Hash {
+    fn insert(&mut self, id: I) {
+        self.access(id);
+    }
+
+    fn access(&mut self, id: I) {
+        self.tick += 1;
+        let k = self.k;
+        let accesses = self.history.entry(id).or_insert_with(VecDeque::new);
+        if accesses.len() == k {
+            accesses.pop_front();
+        }
+        accesses.push_back(self.tick);
+    }
+
+    fn remove(&mut self, id: I) {
+        self.history.remove(&id);
+    }
+
+    fn victim<F>(&mut self, is_fixed: F) -> Option<I> where F: Fn(I) -> bool {
+        let k = self.k;
+        self.history.iter()
+            .filter(|&(&id, _)| !is_fixed(id))
+            .min_by_key(|&(_, accesses)| {
+                if accesses.len() < k {
+                    (false, accesses.back().cloned().unwrap_or(0))
+                }
+                else {
+                    (true, accesses.front().cloned().unwrap_or(0))
+                }
+            })
+            .map(|(&id, _)| id)
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru() {
+        let mut lru = Lru::new();
+        lru.insert(1);
+        lru.insert(2);
+        lru.insert(3);
+        assert_eq!(lru.victim(|_| false), Some(1));
+        lru.access(1);
+        assert_eq!(lru.victim(|_| false), Some(2));
+        assert_eq!(lru.victim(|id| id == 2), Some(3));
+        lru.remove(2);
+        assert_eq!(lru.victim(|_| false), Some(3));
+        assert_eq!(lru.victim(|_| true), None);
+    }
+
+    #[test]
+    fn fifo() {
+        let mut fifo = Fifo::new();
+        fifo.insert(1);
+        fifo.insert(2);
+        fifo.insert(3);
+        fifo.access(1);
+        assert_eq!(fifo.victim(|_| false), Some(1));
+        assert_eq!(fifo.victim(|id| id == 1), Some(2));
+        fifo.remove(1);
+        assert_eq!(fifo.victim(|_| false), Some(2));
+        assert_eq!(fifo.victim(|_| true), None);
+    }
+
+    #[test]
+    fn clock() {
+        let mut clock = Clock::new();
+        clock.insert(1);
+        clock.insert(2);
+        clock.insert(3);
+        // all pages are referenced, so the first sweep only clears the reference bits.
+        assert_eq!(clock.victim(|_| false), Some(1));
+        clock.remove(1);
+        clock.insert(4);
+        clock.access(2);
+        assert_eq!(clock.victim(|_| false), Some(3));
+        assert_eq!(clock.victim(|id| id == 3), Some(2));
+        assert_eq!(clock.victim(|_| true), None);
+    }
+
+    #[test]
+    fn lru_k() {
+        let mut lru_k = LruK::new(2);
+        lru_k.insert(1);
+        lru_k.insert(2);
+        lru_k.access(1);
+        lru_k.access(2);
+        lru_k.insert(3);
+        // 3 was accessed only once, so its backward 2-distance is infinite.
+        assert_eq!(lru_k.victim(|_| false), Some(3));
+        assert_eq!(lru_k.victim(|id| id == 3), Some(1));
+        lru_k.access(1);
+        lru_k.access(1);
+        assert_eq!(lru_k.victim(|id| id == 3), Some(2));
+        lru_k.remove(2);
+        lru_k.remove(3);
+        assert_eq!(lru_k.victim(|_| false), Some(1));
+        assert_eq!(lru_k.victim(|_| true), None);
+    }
+}
@@ -0,0 +1,283 @@
+use std::any::Any;
+use std::fs::File;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use container::{AsyncContainer, AsyncCloneContainer, ContainerFuture};
+use error::{ContainerError, Result};
+use io::blockfilecontainer::{Block, BlockFileContainer, Id};
+use io::storage::RawAccess;
+use super::sharedcontainer::SharedContainer;
+
+struct Slot<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Completes once a worker of the `IoPool` has stored the result of its job.
+struct Pending<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        let mut slot = self.slot.lock().expect("pending operation poisoned");
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+type Job = Box<FnOnce() + Send>;
+
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => String::from(*message),
+            Err(_) => String::from("unknown panic"),
+        },
+    }
+}
+
+/// A fixed set of threads that run the blocking I/O so the executor threads never wait on it.
+struct IoPool {
+    sender: Option<Mutex<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl IoPool {
+    fn new(threads: usize) -> IoPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1)).map(|_| {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().expect("io pool poisoned").recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                }
+            })
+        }).collect();
+        IoPool { sender: Some(Mutex::new(sender)), workers: workers }
+    }
+
+    fn spawn<T, F>(&self, f: F) -> ContainerFuture<T> where T: Send + 'static, F: FnOnce() -> Result<T> + Send + 'static {
+        let slot = Arc::new(Mutex::new(Slot { result: None, waker: None }));
+        let job_slot = slot.clone();
+        let job: Job = Box::new(move || {
+            // A panicking operation fails its future instead of leaving it pending and taking down the worker.
+            let result = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(result) => result,
+                Err(payload) => Err(ContainerError::Panicked(panic_message(payload))),
+            };
+            let mut slot = job_slot.lock().expect("pending operation poisoned");
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        self.sender.as_ref().expect("io pool shut down").lock().expect("io pool poisoned")
+            .send(job).expect("io pool workers stopped");
+        Box::pin(Pending { slot: slot })
+    }
+}
+
+impl Drop for IoPool {
+    fn drop(&mut self) {
+        // Closing the channel lets the workers finish the queued jobs and exit.
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// An `AsyncContainer` over a `BlockFileContainer`.
+///
+/// Every operation is handed to a pool of I/O threads, so awaiting it never blocks the executor. The
+/// container sits in a `SharedContainer`, so operations on different blocks run in parallel on the pool.
+/// Dropping the container waits for the queued operations.
+pub struct AsyncBlockFileContainer<S: RawAccess + 'static = File> {
+    shared: SharedContainer<BlockFileContainer<S>>,
+    pool: IoPool,
+}
+
+impl<S: RawAccess + 'static> AsyncBlockFileContainer<S> {
+    /// Serves `container` with `threads` I/O threads (at least one).
+    pub fn new(container: BlockFileContainer<S>, threads: usize) -> Self {
+        AsyncBlockFileContainer { shared: SharedContainer::new(container), pool: IoPool::new(threads) }
+    }
+
+    /// A handle for blocking access to the same container.
+    pub fn shared(&self) -> SharedContainer<BlockFileContainer<S>> {
+        self.shared.clone()
+    }
+
+    /// Waits for the queued operations and returns the container, unless other `SharedContainer`
+    /// handles to it are still alive.
+    pub fn into_inner(self) -> ::std::result::Result<BlockFileContainer<S>, SharedContainer<BlockFileContainer<S>>> {
+        drop(self.pool);
+        self.shared.try_unwrap()
+    }
+}
+
+impl<S: RawAccess + 'static> AsyncContainer<Block> for AsyncBlockFileContainer<S> {
+    type I = Id;
+
+    fn insert(&self, element: Block) -> ContainerFuture<Id> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.insert(element))
+    }
+
+    fn reserve(&self) -> ContainerFuture<Id> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.reserve())
+    }
+
+    fn clear(&self) -> ContainerFuture<()> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.clear())
+    }
+
+    fn contains(&self, id: Id) -> ContainerFuture<bool> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.contains(id))
+    }
+
+    fn remove(&self, id: Id) -> ContainerFuture<Option<Block>> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.remove(id))
+    }
+
+    fn update(&self, id: Id, new_element: Block) -> ContainerFuture<Option<Block>> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.update(id, new_element))
+    }
+}
+
+impl<S: RawAccess + 'static> AsyncCloneContainer<Block> for AsyncBlockFileContainer<S> {
+    fn get_clone(&self, id: Id) -> ContainerFuture<Option<Block>> {
+        let shared = self.shared.clone();
+        self.pool.spawn(move || shared.get(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::path::Path;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use super::{AsyncBlockFileContainer, IoPool};
+    use adapter::converter::ConverterAdapter;
+    #[cfg(feature = "serde")]
+    use adapter::serde::SerdeAdapter;
+    use container::{AsyncContainer, AsyncCloneContainer};
+    use error::{ContainerError, Result};
+    use io::blockfilecontainer::BlockFileContainer;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::as_mut(&mut future).poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn container(name: &str) -> AsyncBlockFileContainer {
+        let prefix = Path::new("test_output").join(name);
+        AsyncBlockFileContainer::new(BlockFileContainer::new_from_prefix_and_block_size(&prefix, 8).unwrap(), 2)
+    }
+
+    #[test]
+    fn block_file() {
+        let container = container("async_block_file");
+        let ids: Vec<_> = (0..8u8).map(|i| container.insert(vec![i; 8])).collect();
+        let ids: Vec<_> = ids.into_iter().map(|id| block_on(id).unwrap()).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(block_on(container.get_clone(id)).unwrap(), Some(vec![i as u8; 8]));
+        }
+        assert_eq!(block_on(container.update(ids[0], vec![9; 8])).unwrap(), Some(vec![0; 8]));
+        assert_eq!(block_on(container.remove(ids[0])).unwrap(), Some(vec![9; 8]));
+        assert!(!block_on(container.contains(ids[0])).unwrap());
+        let id = block_on(container.reserve()).unwrap();
+        assert!(block_on(container.contains(id)).unwrap());
+        block_on(container.clear()).unwrap();
+        assert!(!block_on(container.contains(ids[1])).unwrap());
+        let container = container.into_inner().ok().unwrap();
+        assert_eq!(container.used_ids().count(), 0);
+    }
+
+    #[test]
+    fn pending_operations_finish_on_drop() {
+        let container = container("async_drop");
+        let shared = container.shared();
+        for i in 0..16u8 {
+            // The operation is queued when it is called, dropping the future does not cancel it.
+            drop(container.insert(vec![i; 8]));
+        }
+        drop(container);
+        let container = shared.try_unwrap().ok().unwrap();
+        assert_eq!(container.used_ids().count(), 16);
+    }
+
+    #[test]
+    fn panicking_operation_fails() {
+        let pool = IoPool::new(1);
+        match block_on(pool.spawn(|| -> Result<u8> { panic!("broken operation") })) {
+            Err(ContainerError::Panicked(message)) => assert_eq!(message, "broken operation"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The worker survived the panic.
+        assert_eq!(block_on(pool.spawn(|| Ok(7))).unwrap(), 7);
+    }
+
+    #[test]
+    fn converter_adapter() {
+        let converter = ConverterAdapter::new(|e: u64| vec![e as u8; 8], |b: Vec<u8>| b[0] as u64, container("async_converter"));
+        let id = block_on(converter.insert(7)).unwrap();
+        assert_eq!(block_on(converter.get_clone(id)).unwrap(), Some(7));
+        assert_eq!(block_on(converter.update(id, 8)).unwrap(), Some(7));
+        assert_eq!(block_on(converter.remove(id)).unwrap(), Some(8));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_adapter() {
+        let serde = SerdeAdapter::<u64, _>::new(container("async_serde"));
+        let id = block_on(serde.insert(0x0102030405060708)).unwrap();
+        assert_eq!(block_on(serde.get_clone(id)).unwrap(), Some(0x0102030405060708));
+        assert_eq!(block_on(serde.remove(id)).unwrap(), Some(0x0102030405060708));
+        assert_eq!(block_on(serde.get_clone(id)).unwrap(), None);
+    }
+}
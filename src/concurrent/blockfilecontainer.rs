@@ -0,0 +1,286 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Mutex, RwLock};
+
+use container::{ConcurrentContainer, IntoConcurrent};
+use error::{ContainerError, Result};
+use io::bitsetfile::{BitSet, BitSetKind};
+use io::blockfilecontainer::{Block, BlockFileContainer, FreeIds, Id, Parts, WAL_CHECKPOINT_BYTE_SIZE};
+use io::blockfilecontainer::{check_block_len, read_block, write_block, used_file_len};
+use io::storage::{RawAccess, StorageProvider};
+use io::wal::{LogRecord, WriteAheadLog};
+
+/// The number of locks the blocks are spread over. Blocks whose ids are equal modulo this number share
+/// a lock.
+static BLOCK_LOCKS: u64 = 64;
+
+static POISONED: &str = "concurrent block file container poisoned";
+
+/// The parts of a `BlockFileContainer`, each behind its own lock.
+///
+/// An operation locks the block of its id first and holds that lock until it is done. The other locks
+/// are taken in the order of the fields below and are held only while the part is read or changed.
+struct State<S: RawAccess + 'static> {
+    blocks: Vec<RwLock<()>>,
+    free_ids: Mutex<FreeIds<S>>,
+    reserved_bit_map: RwLock<Box<BitSet>>,
+    used_bit_map: RwLock<Box<BitSet>>,
+    container_file: RwLock<S>,
+    wal: Mutex<Option<WriteAheadLog<S>>>,
+    provider: Box<StorageProvider<Storage = S>>,
+    metadata_file: S,
+    bit_set_kind: BitSetKind,
+    block_size: usize,
+    resumed_compaction: Option<BTreeMap<Id, Id>>,
+}
+
+impl<S: RawAccess + 'static> State<S> {
+    fn new(parts: Parts<S>) -> State<S> {
+        State {
+            blocks: (0..BLOCK_LOCKS).map(|_| RwLock::new(())).collect(),
+            free_ids: Mutex::new(parts.free_ids),
+            reserved_bit_map: RwLock::new(parts.reserved_bit_map),
+            used_bit_map: RwLock::new(parts.used_bit_map),
+            container_file: RwLock::new(parts.container_file),
+            wal: Mutex::new(parts.wal),
+            provider: parts.provider,
+            metadata_file: parts.metadata_file,
+            bit_set_kind: parts.bit_set_kind,
+            block_size: parts.block_size,
+            resumed_compaction: parts.resumed_compaction,
+        }
+    }
+
+    fn into_parts(self) -> Parts<S> {
+        Parts {
+            provider: self.provider,
+            container_file: self.container_file.into_inner().expect(POISONED),
+            metadata_file: self.metadata_file,
+            reserved_bit_map: self.reserved_bit_map.into_inner().expect(POISONED),
+            used_bit_map: self.used_bit_map.into_inner().expect(POISONED),
+            bit_set_kind: self.bit_set_kind,
+            free_ids: self.free_ids.into_inner().expect(POISONED),
+            block_size: self.block_size,
+            wal: self.wal.into_inner().expect(POISONED),
+            resumed_compaction: self.resumed_compaction,
+        }
+    }
+
+    fn block(&self, id: Id) -> &RwLock<()> {
+        &self.blocks[(id % BLOCK_LOCKS) as usize]
+    }
+
+    /// Logs a change before it is applied. Does nothing outside of write-ahead log mode.
+    fn log(&self, record: LogRecord) -> Result<()> {
+        if let Some(ref mut wal) = *self.wal.lock().expect(POISONED) {
+            try!(wal.append_all(&[record]));
+        }
+        Ok(())
+    }
+
+    fn wal_is_full(&self) -> bool {
+        self.wal.lock().expect(POISONED).as_ref().map_or(false, |wal| wal.len() > WAL_CHECKPOINT_BYTE_SIZE)
+    }
+
+    fn contains(&self, id: Id) -> Result<bool> {
+        Ok(try!(self.reserved_bit_map.read().expect(POISONED).contains(id)))
+    }
+
+    fn get(&self, id: Id) -> Result<Option<Block>> {
+        let _block = self.block(id).read().expect(POISONED);
+        if !try!(self.contains(id)) || !try!(self.used_bit_map.read().expect(POISONED).contains(id)) {
+            return Ok(None);
+        }
+        let container_file = self.container_file.read().expect(POISONED);
+        Ok(Some(try!(read_block(&*container_file, id, self.block_size))))
+    }
+
+    fn reserve(&self) -> Result<Id> {
+        let mut free_ids = self.free_ids.lock().expect(POISONED);
+        let mut reserved_bit_map = self.reserved_bit_map.write().expect(POISONED);
+        let id = try!(free_ids.next(&**reserved_bit_map));
+        try!(self.log(LogRecord::Reserve(id)));
+        try!(reserved_bit_map.insert(id));
+        free_ids.taken(id);
+        Ok(id)
+    }
+
+    fn update(&self, id: Id, element: Block) -> Result<Option<Block>> {
+        try!(check_block_len(&element, self.block_size));
+        let _block = self.block(id).write().expect(POISONED);
+        if !try!(self.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        try!(self.log(LogRecord::Update(id, Cow::Borrowed(&element))));
+        // The used bit is set before the block is written, so a `remove` that shrinks the file keeps it.
+        let used_was_not_set = try!(self.used_bit_map.write().expect(POISONED).insert(id));
+        let mut container_file = self.container_file.write().expect(POISONED);
+        let old_block = if used_was_not_set {
+            None
+        }
+        else {
+            Some(try!(read_block(&*container_file, id, self.block_size)))
+        };
+        try!(write_block(&mut *container_file, id, &element, self.block_size));
+        Ok(old_block)
+    }
+
+    fn remove(&self, id: Id) -> Result<Option<Block>> {
+        let _block = self.block(id).write().expect(POISONED);
+        if !try!(self.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        try!(self.log(LogRecord::Remove(id)));
+        // The block is read while its used bit is still set, so no other `remove` has cut it off the file.
+        let old_block = {
+            let used_bit_map = self.used_bit_map.read().expect(POISONED);
+            if try!(used_bit_map.contains(id)) {
+                let container_file = self.container_file.read().expect(POISONED);
+                Some(try!(read_block(&*container_file, id, self.block_size)))
+            }
+            else {
+                None
+            }
+        };
+        {
+            let mut free_ids = self.free_ids.lock().expect(POISONED);
+            let mut reserved_bit_map = self.reserved_bit_map.write().expect(POISONED);
+            try!(reserved_bit_map.remove(id));
+            try!(free_ids.release(id, &**reserved_bit_map));
+        }
+        if old_block.is_some() {
+            // The used bit set stays locked until the file is shrunk, so no block becomes used behind the
+            // new end in between.
+            let mut used_bit_map = self.used_bit_map.write().expect(POISONED);
+            try!(used_bit_map.remove(id));
+            let mut container_file = self.container_file.write().expect(POISONED);
+            try!(container_file.set_len(used_file_len(&**used_bit_map, self.block_size)));
+        }
+        Ok(old_block)
+    }
+}
+
+/// A `BlockFileContainer` whose operations lock single blocks and bit sets instead of the whole
+/// container, which is how a `SharedContainer` serves it.
+///
+/// Every block id has a read-write lock, shared with the ids that are equal modulo 64. `get` holds it for
+/// reading, `update` and `remove` for writing, so they only wait for operations on the same block. The
+/// reserved and used bit sets, the free list, the `.ctr` file and the write-ahead log each have their own
+/// lock, which is held only while they are touched; reads and writes of the `.ctr` file are positional.
+///
+/// `clear`, `sync` and the checkpoint of a full write-ahead log need the whole container and wait until
+/// all other operations are done.
+pub struct ConcurrentBlockFileContainer<S: RawAccess + 'static = File> {
+    state: RwLock<Option<State<S>>>,
+}
+
+impl<S: RawAccess + 'static> ConcurrentBlockFileContainer<S> {
+    pub fn new(container: BlockFileContainer<S>) -> Self {
+        ConcurrentBlockFileContainer { state: RwLock::new(Some(State::new(container.into_parts()))) }
+    }
+
+    /// Returns the container, which writes its metadata when it is dropped.
+    pub fn into_inner(mut self) -> BlockFileContainer<S> {
+        let state = self.take_state().expect("concurrent block file container taken apart");
+        BlockFileContainer::from_parts(state.into_parts())
+    }
+
+    fn take_state(&mut self) -> Option<State<S>> {
+        match self.state.get_mut() {
+            Ok(state) => state.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+
+    /// Runs `f` while other operations may run, too.
+    fn shared<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&State<S>) -> Result<T> {
+        let state = self.state.read().expect(POISONED);
+        f(state.as_ref().expect("concurrent block file container taken apart"))
+    }
+
+    /// Runs `f` on the whole `BlockFileContainer` once all other operations are done.
+    fn exclusive<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&mut BlockFileContainer<S>) -> Result<T> {
+        let mut state = self.state.write().expect(POISONED);
+        let parts = state.take().expect("concurrent block file container taken apart").into_parts();
+        let mut bfc = BlockFileContainer::from_parts(parts);
+        let result = f(&mut bfc);
+        *state = Some(State::new(bfc.into_parts()));
+        result
+    }
+
+    /// Takes a checkpoint before a change if the write-ahead log has grown too large.
+    fn checkpoint_if_full(&self) -> Result<()> {
+        if try!(self.shared(|state| Ok(state.wal_is_full()))) {
+            try!(self.exclusive(|bfc| bfc.sync()));
+        }
+        Ok(())
+    }
+
+    /// Like `BlockFileContainer::sync`.
+    pub fn sync(&self) -> Result<()> {
+        self.exclusive(|bfc| bfc.sync())
+    }
+}
+
+impl<S: RawAccess + 'static> Drop for ConcurrentBlockFileContainer<S> {
+    fn drop(&mut self) {
+        if let Some(state) = self.take_state() {
+            drop(BlockFileContainer::from_parts(state.into_parts()));
+        }
+    }
+}
+
+impl<S: RawAccess + 'static> ConcurrentContainer for ConcurrentBlockFileContainer<S> {
+    type I = Id;
+    type E = Block;
+
+    fn insert(&self, element: Block) -> Result<Id> {
+        try!(self.checkpoint_if_full());
+        self.shared(|state| {
+            try!(check_block_len(&element, state.block_size));
+            let id = try!(state.reserve());
+            let _ = try!(state.update(id, element));
+            Ok(id)
+        })
+    }
+
+    fn reserve(&self) -> Result<Id> {
+        try!(self.checkpoint_if_full());
+        self.shared(|state| state.reserve())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.exclusive(|bfc| bfc.clear())
+    }
+
+    fn contains(&self, id: Id) -> Result<bool> {
+        self.shared(|state| state.contains(id))
+    }
+
+    fn get(&self, id: Id) -> Result<Option<Block>> {
+        self.shared(|state| state.get(id))
+    }
+
+    fn update(&self, id: Id, new_element: Block) -> Result<Option<Block>> {
+        try!(self.checkpoint_if_full());
+        self.shared(|state| state.update(id, new_element))
+    }
+
+    fn remove(&self, id: Id) -> Result<Option<Block>> {
+        try!(self.checkpoint_if_full());
+        self.shared(|state| state.remove(id))
+    }
+}
+
+impl<S: RawAccess + 'static> IntoConcurrent for BlockFileContainer<S> {
+    type Concurrent = ConcurrentBlockFileContainer<S>;
+
+    fn into_concurrent(self) -> ConcurrentBlockFileContainer<S> {
+        ConcurrentBlockFileContainer::new(self)
+    }
+
+    fn from_concurrent(container: ConcurrentBlockFileContainer<S>) -> Self {
+        container.into_inner()
+    }
+}
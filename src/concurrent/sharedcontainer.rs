@@ -0,0 +1,241 @@
+use std::sync::{Arc, RwLock};
+
+use container::{Container, SharedReadContainer, ConcurrentContainer, IntoConcurrent};
+use error::Result;
+use mem::veccontainer::VecContainer;
+
+/// A cloneable, thread-safe handle to a container. Clones of the handle share the same container.
+///
+/// The container is turned into its `IntoConcurrent::Concurrent` form, which decides how much the
+/// operations lock: a `BlockFileContainer` locks single blocks and bit sets, so reads and writes of
+/// different blocks run in parallel, while a `VecContainer` sits in a `LockedContainer`.
+pub struct SharedContainer<C: IntoConcurrent> {
+    container: Arc<C::Concurrent>,
+}
+
+impl<C: IntoConcurrent> Clone for SharedContainer<C> {
+    fn clone(&self) -> Self {
+        SharedContainer { container: self.container.clone() }
+    }
+}
+
+impl<C: IntoConcurrent> SharedContainer<C> {
+    pub fn new(container: C) -> Self {
+        SharedContainer { container: Arc::new(container.into_concurrent()) }
+    }
+
+    /// Returns the container if this is the last handle to it, otherwise returns the handle.
+    pub fn try_unwrap(self) -> ::std::result::Result<C, Self> {
+        match Arc::try_unwrap(self.container) {
+            Ok(container) => Ok(C::from_concurrent(container)),
+            Err(container) => Err(SharedContainer { container: container }),
+        }
+    }
+}
+
+impl<C, I, E> SharedContainer<C> where I: Copy, C: IntoConcurrent, C::Concurrent: ConcurrentContainer<I=I, E=E> {
+    pub fn get(&self, id: I) -> Result<Option<E>> {
+        self.container.get(id)
+    }
+
+    pub fn contains(&self, id: I) -> Result<bool> {
+        self.container.contains(id)
+    }
+
+    pub fn insert(&self, element: E) -> Result<I> {
+        self.container.insert(element)
+    }
+
+    pub fn reserve(&self) -> Result<I> {
+        self.container.reserve()
+    }
+
+    pub fn update(&self, id: I, element: E) -> Result<Option<E>> {
+        self.container.update(id, element)
+    }
+
+    pub fn remove(&self, id: I) -> Result<Option<E>> {
+        self.container.remove(id)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        self.container.clear()
+    }
+}
+
+/// A container behind one `RwLock`: reads take the shared lock and run concurrently through
+/// `SharedReadContainer`, writes take the exclusive lock and are serialized. The `ConcurrentContainer`
+/// of containers without finer locks.
+pub struct LockedContainer<C> {
+    container: RwLock<C>,
+}
+
+impl<C> LockedContainer<C> {
+    pub fn new(container: C) -> Self {
+        LockedContainer { container: RwLock::new(container) }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.container.into_inner().expect("locked container poisoned")
+    }
+}
+
+impl<C, I, E> ConcurrentContainer for LockedContainer<C>
+    where I: Copy, C: SharedReadContainer<I=I, E=E> + for<'a> Container<'a, E, I=I> + Send + Sync {
+    type I = I;
+    type E = E;
+
+    fn insert(&self, element: E) -> Result<I> {
+        self.container.write().expect("locked container poisoned").insert(element)
+    }
+
+    fn reserve(&self) -> Result<I> {
+        self.container.write().expect("locked container poisoned").reserve()
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.container.write().expect("locked container poisoned").clear()
+    }
+
+    fn contains(&self, id: I) -> Result<bool> {
+        self.container.read().expect("locked container poisoned").contains_shared(id)
+    }
+
+    fn get(&self, id: I) -> Result<Option<E>> {
+        self.container.read().expect("locked container poisoned").get_shared(id)
+    }
+
+    fn update(&self, id: I, new_element: E) -> Result<Option<E>> {
+        self.container.write().expect("locked container poisoned").update(id, new_element)
+    }
+
+    fn remove(&self, id: I) -> Result<Option<E>> {
+        self.container.write().expect("locked container poisoned").remove(id)
+    }
+}
+
+/// A `VecContainer` has no finer locks, so it is shared behind one `RwLock`.
+impl<E> IntoConcurrent for VecContainer<E> where E: Clone + Send + Sync + 'static {
+    type Concurrent = LockedContainer<VecContainer<E>>;
+
+    fn into_concurrent(self) -> LockedContainer<VecContainer<E>> {
+        LockedContainer::new(self)
+    }
+
+    fn from_concurrent(container: LockedContainer<VecContainer<E>>) -> Self {
+        container.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::thread;
+
+    use super::SharedContainer;
+    use io::blockfilecontainer::BlockFileContainer;
+    use mem::veccontainer::VecContainer;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn send_sync() {
+        assert_send_sync::<BlockFileContainer>();
+        assert_send_sync::<SharedContainer<BlockFileContainer>>();
+        assert_send_sync::<SharedContainer<VecContainer<u64>>>();
+    }
+
+    #[test]
+    fn vec_container() {
+        let shared = SharedContainer::new(VecContainer::<u64>::new());
+        let id = shared.insert(7).unwrap();
+        let other = shared.clone();
+        assert_eq!(other.get(id).unwrap(), Some(7));
+        assert_eq!(other.update(id, 8).unwrap(), Some(7));
+        assert!(shared.try_unwrap().is_err());
+        assert_eq!(other.remove(id).unwrap(), Some(8));
+        assert!(!other.contains(id).unwrap());
+        assert!(other.try_unwrap().is_ok());
+    }
+
+    /// A block whose second half is the complement of the first, so a torn block is noticed.
+    fn block_for(n: u64) -> Vec<u8> {
+        let mut block = vec![0u8; 16];
+        for i in 0..8 {
+            block[i] = (n >> (8 * i)) as u8;
+            block[8 + i] = !block[i];
+        }
+        block
+    }
+
+    fn is_whole(block: &[u8]) -> bool {
+        (0..8).all(|i| block[8 + i] == !block[i])
+    }
+
+    fn stress(prefix: &str, wal: bool) {
+        let mut container = BlockFileContainer::new_from_prefix_and_block_size(Path::new(prefix), 16).unwrap();
+        if wal {
+            container.enable_wal().unwrap();
+        }
+        let shared = SharedContainer::new(container);
+        let writers: Vec<_> = (0..4u64).map(|writer| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut kept = Vec::new();
+                for i in 0..200 {
+                    let block = block_for(writer * 1000 + i);
+                    let id = shared.insert(block.clone()).unwrap();
+                    assert_eq!(shared.get(id).unwrap(), Some(block.clone()));
+                    if i % 2 == 0 {
+                        assert_eq!(shared.remove(id).unwrap(), Some(block));
+                    } else {
+                        kept.push((id, block));
+                    }
+                }
+                kept
+            })
+        }).collect();
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for id in 0..800 {
+                        if let Some(block) = shared.get(id).unwrap() {
+                            // An inserted block is read as zeros until its writer has written it.
+                            assert!(is_whole(&block) || block.iter().all(|&b| b == 0));
+                        }
+                    }
+                }
+            })
+        }).collect();
+        let mut kept = Vec::new();
+        for writer in writers {
+            kept.extend(writer.join().unwrap());
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(kept.len(), 400);
+        for &(id, ref block) in &kept {
+            assert_eq!(shared.get(id).unwrap(), Some(block.clone()));
+        }
+        let container = shared.try_unwrap().ok().unwrap();
+        assert_eq!(container.used_ids().count(), 400);
+        drop(container);
+
+        let container = BlockFileContainer::open(Path::new(prefix)).unwrap();
+        for (id, block) in kept {
+            assert_eq!(container.get(id).unwrap(), Some(block));
+        }
+    }
+
+    #[test]
+    fn block_file_stress() {
+        stress("test_output/shared_stress", false);
+    }
+
+    #[test]
+    fn block_file_stress_with_wal() {
+        stress("test_output/shared_stress_wal", true);
+    }
+}
@@ -1,3 +1,7 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 use super::error::Result;
 
 // Container: I: Id, V: Value
@@ -23,26 +27,165 @@ pub trait Container<'a, E> {
 
     fn update(&mut self, id: Self::I, new_element:E) -> Result<Option<E>>;
 
+    /// Inserts all elements and returns their ids in the same order.
+    fn insert_many(&mut self, elements: Vec<E>) -> Result<Vec<Self::I>> {
+        let mut ids = Vec::with_capacity(elements.len());
+        for element in elements {
+            ids.push(try!(self.insert(element)));
+        }
+        Ok(ids)
+    }
+
+    /// Applies the updates in order and returns the replaced elements in the same order.
+    fn update_many(&mut self, updates: Vec<(Self::I, E)>) -> Result<Vec<Option<E>>> {
+        let mut old_elements = Vec::with_capacity(updates.len());
+        for (id, new_element) in updates {
+            old_elements.push(try!(self.update(id, new_element)));
+        }
+        Ok(old_elements)
+    }
+
+    /// Removes the ids in order and returns the removed elements in the same order.
+    fn remove_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<E>>> {
+        let mut old_elements = Vec::with_capacity(ids.len());
+        for &id in ids {
+            old_elements.push(try!(self.remove(id)));
+        }
+        Ok(old_elements)
+    }
+
 }
 
 pub trait BufferContainer<'a, E>: Container<'a, E> {
-    fn flush(&mut self, id: Self::I);
-    fn unfix(&mut self, id: Self::I);
-    fn fix(&mut self, id: Self::I);
+    /// Writes the element of `id` back to the underlying storage if it was modified.
+    fn flush(&mut self, id: Self::I) -> Result<()>;
+    /// Releases a page pinned by `fix`.
+    fn unfix(&mut self, id: Self::I) -> Result<()>;
+    /// Loads the element of `id` and pins it in the buffer until `unfix` is called.
+    fn fix(&mut self, id: Self::I) -> Result<()>;
 }
 
 pub trait CloneContainer<'a, E>: Container<'a, E> {
     fn get_clone(&mut self, id: Self::I) -> Result<Option<E>>;
+
+    /// Returns clones of the elements of `ids` in the same order.
+    fn get_many(&mut self, ids: &[Self::I]) -> Result<Vec<Option<E>>> {
+        let mut elements = Vec::with_capacity(ids.len());
+        for &id in ids {
+            elements.push(try!(self.get_clone(id)));
+        }
+        Ok(elements)
+    }
+}
+
+
+/// A container whose reads only need a shared reference, so several threads can read it at once, e.g.
+/// through a `SharedContainer`.
+pub trait SharedReadContainer {
+    type I: Copy;
+    type E;
+
+    /// Like `CloneContainer::get_clone`.
+    fn get_shared(&self, id: Self::I) -> Result<Option<Self::E>>;
+
+    /// Like `Container::contains`.
+    fn contains_shared(&self, id: Self::I) -> Result<bool>;
+}
+
+/// A container whose operations take a shared reference and lock only what they touch, so several
+/// threads can use it at once.
+pub trait ConcurrentContainer: Send + Sync {
+    type I: Copy;
+    type E;
+
+    fn insert(&self, element: Self::E) -> Result<Self::I>;
+
+    fn reserve(&self) -> Result<Self::I>;
+
+    fn clear(&self) -> Result<()>;
+
+    fn contains(&self, id: Self::I) -> Result<bool>;
+
+    /// Like `CloneContainer::get_clone`.
+    fn get(&self, id: Self::I) -> Result<Option<Self::E>>;
+
+    fn update(&self, id: Self::I, new_element: Self::E) -> Result<Option<Self::E>>;
+
+    fn remove(&self, id: Self::I) -> Result<Option<Self::E>>;
+}
+
+/// A container that can be turned into a `ConcurrentContainer` and back, which a `SharedContainer` does.
+pub trait IntoConcurrent: Sized {
+    type Concurrent: ConcurrentContainer;
+
+    fn into_concurrent(self) -> Self::Concurrent;
+
+    fn from_concurrent(container: Self::Concurrent) -> Self;
 }
 
+/// The future returned by the operations of an `AsyncContainer`. It owns everything it needs, so it can be
+/// spawned on any executor.
+pub type ContainerFuture<T> = Pin<Box<Future<Output=Result<T>> + Send>>;
+
+/// Maps the output of `future` with `f` once it completes. Used by the adapters to convert elements.
+pub fn map<T, U, F>(future: ContainerFuture<T>, f: F) -> ContainerFuture<U>
+    where T: 'static, U: 'static, F: FnOnce(T) -> Result<U> + Send + Unpin + 'static {
+    Box::pin(Map { future: future, f: Some(f) })
+}
+
+struct Map<T, F> {
+    future: ContainerFuture<T>,
+    f: Option<F>,
+}
+
+impl<T, U, F> Future for Map<T, F> where F: FnOnce(T) -> Result<U> + Unpin {
+    type Output = Result<U>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<U>> {
+        let this = self.get_mut();
+        match this.future.as_mut().poll(cx) {
+            Poll::Ready(Ok(value)) => {
+                let f = this.f.take().expect("polled a completed future");
+                Poll::Ready(f(value))
+            },
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The non-blocking counterpart of `Container`. The operations take a shared reference and return
+/// futures, so several of them may be in flight at once.
+pub trait AsyncContainer<E> {
+    type I: Copy + Send + 'static;
+
+    fn insert(&self, element: E) -> ContainerFuture<Self::I>;
+
+    fn reserve(&self) -> ContainerFuture<Self::I>;
+
+    fn clear(&self) -> ContainerFuture<()>;
+
+    fn contains(&self, id: Self::I) -> ContainerFuture<bool>;
+
+    fn remove(&self, id: Self::I) -> ContainerFuture<Option<E>>;
+
+    fn update(&self, id: Self::I, new_element: E) -> ContainerFuture<Option<E>>;
+}
+
+/// The non-blocking counterpart of `CloneContainer`.
+pub trait AsyncCloneContainer<E>: AsyncContainer<E> {
+    fn get_clone(&self, id: Self::I) -> ContainerFuture<Option<E>>;
+}
 
 pub trait EntryContainer<'a, E>: Container<'a, E> {
-    type VacantEntry: VacantEntry<Id=Self::I, Value=E>;
+    type VacantEntry: VacantEntry<Id=Self::I, Value=E, OccupiedEntry=Self::OccupiedEntry>;
     type OccupiedEntry: OccupiedEntry<Id=Self::I, Value=E>;
+
+    /// Gets the entry of a reserved id. Returns `ContainerError::InvalidId` if the id is not reserved.
     fn entry(&'a mut self, id: Self::I) -> Result<Entry<Self::OccupiedEntry, Self::VacantEntry>>;
 }
 
-/// A View into a single occupied location in a HashMap
+/// A View into a single occupied location in a Container
 pub trait OccupiedEntry {
     type Id;
     type Value;
@@ -50,17 +193,21 @@ pub trait OccupiedEntry {
     fn get(&self) -> &Self::Value;
     // fn get_mut(&mut self) -> &mut V;
     // fn into_mut(self) -> &'a mut V;
-    fn update(&mut self, value: Self::Value) -> Self::Value;
-    fn remove(self) -> Self::Value;
+
+    /// Applies `f` to the value and stores the modified value in the container.
+    fn modify<F>(&mut self, f: F) -> Result<()> where F: FnOnce(&mut Self::Value);
+    fn update(&mut self, value: Self::Value) -> Result<Self::Value>;
+    fn remove(self) -> Result<Self::Value>;
 }
 
-/// A View into a single empty location in a HashMap
+/// A View into a single reserved but empty location in a Container
 pub trait VacantEntry{
     type Id;
     type Value;
+    type OccupiedEntry: OccupiedEntry<Id=Self::Id, Value=Self::Value>;
     fn id(&self) -> Self::Id;
 
-    fn insert(self, value: Self::Value);
+    fn insert(self, value: Self::Value) -> Result<Self::OccupiedEntry>;
 }
 
 /// A View into a single location
@@ -71,3 +218,39 @@ pub enum Entry<O: OccupiedEntry, V: VacantEntry> {
     /// A vacant View
     Vacant(V),
 }
+
+impl<O, V> Entry<O, V> where O: OccupiedEntry, V: VacantEntry<Id=O::Id, Value=O::Value, OccupiedEntry=O> {
+    pub fn id(&self) -> O::Id {
+        match *self {
+            Entry::Occupied(ref entry) => entry.id(),
+            Entry::Vacant(ref entry) => entry.id(),
+        }
+    }
+
+    /// Inserts `default` if the entry is vacant.
+    pub fn or_insert(self, default: O::Value) -> Result<O> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry),
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Inserts the result of `default` if the entry is vacant.
+    pub fn or_insert_with<F>(self, default: F) -> Result<O> where F: FnOnce() -> O::Value {
+        match self {
+            Entry::Occupied(entry) => Ok(entry),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Applies `f` to the value if the entry is occupied.
+    pub fn and_modify<F>(self, f: F) -> Result<Self> where F: FnOnce(&mut O::Value) {
+        match self {
+            Entry::Occupied(mut entry) => {
+                try!(entry.modify(f));
+                Ok(Entry::Occupied(entry))
+            },
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+}
@@ -4,16 +4,51 @@ use std::io;
 use std::error;
 use std::ops::Deref;
 
+use byteorder;
+
+#[cfg(feature = "serde")]
 use bincode;
 
+#[cfg(feature = "msgpack")]
+use rmp_serde;
+
+#[cfg(feature = "cbor")]
+use ciborium;
+
+#[cfg(feature = "json")]
+use serde_json;
+
+#[cfg(feature = "rustc-serialize")]
+use bincode_legacy;
+
+#[cfg(feature = "compression")]
+use lz4_flex;
+
+#[cfg(feature = "compression")]
+use miniz_oxide;
+
 pub type Result<T> = result::Result<T, ContainerError>;
 
 #[derive(Debug)]
 pub enum ContainerError {
     Io(io::Error),
-    Serializer(Box<error::Error>),
+    Serializer(Box<error::Error + Send + Sync>),
     InvalidId,
     Reserved,
+    InvalidMetadata,
+    BlockSizeMismatch(usize, usize),
+    Compression(Box<error::Error + Send + Sync>),
+    InvalidCodec(u8),
+    Fixed,
+    BufferFull,
+    NotSorted,
+    InvalidMagic([u8; 4]),
+    UnsupportedVersion(u32),
+    CorruptLog,
+    UnknownContainer(String),
+    NodeTooLarge(usize, usize),
+    NotConsecutive,
+    Panicked(String),
 }
 
 impl fmt::Display for ContainerError {
@@ -22,6 +57,20 @@ impl fmt::Display for ContainerError {
             ContainerError::Io(ref err) => write!(f, "IO error: {}", err),
             ContainerError::Serializer(ref err) => write!(f, "Serializer error: {}", err),
             ContainerError::InvalidId => write!(f, "Invalid ID error:"),
+            ContainerError::InvalidMetadata => write!(f, "Invalid metadata error:"),
+            ContainerError::BlockSizeMismatch(expected, found) => write!(f, "Block size mismatch error: expected {}, found {}", expected, found),
+            ContainerError::Compression(ref err) => write!(f, "Compression error: {}", err),
+            ContainerError::InvalidCodec(tag) => write!(f, "Invalid codec error: {}", tag),
+            ContainerError::Fixed => write!(f, "Fixed page error:"),
+            ContainerError::BufferFull => write!(f, "Buffer full error:"),
+            ContainerError::NotSorted => write!(f, "Not sorted error:"),
+            ContainerError::InvalidMagic(magic) => write!(f, "Invalid magic number error: {:?}", magic),
+            ContainerError::UnsupportedVersion(version) => write!(f, "Unsupported format version error: {}", version),
+            ContainerError::CorruptLog => write!(f, "Corrupt log error:"),
+            ContainerError::UnknownContainer(ref container) => write!(f, "Unknown container error: {}", container),
+            ContainerError::NodeTooLarge(size, max_size) => write!(f, "Node too large error: {} bytes, at most {} fit", size, max_size),
+            ContainerError::NotConsecutive => write!(f, "Not consecutive error:"),
+            ContainerError::Panicked(ref message) => write!(f, "Panicked error: {}", message),
             _ => write!(f, "Unexpected error!"),
         }
     }
@@ -33,6 +82,20 @@ impl error::Error for ContainerError {
             ContainerError::Io(ref err) => err.description(),
             ContainerError::Serializer(ref err) => err.description(),
             ContainerError::InvalidId => "tried to access an invalid id",
+            ContainerError::InvalidMetadata => "the stored metadata is invalid or inconsistent",
+            ContainerError::BlockSizeMismatch(..) => "the stored block size does not match the requested block size",
+            ContainerError::Compression(ref err) => err.description(),
+            ContainerError::InvalidCodec(..) => "the payload was written by an unknown codec",
+            ContainerError::Fixed => "tried to remove a fixed page",
+            ContainerError::BufferFull => "all pages of the buffer are fixed",
+            ContainerError::NotSorted => "the input is not sorted",
+            ContainerError::InvalidMagic(..) => "the file does not start with the expected magic number",
+            ContainerError::UnsupportedVersion(..) => "the file was written in an unsupported format version",
+            ContainerError::CorruptLog => "a log record can not be decoded",
+            ContainerError::UnknownContainer(..) => "the transaction log refers to a container that was not passed in",
+            ContainerError::NodeTooLarge(..) => "a serialized node does not fit into a block",
+            ContainerError::NotConsecutive => "the container did not hand out consecutive ids",
+            ContainerError::Panicked(..) => "an operation panicked",
             _ => "Unexpected Error",
         }
     }
@@ -41,6 +104,7 @@ impl error::Error for ContainerError {
         match *self {
             ContainerError::Io(ref err) => Some(err),
             ContainerError::Serializer(ref err) => Some(err.deref()),
+            ContainerError::Compression(ref err) => Some(err.deref()),
             ContainerError::InvalidId => None,
             _ => None,
         }
@@ -53,26 +117,78 @@ impl From<io::Error> for ContainerError {
     }
 }
 
-impl From<bincode::rustc_serialize::DecodingError> for ContainerError {
-    fn from(err: bincode::rustc_serialize::DecodingError) -> ContainerError {
+impl From<byteorder::Error> for ContainerError {
+    fn from(err: byteorder::Error) -> ContainerError {
+        ContainerError::Io(io::Error::from(err))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<bincode::Error> for ContainerError {
+    fn from(err: bincode::Error) -> ContainerError {
+        ContainerError::Serializer(err)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::encode::Error> for ContainerError {
+    fn from(err: rmp_serde::encode::Error) -> ContainerError {
         ContainerError::Serializer(Box::new(err))
     }
 }
 
-impl From<bincode::rustc_serialize::EncodingError> for ContainerError {
-    fn from(err: bincode::rustc_serialize::EncodingError) -> ContainerError {
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::decode::Error> for ContainerError {
+    fn from(err: rmp_serde::decode::Error) -> ContainerError {
         ContainerError::Serializer(Box::new(err))
     }
 }
 
-impl From<bincode::serde::SerializeError> for ContainerError {
-    fn from(err: bincode::serde::SerializeError) -> ContainerError {
+#[cfg(feature = "cbor")]
+impl From<ciborium::ser::Error<io::Error>> for ContainerError {
+    fn from(err: ciborium::ser::Error<io::Error>) -> ContainerError {
         ContainerError::Serializer(Box::new(err))
     }
 }
 
-impl From<bincode::serde::DeserializeError> for ContainerError {
-    fn from(err: bincode::serde::DeserializeError) -> ContainerError {
+#[cfg(feature = "cbor")]
+impl From<ciborium::de::Error<io::Error>> for ContainerError {
+    fn from(err: ciborium::de::Error<io::Error>) -> ContainerError {
         ContainerError::Serializer(Box::new(err))
     }
 }
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for ContainerError {
+    fn from(err: serde_json::Error) -> ContainerError {
+        ContainerError::Serializer(Box::new(err))
+    }
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl From<bincode_legacy::rustc_serialize::DecodingError> for ContainerError {
+    fn from(err: bincode_legacy::rustc_serialize::DecodingError) -> ContainerError {
+        ContainerError::Serializer(Box::new(err))
+    }
+}
+
+#[cfg(feature = "rustc-serialize")]
+impl From<bincode_legacy::rustc_serialize::EncodingError> for ContainerError {
+    fn from(err: bincode_legacy::rustc_serialize::EncodingError) -> ContainerError {
+        ContainerError::Serializer(Box::new(err))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl From<lz4_flex::block::DecompressError> for ContainerError {
+    fn from(err: lz4_flex::block::DecompressError) -> ContainerError {
+        ContainerError::Compression(Box::new(err))
+    }
+}
+
+#[cfg(feature = "compression")]
+impl From<miniz_oxide::inflate::DecompressError> for ContainerError {
+    fn from(err: miniz_oxide::inflate::DecompressError) -> ContainerError {
+        ContainerError::Compression(Box::new(err))
+    }
+}
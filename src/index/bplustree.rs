@@ -0,0 +1,702 @@
+use serde;
+
+use std::marker::{PhantomData};
+use std::ops::Bound;
+use std::{mem, result, vec};
+
+use adapter::codec::Codec;
+use container::{CloneContainer};
+use error::{ContainerError, Result};
+use io::blockfilecontainer::Id;
+
+/// A node of a `BPlusTree`. Leaves store the values and the id of the next leaf,
+/// internal nodes store the ids of their children.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<K, V> {
+    keys: Vec<K>,
+    values: Vec<V>,
+    children: Vec<Id>,
+    next: Option<Id>,
+}
+
+impl<K, V> Node<K, V> {
+    fn leaf(keys: Vec<K>, values: Vec<V>, next: Option<Id>) -> Self {
+        Node{keys: keys, values: values, children: Vec::new(), next: next}
+    }
+
+    fn internal(keys: Vec<K>, children: Vec<Id>) -> Self {
+        Node{keys: keys, values: Vec::new(), children: children, next: None}
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+impl<K, V> serde::ser::Serialize for Node<K, V> where K: serde::ser::Serialize, V: serde::ser::Serialize {
+    fn serialize<S>(&self, serializer: S) -> result::Result<S::Ok, S::Error> where S: serde::ser::Serializer {
+        (&self.keys, &self.values, &self.children, &self.next).serialize(serializer)
+    }
+}
+
+impl<'de, K, V> serde::de::Deserialize<'de> for Node<K, V> where K: serde::de::Deserialize<'de>, V: serde::de::Deserialize<'de> {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error> where D: serde::de::Deserializer<'de> {
+        let (keys, values, children, next) = try!(serde::de::Deserialize::deserialize(deserializer));
+        Ok(Node{keys: keys, values: values, children: children, next: next})
+    }
+}
+
+/// The index of the child of an internal node that covers `key`.
+fn child_index<K: Ord>(keys: &[K], key: &K) -> usize {
+    match keys.binary_search(key) {
+        Ok(index) => index + 1,
+        Err(index) => index,
+    }
+}
+
+/// Returns the serialized size of a node, see `BPlusTree::limit_node_size`.
+type NodeSize<K, V> = Box<Fn(&Node<K, V>) -> Result<usize> + Send + Sync>;
+
+/// An ordered index whose nodes are stored in a container, e.g. a `SerdeAdapter` on top of a
+/// `BlockFileContainer`. Each node holds at most `order` keys, so `order` has to be chosen small enough
+/// for a serialized node to fit into a block. With `limit_node_size`, `insert` checks that every node
+/// it writes fits and fails before it changes the tree otherwise.
+///
+/// The root node keeps its id for the lifetime of the tree. Store `root()` to `open` the tree again.
+/// Nodes refer to their children by id, so the container must never be compacted.
+pub struct BPlusTree<K, V, C> {
+    container: C,
+    root: Id,
+    order: usize,
+    node_size: Option<(usize, NodeSize<K, V>)>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V, C> BPlusTree<K, V, C> {
+    /// The id of the root node.
+    pub fn root(&self) -> Id {
+        self.root
+    }
+
+    /// The maximum number of keys in a node.
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    pub fn into_inner(self) -> C {
+        self.container
+    }
+
+    /// Lets `insert` fail with `NodeTooLarge` instead of writing a node that `codec` serializes to more
+    /// than `max_size` bytes, e.g. the codec and block size of the `SerdeAdapter` the tree is stored in.
+    pub fn limit_node_size<X>(&mut self, codec: X, max_size: usize)
+        where X: Codec + Send + Sync + 'static, K: serde::ser::Serialize, V: serde::ser::Serialize {
+        let size = move |node: &Node<K, V>| -> Result<usize> { Ok(try!(codec.encode(node)).len()) };
+        self.node_size = Some((max_size, Box::new(size)));
+    }
+
+    fn min_keys(&self) -> usize {
+        self.order / 2
+    }
+
+    fn check_size(&self, node: &Node<K, V>) -> Result<()> {
+        if let Some((max_size, ref size)) = self.node_size {
+            let size = try!(size(node));
+            if size > max_size {
+                return Err(ContainerError::NodeTooLarge(size, max_size));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, K, V, C> BPlusTree<K, V, C> where K: Ord + Clone, V: Clone, C: CloneContainer<'a, Node<K, V>, I=Id> {
+
+    /// Creates an empty tree in `container`.
+    pub fn new(order: usize, mut container: C) -> Result<Self> {
+        assert!(order >= 3, "a B+-tree needs an order of at least three");
+        let root = try!(container.insert(Node::leaf(Vec::new(), Vec::new(), None)));
+        Ok(BPlusTree{container: container, root: root, order: order, node_size: None, phantom: PhantomData})
+    }
+
+    /// Opens a tree whose root node is stored at `root`.
+    pub fn open(order: usize, root: Id, mut container: C) -> Result<Self> {
+        assert!(order >= 3, "a B+-tree needs an order of at least three");
+        if !try!(container.contains(root)) {
+            return Err(ContainerError::InvalidId);
+        }
+        Ok(BPlusTree{container: container, root: root, order: order, node_size: None, phantom: PhantomData})
+    }
+
+    /// Builds a tree from `input`, which has to be sorted by strictly ascending keys.
+    /// The leaves are filled completely, the inner nodes are filled evenly.
+    pub fn bulk_load<T>(order: usize, mut container: C, input: T) -> Result<Self> where T: IntoIterator<Item=(K, V)> {
+        assert!(order >= 3, "a B+-tree needs an order of at least three");
+        let root = try!(container.reserve());
+        let mut tree = BPlusTree{container: container, root: root, order: order, node_size: None, phantom: PhantomData};
+
+        // the last full leaf is kept back until its successor is known.
+        let mut level: Vec<(K, Id)> = Vec::new();
+        let mut pending: Option<(Id, Node<K, V>)> = None;
+        let mut current = Node::leaf(Vec::new(), Vec::new(), None);
+        let mut previous: Option<K> = None;
+        for (key, value) in input {
+            if previous.as_ref().map_or(false, |previous| *previous >= key) {
+                return Err(ContainerError::NotSorted);
+            }
+            previous = Some(key.clone());
+            if current.keys.len() == order {
+                let id = try!(tree.container.reserve());
+                if let Some((pending_id, mut pending_node)) = pending.take() {
+                    pending_node.next = Some(id);
+                    level.push((pending_node.keys[0].clone(), pending_id));
+                    try!(tree.write(pending_id, pending_node));
+                }
+                pending = Some((id, mem::replace(&mut current, Node::leaf(Vec::new(), Vec::new(), None))));
+            }
+            current.keys.push(key);
+            current.values.push(value);
+        }
+
+        let (pending_id, mut pending_node) = match pending {
+            Some(pending) => pending,
+            None => {
+                try!(tree.write(root, current));
+                return Ok(tree);
+            }
+        };
+        if current.keys.len() < tree.min_keys() {
+            let split = (pending_node.keys.len() + current.keys.len()) / 2;
+            let mut keys = pending_node.keys.split_off(split);
+            let mut values = pending_node.values.split_off(split);
+            keys.extend(current.keys.drain(..));
+            values.extend(current.values.drain(..));
+            current = Node::leaf(keys, values, None);
+        }
+        let id = try!(tree.container.reserve());
+        pending_node.next = Some(id);
+        level.push((pending_node.keys[0].clone(), pending_id));
+        level.push((current.keys[0].clone(), id));
+        try!(tree.write(pending_id, pending_node));
+        try!(tree.write(id, current));
+
+        while level.len() > 1 {
+            let length = level.len();
+            let groups = (length + order) / (order + 1);
+            let mut entries = level.into_iter();
+            level = Vec::with_capacity(groups);
+            for group in 0 .. groups {
+                let size = length / groups + if group < length % groups { 1 } else { 0 };
+                let children: Vec<(K, Id)> = entries.by_ref().take(size).collect();
+                let first = children[0].0.clone();
+                let keys = children[1..].iter().map(|child| child.0.clone()).collect();
+                let ids = children.iter().map(|child| child.1).collect();
+                let id = try!(tree.container.insert(Node::internal(keys, ids)));
+                level.push((first, id));
+            }
+        }
+
+        let top = level[0].1;
+        let node = try!(tree.read(top));
+        try!(tree.write(root, node));
+        try!(tree.free(top));
+        Ok(tree)
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let root = self.root;
+        let mut node = try!(self.read(root));
+        while !node.is_leaf() {
+            let child = node.children[child_index(&node.keys, key)];
+            node = try!(self.read(child));
+        }
+        match node.keys.binary_search(key) {
+            Ok(index) => Ok(Some(node.values.swap_remove(index))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Inserts a key-value pair. Returns the old value if the key was already present.
+    ///
+    /// The changed nodes are collected and checked before the first one is written, so a node that is too
+    /// large leaves the tree unchanged.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let mut writes = Vec::new();
+        let mut reserved = Vec::new();
+        let result = self.plan_insert(key, value, &mut writes, &mut reserved);
+        let result = result.and_then(|old_value| {
+            for (_, node) in &writes {
+                try!(self.check_size(node));
+            }
+            Ok(old_value)
+        });
+        let old_value = match result {
+            Ok(old_value) => old_value,
+            Err(err) => {
+                for id in reserved {
+                    let _ = try!(self.container.remove(id));
+                }
+                return Err(err);
+            },
+        };
+        for (id, node) in writes {
+            try!(self.write(id, node));
+        }
+        Ok(old_value)
+    }
+
+    fn plan_insert(&mut self, key: K, value: V, writes: &mut Vec<(Id, Node<K, V>)>, reserved: &mut Vec<Id>) -> Result<Option<V>> {
+        let root = self.root;
+        let (old_value, split) = try!(self.insert_into(root, key, value, writes, reserved));
+        if let Some((separator, right)) = split {
+            // the root keeps its id, so its left half is moved to a new node.
+            let left = try!(self.container.reserve());
+            reserved.push(left);
+            for write in writes.iter_mut().filter(|write| write.0 == root) {
+                write.0 = left;
+            }
+            writes.push((root, Node::internal(vec![separator], vec![left, right])));
+        }
+        Ok(old_value)
+    }
+
+    /// Removes a key. Underfull nodes borrow from or are merged with a sibling.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>> {
+        let root = self.root;
+        let (removed, underflow) = try!(self.remove_from(root, key));
+        if underflow {
+            let root_node = try!(self.read(root));
+            if !root_node.is_leaf() && root_node.keys.is_empty() {
+                let child = root_node.children[0];
+                let child_node = try!(self.read(child));
+                try!(self.write(root, child_node));
+                try!(self.free(child));
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns an iterator over all entries with keys between `start` and `end` in ascending order.
+    pub fn range<'b>(&'b mut self, start: Bound<K>, end: Bound<K>) -> Result<Range<'b, K, V, C>> {
+        let root = self.root;
+        let mut node = try!(self.read(root));
+        while !node.is_leaf() {
+            let index = match start {
+                Bound::Included(ref key) | Bound::Excluded(ref key) => child_index(&node.keys, key),
+                Bound::Unbounded => 0,
+            };
+            let child = node.children[index];
+            node = try!(self.read(child));
+        }
+        let skip = match start {
+            Bound::Included(ref key) => node.keys.iter().take_while(|k| *k < key).count(),
+            Bound::Excluded(ref key) => node.keys.iter().take_while(|k| *k <= key).count(),
+            Bound::Unbounded => 0,
+        };
+        let mut keys = node.keys.into_iter();
+        let mut values = node.values.into_iter();
+        for _ in 0 .. skip {
+            keys.next();
+            values.next();
+        }
+        Ok(Range{tree: self, keys: keys, values: values, next: node.next, end: end, done: false})
+    }
+
+    /// Returns an iterator over all entries in ascending order.
+    pub fn iter<'b>(&'b mut self) -> Result<Range<'b, K, V, C>> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    fn read(&mut self, id: Id) -> Result<Node<K, V>> {
+        match try!(self.container.get_clone(id)) {
+            Some(node) => Ok(node),
+            None => Err(ContainerError::InvalidId),
+        }
+    }
+
+    fn write(&mut self, id: Id, node: Node<K, V>) -> Result<()> {
+        let _ = try!(self.container.update(id, node));
+        Ok(())
+    }
+
+    fn free(&mut self, id: Id) -> Result<()> {
+        let _ = try!(self.container.remove(id));
+        Ok(())
+    }
+
+    /// Inserts into the subtree of `id`, collecting the changed nodes in `writes` and the ids of new nodes
+    /// in `reserved`. Returns the old value and, if the node was split, the separator and the id of the
+    /// new right sibling.
+    fn insert_into(&mut self, id: Id, key: K, value: V, writes: &mut Vec<(Id, Node<K, V>)>, reserved: &mut Vec<Id>)
+        -> Result<(Option<V>, Option<(K, Id)>)> {
+        let mut node = try!(self.read(id));
+        let old_value;
+        if node.is_leaf() {
+            match node.keys.binary_search(&key) {
+                Ok(index) => {
+                    old_value = Some(mem::replace(&mut node.values[index], value));
+                    writes.push((id, node));
+                    return Ok((old_value, None));
+                },
+                Err(index) => {
+                    node.keys.insert(index, key);
+                    node.values.insert(index, value);
+                    old_value = None;
+                }
+            }
+        }
+        else {
+            let index = child_index(&node.keys, &key);
+            let child = node.children[index];
+            match try!(self.insert_into(child, key, value, writes, reserved)) {
+                (child_old_value, Some((separator, right))) => {
+                    node.keys.insert(index, separator);
+                    node.children.insert(index + 1, right);
+                    old_value = child_old_value;
+                },
+                (child_old_value, None) => return Ok((child_old_value, None)),
+            }
+        }
+
+        if node.keys.len() <= self.order {
+            writes.push((id, node));
+            return Ok((old_value, None));
+        }
+        let split = try!(self.split(id, node, writes, reserved));
+        Ok((old_value, Some(split)))
+    }
+
+    fn split(&mut self, id: Id, mut node: Node<K, V>, writes: &mut Vec<(Id, Node<K, V>)>, reserved: &mut Vec<Id>) -> Result<(K, Id)> {
+        let middle = node.keys.len() / 2;
+        let separator;
+        let right_node;
+        if node.is_leaf() {
+            let keys = node.keys.split_off(middle);
+            let values = node.values.split_off(middle);
+            separator = keys[0].clone();
+            right_node = Node::leaf(keys, values, node.next);
+        }
+        else {
+            let mut keys = node.keys.split_off(middle);
+            separator = keys.remove(0);
+            let children = node.children.split_off(middle + 1);
+            right_node = Node::internal(keys, children);
+        }
+        let right = try!(self.container.reserve());
+        reserved.push(right);
+        if node.is_leaf() {
+            node.next = Some(right);
+        }
+        writes.push((right, right_node));
+        writes.push((id, node));
+        Ok((separator, right))
+    }
+
+    /// Removes from the subtree of `id`. Returns the removed value and whether the node is underfull.
+    fn remove_from(&mut self, id: Id, key: &K) -> Result<(Option<V>, bool)> {
+        let mut node = try!(self.read(id));
+        if node.is_leaf() {
+            return match node.keys.binary_search(key) {
+                Ok(index) => {
+                    node.keys.remove(index);
+                    let value = node.values.remove(index);
+                    let underflow = node.keys.len() < self.min_keys();
+                    try!(self.write(id, node));
+                    Ok((Some(value), underflow))
+                },
+                Err(_) => Ok((None, false)),
+            };
+        }
+
+        let index = child_index(&node.keys, key);
+        let child = node.children[index];
+        let (removed, child_underflow) = try!(self.remove_from(child, key));
+        if !child_underflow {
+            return Ok((removed, false));
+        }
+        try!(self.rebalance(&mut node, index));
+        let underflow = node.keys.len() < self.min_keys();
+        try!(self.write(id, node));
+        Ok((removed, underflow))
+    }
+
+    /// Fixes the underfull child at `index` of `parent` by borrowing from or merging with a sibling.
+    fn rebalance(&mut self, parent: &mut Node<K, V>, index: usize) -> Result<()> {
+        let (left_index, right_index) = if index > 0 { (index - 1, index) } else { (index, index + 1) };
+        let left_id = parent.children[left_index];
+        let right_id = parent.children[right_index];
+        let mut left = try!(self.read(left_id));
+        let mut right = try!(self.read(right_id));
+        let sibling_keys = if index > 0 { left.keys.len() } else { right.keys.len() };
+
+        if sibling_keys > self.min_keys() {
+            if index > 0 {
+                borrow_from_left(&mut left, &mut right, &mut parent.keys[left_index]);
+            }
+            else {
+                borrow_from_right(&mut left, &mut right, &mut parent.keys[left_index]);
+            }
+            try!(self.write(left_id, left));
+            try!(self.write(right_id, right));
+        }
+        else {
+            let separator = parent.keys.remove(left_index);
+            parent.children.remove(right_index);
+            merge(&mut left, right, separator);
+            try!(self.write(left_id, left));
+            try!(self.free(right_id));
+        }
+        Ok(())
+    }
+}
+
+fn borrow_from_left<K: Clone, V>(left: &mut Node<K, V>, right: &mut Node<K, V>, separator: &mut K) {
+    let key = left.keys.pop().expect("sibling is not empty");
+    if right.is_leaf() {
+        right.keys.insert(0, key);
+        right.values.insert(0, left.values.pop().expect("sibling is not empty"));
+        *separator = right.keys[0].clone();
+    }
+    else {
+        right.keys.insert(0, mem::replace(separator, key));
+        right.children.insert(0, left.children.pop().expect("sibling is not empty"));
+    }
+}
+
+fn borrow_from_right<K: Clone, V>(left: &mut Node<K, V>, right: &mut Node<K, V>, separator: &mut K) {
+    let key = right.keys.remove(0);
+    if left.is_leaf() {
+        left.keys.push(key);
+        left.values.push(right.values.remove(0));
+        *separator = right.keys[0].clone();
+    }
+    else {
+        left.keys.push(mem::replace(separator, key));
+        left.children.push(right.children.remove(0));
+    }
+}
+
+fn merge<K, V>(left: &mut Node<K, V>, right: Node<K, V>, separator: K) {
+    if left.is_leaf() {
+        left.next = right.next;
+    }
+    else {
+        left.keys.push(separator);
+    }
+    left.keys.extend(right.keys);
+    left.values.extend(right.values);
+    left.children.extend(right.children);
+}
+
+/// Iterates over the leaves of a `BPlusTree`. A node that can not be read ends the iteration with an error.
+pub struct Range<'b, K: 'b, V: 'b, C: 'b> {
+    tree: &'b mut BPlusTree<K, V, C>,
+    keys: vec::IntoIter<K>,
+    values: vec::IntoIter<V>,
+    next: Option<Id>,
+    end: Bound<K>,
+    done: bool,
+}
+
+impl<'a, 'b, K, V, C> Iterator for Range<'b, K, V, C> where K: Ord + Clone, V: Clone, C: CloneContainer<'a, Node<K, V>, I=Id> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let (Some(key), Some(value)) = (self.keys.next(), self.values.next()) {
+                let in_range = match self.end {
+                    Bound::Included(ref end) => key <= *end,
+                    Bound::Excluded(ref end) => key < *end,
+                    Bound::Unbounded => true,
+                };
+                if in_range {
+                    return Some(Ok((key, value)));
+                }
+                self.done = true;
+            }
+            else if let Some(next) = self.next {
+                let node = match self.tree.read(next) {
+                    Ok(node) => node,
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    },
+                };
+                self.keys = node.keys.into_iter();
+                self.values = node.values.into_iter();
+                self.next = node.next;
+            }
+            else {
+                self.done = true;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Bound;
+    use std::path::Path;
+    use container::Container;
+    use adapter::codec::Bincode;
+    use adapter::serde::SerdeAdapter;
+    use io::blockfilecontainer::{BlockFileContainer, Id};
+
+    type Tree = BPlusTree<u64, u64, SerdeAdapter<Node<u64, u64>, BlockFileContainer>>;
+
+    fn new_tree(name: &str, order: usize) -> Tree {
+        let prefix = Path::new("./test_output").join(name);
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 512).unwrap();
+        BPlusTree::new(order, SerdeAdapter::new(bfc)).unwrap()
+    }
+
+    /// Checks the order of the keys, the fill degree of the nodes and that all leaves have the same depth.
+    fn check_node(tree: &mut Tree, id: Id, lower: Option<u64>, upper: Option<u64>, is_root: bool) -> usize {
+        let node = tree.read(id).unwrap();
+        assert!(node.keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(node.keys.len() <= tree.order());
+        if !is_root {
+            assert!(node.keys.len() >= tree.min_keys());
+        }
+        assert!(node.keys.iter().all(|&key| lower.map_or(true, |lower| key >= lower) && upper.map_or(true, |upper| key < upper)));
+        if node.is_leaf() {
+            assert_eq!(node.keys.len(), node.values.len());
+            return 1;
+        }
+        assert_eq!(node.keys.len() + 1, node.children.len());
+        let depths: Vec<usize> = (0 .. node.children.len()).map(|index| {
+            let child_lower = if index == 0 { lower } else { Some(node.keys[index - 1]) };
+            let child_upper = if index == node.keys.len() { upper } else { Some(node.keys[index]) };
+            check_node(tree, node.children[index], child_lower, child_upper, false)
+        }).collect();
+        assert!(depths.iter().all(|&depth| depth == depths[0]));
+        depths[0] + 1
+    }
+
+    fn check(tree: &mut Tree) {
+        let root = tree.root();
+        check_node(tree, root, None, None, true);
+    }
+
+    fn permutation(n: u64) -> Vec<u64> {
+        (0 .. n).map(|i| (i * 7919) % n).collect()
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut tree = new_tree("bplustree_insert", 4);
+        for key in permutation(1000) {
+            assert_eq!(tree.insert(key, key * 2).unwrap(), None);
+        }
+        check(&mut tree);
+        for key in 0 .. 1000 {
+            assert_eq!(tree.get(&key).unwrap(), Some(key * 2));
+        }
+        assert_eq!(tree.get(&1000).unwrap(), None);
+        assert_eq!(tree.insert(5, 0).unwrap(), Some(10));
+        assert_eq!(tree.get(&5).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn remove() {
+        let mut tree = new_tree("bplustree_remove", 3);
+        for key in permutation(500) {
+            tree.insert(key, key).unwrap();
+        }
+        for key in permutation(500).into_iter().filter(|key| key % 3 != 0) {
+            assert_eq!(tree.remove(&key).unwrap(), Some(key));
+            assert_eq!(tree.remove(&key).unwrap(), None);
+        }
+        check(&mut tree);
+        let keys: Vec<u64> = tree.iter().unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, (0 .. 500).filter(|key| key % 3 == 0).collect::<Vec<u64>>());
+
+        for key in 0 .. 500 {
+            tree.remove(&key).unwrap();
+        }
+        check(&mut tree);
+        assert_eq!(tree.iter().unwrap().count(), 0);
+        // only the root is left
+        let mut container = tree.into_inner();
+        assert_eq!(container.ids().count(), 1);
+    }
+
+    #[test]
+    fn range() {
+        let mut tree = new_tree("bplustree_range", 4);
+        for key in permutation(200) {
+            tree.insert(key * 2, key).unwrap();
+        }
+        let range: Vec<u64> = tree.range(Bound::Included(10), Bound::Excluded(20)).unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(range, vec!(10, 12, 14, 16, 18));
+        let range: Vec<u64> = tree.range(Bound::Excluded(9), Bound::Included(20)).unwrap().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(range, vec!(10, 12, 14, 16, 18, 20));
+        let range: Vec<(u64, u64)> = tree.range(Bound::Excluded(394), Bound::Unbounded).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(range, vec!((396, 198), (398, 199)));
+        assert_eq!(tree.iter().unwrap().count(), 200);
+    }
+
+    #[test]
+    fn bulk_load() {
+        for &n in &[0u64, 1, 4, 5, 6, 17, 1000] {
+            let prefix = Path::new("./test_output/bplustree_bulk_load");
+            let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 512).unwrap();
+            let mut tree: Tree = BPlusTree::bulk_load(4, SerdeAdapter::new(bfc), (0 .. n).map(|key| (key, key + 1))).unwrap();
+            check(&mut tree);
+            let entries: Vec<(u64, u64)> = tree.iter().unwrap().collect::<Result<_>>().unwrap();
+            assert_eq!(entries, (0 .. n).map(|key| (key, key + 1)).collect::<Vec<(u64, u64)>>());
+            tree.insert(n, n + 1).unwrap();
+            check(&mut tree);
+        }
+    }
+
+    #[test]
+    fn bulk_load_not_sorted() {
+        let prefix = Path::new("./test_output/bplustree_bulk_load_not_sorted");
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 512).unwrap();
+        let result: Result<Tree> = BPlusTree::bulk_load(4, SerdeAdapter::new(bfc), vec!((1, 1), (1, 2)));
+        match result {
+            Err(ContainerError::NotSorted) => {},
+            _ => panic!("loaded unsorted input"),
+        }
+    }
+
+    #[test]
+    fn node_too_large() {
+        let prefix = Path::new("./test_output/bplustree_node_too_large");
+        let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 128).unwrap();
+        let mut tree: BPlusTree<String, u64, _> = BPlusTree::new(4, SerdeAdapter::<Node<String, u64>, _>::new(bfc)).unwrap();
+        tree.limit_node_size(Bincode, 128);
+        for key in 0 .. 20u64 {
+            tree.insert(format!("{:04}", key), key).unwrap();
+        }
+        let ids = tree.container.ids().count();
+        match tree.insert(format!("{:0100}", 7), 7) {
+            Err(ContainerError::NodeTooLarge(size, 128)) => assert!(size > 128),
+            _ => panic!("inserted a node that does not fit into a block"),
+        }
+        assert_eq!(tree.container.ids().count(), ids);
+        let entries: Vec<(String, u64)> = tree.iter().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(entries, (0 .. 20u64).map(|key| (format!("{:04}", key), key)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn open() {
+        let prefix = Path::new("./test_output/bplustree_open");
+        let root;
+        {
+            let bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 512).unwrap();
+            let mut tree: Tree = BPlusTree::new(4, SerdeAdapter::new(bfc)).unwrap();
+            for key in permutation(100) {
+                tree.insert(key, key).unwrap();
+            }
+            root = tree.root();
+        }
+        let bfc = BlockFileContainer::open(&prefix).unwrap();
+        let mut tree: Tree = BPlusTree::open(4, root, SerdeAdapter::new(bfc)).unwrap();
+        check(&mut tree);
+        assert_eq!(tree.get(&42).unwrap(), Some(42));
+    }
+}
@@ -1,8 +1,12 @@
 use std::fs::{File};
-use std::io::{Result, Seek, SeekFrom};
+use std::io::{Result};
 use std::{cmp};
 
-use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::super::error::{Result as ContainerResult};
+use super::header::{self, BIT_SET_MAGIC};
+use super::storage::RawAccess;
 
 #[inline]
 pub fn bit_map_offset_and_bit_mask(bit: u64) -> (u64, u8){
@@ -58,46 +62,356 @@ fn max_id_from_test() {
     assert_eq!(max_id_from(4, 0b00000001), Some(32));
 }
 
-static  HEADER_BYTE_SIZE: u64 = 2*8;
+/// The operations a `BlockFileContainer` needs from the sets of its reserved and used ids. Reads only
+/// need `&self`, so a set can be read by several threads.
+pub trait BitSet: Send + Sync {
+    /// Adds a value to the set. Returns `true` if the value was not already present in the set.
+    fn insert(&mut self, bit: u64) -> Result<bool>;
+    /// Removes a value from the set. Returns `true` if the value was present in the set.
+    fn remove(&mut self, bit: u64) -> Result<bool>;
+    fn contains(&self, bit: u64) -> Result<bool>;
+    fn max_bit(&self) -> Option<u64>;
+    fn size(&self) -> u64;
+    fn clear(&mut self) -> Result<()>;
+    /// Writes the set to disc.
+    fn sync(&mut self) -> Result<()>;
+    /// Returns the smallest value in the set that is not smaller than `from`.
+    fn next_set_bit(&self, from: u64) -> Result<Option<u64>>;
+    /// Returns the largest value in the set that is not larger than `from`.
+    fn prev_set_bit(&self, from: u64) -> Result<Option<u64>>;
+
+    fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    /// Adds all values of `bits`. Returns the number of values that were not already present in the set.
+    fn insert_many(&mut self, bits: &[u64]) -> Result<u64> {
+        let mut inserted = 0;
+        for &bit in bits {
+            if try!(self.insert(bit)) {
+                inserted += 1;
+            }
+        }
+        Ok(inserted)
+    }
+
+    /// Removes all values of `bits`. Returns the number of values that were present in the set.
+    fn remove_many(&mut self, bits: &[u64]) -> Result<u64> {
+        let mut removed = 0;
+        for &bit in bits {
+            if try!(self.remove(bit)) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the smallest value not in the set that is not smaller than `from`.
+    fn next_clear_bit(&self, from: u64) -> Result<u64> {
+        let mut bit = from;
+        while try!(self.next_set_bit(bit)) == Some(bit) {
+            bit += 1;
+        }
+        Ok(bit)
+    }
+
+    /// Returns the number of values in `lo .. hi`.
+    fn count_range(&self, lo: u64, hi: u64) -> Result<u64> {
+        let mut count = 0;
+        let mut next = lo;
+        while next < hi {
+            match try!(self.next_set_bit(next)) {
+                Some(bit) if bit < hi => {
+                    count += 1;
+                    next = bit + 1;
+                },
+                _ => break,
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the number of values smaller than `bit`.
+    fn rank(&self, bit: u64) -> Result<u64> {
+        self.count_range(0, bit)
+    }
+
+    /// Returns the value at position `n` of the ascending values, starting with 0.
+    fn select(&self, n: u64) -> Result<Option<u64>> {
+        if n >= self.size() {
+            return Ok(None);
+        }
+        let mut bit = try!(self.next_set_bit(0)).expect("bit set holds more values");
+        for _ in 0 .. n {
+            bit = try!(self.next_set_bit(bit + 1)).expect("bit set holds more values");
+        }
+        Ok(Some(bit))
+    }
+}
+
+/// The set operations supported by a `SetOperationIterator`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+/// The on-disc layouts of the reserved and used ids of a `BlockFileContainer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitSetKind {
+    /// A `BitSetFile` with one bit per id.
+    Plain,
+    /// A compressed `RoaringBitSetFile`.
+    Roaring,
+}
+
+impl BitSetKind {
+    pub fn tag(&self) -> u8 {
+        match *self {
+            BitSetKind::Plain => 0,
+            BitSetKind::Roaring => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<BitSetKind> {
+        match tag {
+            0 => Some(BitSetKind::Plain),
+            1 => Some(BitSetKind::Roaring),
+            _ => None,
+        }
+    }
+}
+
+/// The file header followed by the size and the max_bit of the set.
+static  HEADER_BYTE_SIZE: u64 = 8 + 2*8;
+
+/// The number of bytes read at once when scanning for set bits.
+static SCAN_CHUNK_BYTE_SIZE: u64 = 4096;
+
+/// The largest number of bytes `insert_many` and `remove_many` read and write at once.
+static RUN_MAX_BYTE_SIZE: u64 = 1 << 20;
+
+/// Sorts and deduplicates `bits` and splits them into runs, so the bytes of a run can be read and written
+/// at once. Values whose bytes lie more than a scan chunk apart start a new run.
+fn byte_runs(bits: &[u64]) -> Vec<Vec<u64>> {
+    let mut sorted = bits.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    let mut runs: Vec<Vec<u64>> = Vec::new();
+    for bit in sorted {
+        let extends_last_run = match runs.last() {
+            Some(run) => bit / 8 - run[run.len() - 1] / 8 <= SCAN_CHUNK_BYTE_SIZE && bit / 8 - run[0] / 8 < RUN_MAX_BYTE_SIZE,
+            None => false,
+        };
+        if extends_last_run {
+            runs.last_mut().expect("non empty runs").push(bit);
+        }
+        else {
+            runs.push(vec![bit]);
+        }
+    }
+    runs
+}
+
+#[test]
+fn byte_runs_test() {
+    assert!(byte_runs(&[]).is_empty());
+    assert_eq!(byte_runs(&[9, 3, 9, 0]), vec![vec![0, 3, 9]]);
+    let far = (SCAN_CHUNK_BYTE_SIZE + 2) * 8;
+    assert_eq!(byte_runs(&[far, 1, 2]), vec![vec![1, 2], vec![far]]);
+}
+
+#[inline]
+fn word_at(bytes: &[u8], position: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[position .. position + 8]);
+    u64::from_le_bytes(word)
+}
+
+/// Returns the position of the lowest set bit in `bytes`. Zero words are skipped.
+fn first_set_bit(bytes: &[u8]) -> Option<u64> {
+    let mut position = 0;
+    while position + 8 <= bytes.len() {
+        let word = word_at(bytes, position);
+        if word != 0 {
+            return Some(position as u64 * 8 + word.trailing_zeros() as u64);
+        }
+        position += 8;
+    }
+    while position < bytes.len() {
+        if bytes[position] != 0 {
+            return Some(position as u64 * 8 + bytes[position].trailing_zeros() as u64);
+        }
+        position += 1;
+    }
+    None
+}
+
+/// Returns the position of the highest set bit in `bytes`. Zero words are skipped.
+fn last_set_bit(bytes: &[u8]) -> Option<u64> {
+    let mut end = bytes.len();
+    while end >= 8 {
+        let word = word_at(bytes, end - 8);
+        if word != 0 {
+            return Some((end as u64 - 8) * 8 + 63 - word.leading_zeros() as u64);
+        }
+        end -= 8;
+    }
+    while end > 0 {
+        end -= 1;
+        if bytes[end] != 0 {
+            return Some(end as u64 * 8 + 7 - bytes[end].leading_zeros() as u64);
+        }
+    }
+    None
+}
+
+/// Returns the number of set bits in `bytes`.
+fn count_ones(bytes: &[u8]) -> u64 {
+    let mut count = 0;
+    let mut position = 0;
+    while position + 8 <= bytes.len() {
+        count += word_at(bytes, position).count_ones() as u64;
+        position += 8;
+    }
+    for byte in &bytes[position..] {
+        count += byte.count_ones() as u64;
+    }
+    count
+}
+
+#[test]
+fn first_and_last_set_bit_test() {
+    let mut bytes = vec![0u8; 20];
+    assert_eq!(first_set_bit(&bytes), None);
+    assert_eq!(last_set_bit(&bytes), None);
+    bytes[9] = 0b00010100;
+    assert_eq!(first_set_bit(&bytes), Some(74));
+    assert_eq!(last_set_bit(&bytes), Some(76));
+    bytes[17] = 0b00000001;
+    bytes[3] = 0b10000000;
+    assert_eq!(first_set_bit(&bytes), Some(31));
+    assert_eq!(last_set_bit(&bytes), Some(136));
+}
 
 #[derive(Debug)]
-pub struct BitSetFile{
-    file: File, //file_cell: RefCell<File>,
+pub struct BitSetFile<S: RawAccess = File>{
+    file: S,
     max_bit: u64,
     size: u64,
 }
 
-impl BitSetFile {
-    pub fn new(file: File) -> Self {
+impl<S: RawAccess> BitSetFile<S> {
+    pub fn new(file: S) -> Self {
         let mut bsf = BitSetFile{file: file, max_bit: 0, size: 0};
         bsf.write_header().expect("Could not write file header");
         bsf
     }
 
-    pub fn open(file: File) -> Self{
+    pub fn open(file: S) -> ContainerResult<Self>{
         let mut bsf = BitSetFile{file: file, max_bit: 0, size: 0};
+        let (size, max_bit) = try!(bsf.read_header());
+        bsf.size = size;
+        bsf.max_bit = max_bit;
+        Ok(bsf)
+    }
 
-        if let Ok(header) = bsf.read_header(){
-            bsf.size = header.0;
-            bsf.max_bit = header.1;
+    /// Opens a bit set whose header may be stale, e.g. after a crash. The size and the max_bit are
+    /// recomputed from the stored bits and trailing zero bytes are cut off.
+    pub fn recover(file: S) -> ContainerResult<Self>{
+        let mut bsf = BitSetFile{file: file, max_bit: 0, size: 0};
+        let _ = try!(bsf.read_header());
+        let mut bytes = vec![0u8; (try!(bsf.file.len()) - HEADER_BYTE_SIZE) as usize];
+        try!(bsf.file.read_exact_at(&mut bytes, HEADER_BYTE_SIZE));
+        for byte in &bytes {
+            bsf.size += byte.count_ones() as u64;
         }
-        bsf
+        match bytes.iter().rposition(|byte| *byte != 0) {
+            Some(offset) => {
+                bsf.max_bit = max_id_from(offset as u64, bytes[offset]).expect("non empty byte");
+                try!(bsf.file.set_len(offset as u64 + 1 + HEADER_BYTE_SIZE));
+            },
+            None => try!(bsf.file.set_len(HEADER_BYTE_SIZE)),
+        }
+        try!(bsf.write_header());
+        Ok(bsf)
+    }
+
+    /// Adds the values of `bits`, reading and writing the bytes of nearby values at once. Returns the
+    /// number of values that were not already present in the set.
+    pub fn insert_many(&mut self, bits: &[u64]) -> Result<u64>{
+        let mut inserted = 0;
+        let mut max_bit = None;
+        for run in byte_runs(bits) {
+            let lo = run[0] / 8;
+            let mut bytes = vec![0u8; (run[run.len() - 1] / 8 - lo + 1) as usize];
+            try!(self.read_bytes(lo, &mut bytes));
+            for &bit in &run {
+                let (bit_map_offset, mask) = bit_map_offset_and_bit_mask(bit);
+                let byte = &mut bytes[(bit_map_offset - lo) as usize];
+                if *byte & mask != mask {
+                    *byte |= mask;
+                    inserted += 1;
+                }
+            }
+            try!(self.file.write_at(&bytes, lo + HEADER_BYTE_SIZE));
+            max_bit = Some(run[run.len() - 1]);
+        }
+        if inserted > 0 {
+            let max_bit = max_bit.expect("inserted values");
+            self.max_bit = if self.is_empty() { max_bit } else { cmp::max(max_bit, self.max_bit) };
+            self.size += inserted;
+        }
+        Ok(inserted)
+    }
+
+    /// Removes the values of `bits`, reading and writing the bytes of nearby values at once. Returns the
+    /// number of values that were present in the set.
+    pub fn remove_many(&mut self, bits: &[u64]) -> Result<u64>{
+        let bits: Vec<u64> = bits.iter().cloned().filter(|&bit| !self.is_empty() && bit <= self.max_bit).collect();
+        let mut removed = 0;
+        for run in byte_runs(&bits) {
+            let lo = run[0] / 8;
+            let mut bytes = vec![0u8; (run[run.len() - 1] / 8 - lo + 1) as usize];
+            try!(self.read_bytes(lo, &mut bytes));
+            for &bit in &run {
+                let (bit_map_offset, mask) = bit_map_offset_and_bit_mask(bit);
+                let byte = &mut bytes[(bit_map_offset - lo) as usize];
+                if *byte & mask == mask {
+                    *byte &= !mask;
+                    removed += 1;
+                }
+            }
+            try!(self.file.write_at(&bytes, lo + HEADER_BYTE_SIZE));
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        self.size -= removed;
+        if self.is_empty() {
+            self.max_bit = 0;
+            try!(self.file.set_len(HEADER_BYTE_SIZE));
+        }
+        else if !try!(self.contains(self.max_bit)) {
+            let max_bit = try!(self.prev_set_bit(self.max_bit)).expect("bit set holds more values");
+            self.max_bit = max_bit;
+            try!(self.file.set_len(max_bit / 8 + 1 + HEADER_BYTE_SIZE));
+        }
+        Ok(removed)
     }
 
     /// Adds a value to the set. Returns `true` if the value was not already present in the set.
     pub fn insert(&mut self, bit: u64) -> Result<bool>{
         let (bit_map_offset, mask) = bit_map_offset_and_bit_mask(bit);
-        let _ = try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-
         let was_set;
-        if self.is_empty() || (bit > self.max_bit && mask == 1) {
-            try!(self.file.write_u8(mask));
+        if self.is_empty() || bit_map_offset > self.max_bit / 8 {
+            try!(self.write_byte(bit_map_offset + HEADER_BYTE_SIZE, mask));
             was_set = false;
         }
         else {
-            let byte = try!(self.file.read_u8());
-            try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-            try!(self.file.write_u8(byte | mask));
+            let byte = try!(self.read_byte(bit_map_offset + HEADER_BYTE_SIZE));
+            try!(self.write_byte(bit_map_offset + HEADER_BYTE_SIZE, byte | mask));
             was_set = byte & mask == mask;
         }
         if !was_set {
@@ -108,16 +422,234 @@ impl BitSetFile {
         Ok(!was_set)
     }
 
-    pub fn contains(&mut self, bit: u64) -> Result<bool>{
+    pub fn contains(&self, bit: u64) -> Result<bool>{
         if self.size() > 0 && /*0 <= bit &&*/ bit <= self.max_bit {
             let (bit_map_offset, mask) = bit_map_offset_and_bit_mask(bit);
-            try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-            let byte = try!(self.file.read_u8());
+            let byte = try!(self.read_byte(bit_map_offset + HEADER_BYTE_SIZE));
             return Ok(byte & mask == mask);
         }
         Ok(false)
     }
 
+    /// Returns the smallest value in the set that is not smaller than `from`.
+    pub fn next_set_bit(&self, from: u64) -> Result<Option<u64>>{
+        if self.is_empty() || from > self.max_bit {
+            return Ok(None);
+        }
+        let end = self.max_bit / 8 + 1;
+        let mut offset = from / 8;
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        while offset < end {
+            let len = cmp::min(SCAN_CHUNK_BYTE_SIZE, end - offset) as usize;
+            try!(self.file.read_exact_at(&mut buffer[..len], offset + HEADER_BYTE_SIZE));
+            if offset == from / 8 {
+                buffer[0] &= 0xFF << (from % 8);
+            }
+            if let Some(bit) = first_set_bit(&buffer[..len]) {
+                return Ok(Some(offset * 8 + bit));
+            }
+            offset += len as u64;
+        }
+        Ok(None)
+    }
+
+    /// Returns the smallest value not in the set that is not smaller than `from`. Full words are skipped.
+    pub fn next_clear_bit(&self, from: u64) -> Result<u64>{
+        if self.is_empty() || from > self.max_bit {
+            return Ok(from);
+        }
+        let end = self.max_bit / 8 + 1;
+        let mut offset = from / 8;
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        while offset < end {
+            let len = cmp::min(SCAN_CHUNK_BYTE_SIZE, end - offset) as usize;
+            try!(self.file.read_exact_at(&mut buffer[..len], offset + HEADER_BYTE_SIZE));
+            if offset == from / 8 {
+                buffer[0] |= !(0xFF << (from % 8));
+            }
+            for byte in &mut buffer[..len] {
+                *byte = !*byte;
+            }
+            if let Some(bit) = first_set_bit(&buffer[..len]) {
+                return Ok(offset * 8 + bit);
+            }
+            offset += len as u64;
+        }
+        Ok(end * 8)
+    }
+
+    /// Returns the largest value in the set that is not larger than `from`.
+    pub fn prev_set_bit(&self, from: u64) -> Result<Option<u64>>{
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let from = cmp::min(from, self.max_bit);
+        let mut end = from / 8 + 1;
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        while end > 0 {
+            let offset = end - cmp::min(SCAN_CHUNK_BYTE_SIZE, end);
+            let len = (end - offset) as usize;
+            try!(self.file.read_exact_at(&mut buffer[..len], offset + HEADER_BYTE_SIZE));
+            if end == from / 8 + 1 {
+                buffer[len - 1] &= 0xFF >> (7 - from % 8);
+            }
+            if let Some(bit) = last_set_bit(&buffer[..len]) {
+                return Ok(Some(offset * 8 + bit));
+            }
+            end = offset;
+        }
+        Ok(None)
+    }
+
+    /// Returns the number of values in `lo .. hi`. The bits are counted a chunk at a time.
+    pub fn count_range(&self, lo: u64, hi: u64) -> Result<u64>{
+        if self.is_empty() || lo >= hi || lo > self.max_bit {
+            return Ok(0);
+        }
+        let hi = cmp::min(hi, self.max_bit + 1);
+        let (first, last) = (lo / 8, (hi - 1) / 8);
+        let mut count = 0;
+        let mut offset = first;
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        while offset <= last {
+            let len = cmp::min(SCAN_CHUNK_BYTE_SIZE, last + 1 - offset) as usize;
+            try!(self.file.read_exact_at(&mut buffer[..len], offset + HEADER_BYTE_SIZE));
+            if offset == first {
+                buffer[0] &= 0xFF << (lo % 8);
+            }
+            if offset + len as u64 - 1 == last {
+                buffer[len - 1] &= 0xFF >> (7 - (hi - 1) % 8);
+            }
+            count += count_ones(&buffer[..len]);
+            offset += len as u64;
+        }
+        Ok(count)
+    }
+
+    /// Returns the number of values smaller than `bit`.
+    pub fn rank(&self, bit: u64) -> Result<u64>{
+        self.count_range(0, bit)
+    }
+
+    /// Returns the value at position `n` of the ascending values, starting with 0. Whole chunks are
+    /// skipped by their number of set bits.
+    pub fn select(&self, n: u64) -> Result<Option<u64>>{
+        if n >= self.size {
+            return Ok(None);
+        }
+        let end = self.max_bit / 8 + 1;
+        let mut remaining = n;
+        let mut offset = 0;
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        while offset < end {
+            let len = cmp::min(SCAN_CHUNK_BYTE_SIZE, end - offset) as usize;
+            try!(self.file.read_exact_at(&mut buffer[..len], offset + HEADER_BYTE_SIZE));
+            let count = count_ones(&buffer[..len]);
+            if remaining >= count {
+                remaining -= count;
+                offset += len as u64;
+                continue;
+            }
+            for (position, byte) in buffer[..len].iter().enumerate() {
+                let ones = byte.count_ones() as u64;
+                if remaining >= ones {
+                    remaining -= ones;
+                    continue;
+                }
+                let mut byte = *byte;
+                for _ in 0 .. remaining {
+                    byte &= byte - 1;
+                }
+                return Ok(Some((offset + position as u64) * 8 + byte.trailing_zeros() as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Adds all values of `other` to the set.
+    pub fn union_with<T: RawAccess>(&mut self, other: &BitSetFile<T>) -> Result<()>{
+        self.combine_with(other, |a, b| a | b)
+    }
+
+    /// Removes all values that are not in `other` from the set.
+    pub fn intersect_with<T: RawAccess>(&mut self, other: &BitSetFile<T>) -> Result<()>{
+        self.combine_with(other, |a, b| a & b)
+    }
+
+    /// Removes all values of `other` from the set.
+    pub fn difference_with<T: RawAccess>(&mut self, other: &BitSetFile<T>) -> Result<()>{
+        self.combine_with(other, |a, b| a & !b)
+    }
+
+    /// Streams the values of both sets in ascending order without changing them.
+    pub fn union<'a, T: RawAccess>(&'a self, other: &'a BitSetFile<T>) -> SetOperationIterator<'a> {
+        SetOperationIterator::new(SetOperation::Union, self, other)
+    }
+
+    /// Streams the values contained in both sets in ascending order without changing them.
+    pub fn intersection<'a, T: RawAccess>(&'a self, other: &'a BitSetFile<T>) -> SetOperationIterator<'a> {
+        SetOperationIterator::new(SetOperation::Intersection, self, other)
+    }
+
+    /// Streams the values that are not in `other` in ascending order without changing the sets.
+    pub fn difference<'a, T: RawAccess>(&'a self, other: &'a BitSetFile<T>) -> SetOperationIterator<'a> {
+        SetOperationIterator::new(SetOperation::Difference, self, other)
+    }
+
+    /// The number of bytes holding bits.
+    fn byte_len(&self) -> u64 {
+        if self.is_empty() { 0 } else { self.max_bit / 8 + 1 }
+    }
+
+    /// Reads the bytes at `offset`. Bytes behind the last byte holding bits are set to 0.
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<()>{
+        let available = cmp::min(self.byte_len().saturating_sub(offset), buffer.len() as u64) as usize;
+        if available > 0 {
+            try!(self.file.read_exact_at(&mut buffer[..available], offset + HEADER_BYTE_SIZE));
+        }
+        for byte in &mut buffer[available..] {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    /// Replaces every byte of the set with `f` of itself and the byte of `other` at the same offset,
+    /// a chunk at a time.
+    fn combine_with<T: RawAccess, F>(&mut self, other: &BitSetFile<T>, f: F) -> Result<()> where F: Fn(u8, u8) -> u8 {
+        let len = cmp::max(self.byte_len(), other.byte_len());
+        let mut buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        let mut other_buffer = vec![0u8; SCAN_CHUNK_BYTE_SIZE as usize];
+        let mut size = 0;
+        let mut last_byte = None;
+        let mut offset = 0;
+        while offset < len {
+            let chunk_len = cmp::min(SCAN_CHUNK_BYTE_SIZE, len - offset) as usize;
+            try!(self.read_bytes(offset, &mut buffer[..chunk_len]));
+            try!(other.read_bytes(offset, &mut other_buffer[..chunk_len]));
+            for (byte, other_byte) in buffer[..chunk_len].iter_mut().zip(&other_buffer[..chunk_len]) {
+                *byte = f(*byte, *other_byte);
+            }
+            size += count_ones(&buffer[..chunk_len]);
+            if let Some(position) = buffer[..chunk_len].iter().rposition(|byte| *byte != 0) {
+                last_byte = Some((offset + position as u64, buffer[position]));
+            }
+            try!(self.file.write_at(&buffer[..chunk_len], offset + HEADER_BYTE_SIZE));
+            offset += chunk_len as u64;
+        }
+        self.size = size;
+        match last_byte {
+            Some((offset, byte)) => {
+                self.max_bit = max_id_from(offset, byte).expect("non empty byte");
+                try!(self.file.set_len(offset + 1 + HEADER_BYTE_SIZE));
+            },
+            None => {
+                self.max_bit = 0;
+                try!(self.file.set_len(HEADER_BYTE_SIZE));
+            },
+        }
+        Ok(())
+    }
+
     pub fn max_bit(&self) -> Option<u64> {
         if self.size() > 0 {
             Some(self.max_bit)
@@ -145,8 +677,7 @@ impl BitSetFile {
         if !self.is_empty() && /*0 <= bit &&*/ bit <= self.max_bit {
             let (mut bit_map_offset, mask) = bit_map_offset_and_bit_mask(bit);
             //println!("[BitSetFile::unset_bit] ID: {} reserved_map_pos: {} mask: {:b}", bit, bit_map_offset, mask);
-            let _ = try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-            let mut byte = try!(self.file.read_u8());
+            let mut byte = try!(self.read_byte(bit_map_offset + HEADER_BYTE_SIZE));
             //println!("[BitSetFile::unset_bit] old byte: {:b}", byte);
             let bit_was_set = byte & mask == mask;
             if bit_was_set{
@@ -160,11 +691,13 @@ impl BitSetFile {
                 }
                 else if bit == self.max_bit {
                     //println!("[BitSetFile::unset_bit] bit {} is max_bit: {}", bit, self.max_bit);
+                    if byte != 0 {
+                        try!(self.write_byte(bit_map_offset + HEADER_BYTE_SIZE, byte));
+                    }
 
                     while bit_map_offset > 0 && byte == 0 {
                         bit_map_offset -= 1;
-                        let _ = try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-                        byte = try!(self.file.read_u8());
+                        byte = try!(self.read_byte(bit_map_offset + HEADER_BYTE_SIZE));
                         //println!("[BitSetFile::unset_bit] LOOP Bit:{} bit_map_offset:{} byte:{:b} ",bit, bit_map_offset, byte);
                     }
                     try!(self.file.set_len(bit_map_offset + 1 + HEADER_BYTE_SIZE)); //+1
@@ -174,8 +707,7 @@ impl BitSetFile {
                 }
                 else{
                     //println!("[delete] no_shrink: {:b},  bit_map_pos: {}", byte, bit_map_offset);
-                    try!(self.file.seek(SeekFrom::Start(bit_map_offset + HEADER_BYTE_SIZE)));
-                    try!(self.file.write_u8(byte));
+                    try!(self.write_byte(bit_map_offset + HEADER_BYTE_SIZE, byte));
                     }
 
                 self.size -= 1;
@@ -186,85 +718,179 @@ impl BitSetFile {
         Ok(false)
     }
 
-    pub fn contains_iter(&mut self) -> ContainsIterator {
-        ContainsIterator{lower_next: 0, upper_next: self.max_bit, done: self.is_empty(), bsf: self}
+    /// Writes the header and flushes the bit set to disc.
+    pub fn sync(&mut self) -> Result<()>{
+        try!(self.write_header());
+        self.file.sync()
     }
 
-    fn read_header(&mut self) -> Result<(u64,u64)> {
-        let _ = try!(self.file.seek(SeekFrom::Start(0)));
-        let size = try!(self.file.read_u64::<NativeEndian>());
-        let max_bit = try!(self.file.read_u64::<NativeEndian>());
+    pub fn contains_iter(&self) -> ContainsIterator<'_> {
+        ContainsIterator::new(self)
+    }
+
+    fn read_byte(&self, position: u64) -> Result<u8> {
+        let mut byte = [0u8; 1];
+        try!(self.file.read_exact_at(&mut byte, position));
+        Ok(byte[0])
+    }
+
+    fn write_byte(&mut self, position: u64, byte: u8) -> Result<()> {
+        self.file.write_at(&[byte], position)
+    }
+
+    fn read_header(&mut self) -> ContainerResult<(u64,u64)> {
+        try!(header::read_header(&self.file, BIT_SET_MAGIC));
+        let mut bytes = [0u8; 2 * 8];
+        try!(self.file.read_exact_at(&mut bytes, header::HEADER_BYTE_SIZE));
+        let mut reader = &bytes[..];
+        let size = try!(reader.read_u64::<LittleEndian>());
+        let max_bit = try!(reader.read_u64::<LittleEndian>());
         Ok((size, max_bit))
     }
 
     fn write_header(&mut self) -> Result<()>{
-        let _ = try!(self.file.seek(SeekFrom::Start(0)));
-        let _ = try!(self.file.write_u64::<NativeEndian>(self.size));
-        let _ = try!(self.file.write_u64::<NativeEndian>(self.max_bit));
-        Ok(())
+        try!(header::write_header(&mut self.file, BIT_SET_MAGIC));
+        let mut bytes = Vec::with_capacity(2 * 8);
+        try!(bytes.write_u64::<LittleEndian>(self.size));
+        try!(bytes.write_u64::<LittleEndian>(self.max_bit));
+        self.file.write_at(&bytes, header::HEADER_BYTE_SIZE)
     }
 }
 
-impl Drop for BitSetFile{
+impl<S: RawAccess> BitSet for BitSetFile<S> {
+    fn insert(&mut self, bit: u64) -> Result<bool> { BitSetFile::insert(self, bit) }
+    fn remove(&mut self, bit: u64) -> Result<bool> { BitSetFile::remove(self, bit) }
+    fn insert_many(&mut self, bits: &[u64]) -> Result<u64> { BitSetFile::insert_many(self, bits) }
+    fn remove_many(&mut self, bits: &[u64]) -> Result<u64> { BitSetFile::remove_many(self, bits) }
+    fn contains(&self, bit: u64) -> Result<bool> { BitSetFile::contains(self, bit) }
+    fn max_bit(&self) -> Option<u64> { BitSetFile::max_bit(self) }
+    fn size(&self) -> u64 { BitSetFile::size(self) }
+    fn clear(&mut self) -> Result<()> { BitSetFile::clear(self) }
+    fn sync(&mut self) -> Result<()> { BitSetFile::sync(self) }
+    fn next_set_bit(&self, from: u64) -> Result<Option<u64>> { BitSetFile::next_set_bit(self, from) }
+    fn prev_set_bit(&self, from: u64) -> Result<Option<u64>> { BitSetFile::prev_set_bit(self, from) }
+    fn next_clear_bit(&self, from: u64) -> Result<u64> { BitSetFile::next_clear_bit(self, from) }
+    fn count_range(&self, lo: u64, hi: u64) -> Result<u64> { BitSetFile::count_range(self, lo, hi) }
+    fn select(&self, n: u64) -> Result<Option<u64>> { BitSetFile::select(self, n) }
+}
+
+impl<S: RawAccess> Drop for BitSetFile<S>{
     fn drop(&mut self) {
-        let _ = self.file.sync_data();
+        let _ = self.file.sync();
         self.write_header().expect("Could not write header file");
     }
 }
 
+/// Iterates over the values of a `BitSet` in ascending or descending order. Each step scans for the
+/// next set bit, so the cost depends on the number of values and not on the largest one.
 pub struct ContainsIterator<'a> {
     lower_next: u64, // TODO: investigate how to use RangeInclusive (...) for this.
     upper_next: u64,
-    done: bool,
-    bsf: &'a mut BitSetFile,
+    remaining: u64,
+    bsf: &'a BitSet,
+}
+
+impl<'a> ContainsIterator<'a> {
+    pub fn new(bsf: &'a BitSet) -> ContainsIterator<'a> {
+        ContainsIterator{lower_next: 0, upper_next: bsf.max_bit().unwrap_or(0), remaining: bsf.size(), bsf: bsf}
+    }
 }
 
 impl<'a> Iterator for ContainsIterator<'a> {
     type Item = u64;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.done{
+        if self.remaining == 0 {
             return None
         }
-        while self.lower_next <= self.upper_next{
-            let cur_bit = self.lower_next;
-            self.lower_next += 1;
-            if self.bsf.contains(cur_bit).expect("can not read bit") {
-                return Some(cur_bit);
-            }
-        }
-        None
+        let bit = self.bsf.next_set_bit(self.lower_next).expect("can not read bit").expect("bit set holds more values");
+        self.lower_next = bit + 1;
+        self.remaining -= 1;
+        Some(bit)
     }
 
     fn size_hint(&self) -> (usize, Option<usize>){
-        (0, Some(self.bsf.size() as usize))
+        (self.remaining as usize, Some(self.remaining as usize))
     }
 
 }
 
 impl<'a> DoubleEndedIterator for ContainsIterator<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        if self.done{
+        if self.remaining == 0 {
             return None
         }
-        while self.lower_next <= self.upper_next{
-            let cur_bit = self.upper_next;
-            if cur_bit == 0 {
-                self.done = true;
-            }
-            else {
-                self.upper_next -= 1;
-            }
-            if self.bsf.contains(cur_bit).expect("can not use BlockFileContainer") {
-                return Some(cur_bit);
-            }
-        }
-        None
+        let bit = self.bsf.prev_set_bit(self.upper_next).expect("can not read bit").expect("bit set holds more values");
+        self.upper_next = bit.saturating_sub(1);
+        self.remaining -= 1;
+        Some(bit)
     }
 }
 
 impl<'a> ExactSizeIterator for ContainsIterator<'a> {
     fn len(&self) -> usize {
-        return self.bsf.size() as usize
+        self.remaining as usize
+    }
+}
+
+/// Streams the result of a set operation on two `BitSet`s in ascending order. Neither set is changed.
+pub struct SetOperationIterator<'a> {
+    operation: SetOperation,
+    next: Option<u64>,
+    a: &'a BitSet,
+    b: &'a BitSet,
+}
+
+impl<'a> SetOperationIterator<'a> {
+    pub fn new(operation: SetOperation, a: &'a BitSet, b: &'a BitSet) -> SetOperationIterator<'a> {
+        SetOperationIterator{operation: operation, next: Some(0), a: a, b: b}
+    }
+
+    fn find(&mut self, mut from: u64) -> Result<Option<u64>> {
+        match self.operation {
+            SetOperation::Union => {
+                let a = try!(self.a.next_set_bit(from));
+                let b = try!(self.b.next_set_bit(from));
+                Ok(match (a, b) {
+                    (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                    (a, None) => a,
+                    (None, b) => b,
+                })
+            },
+            SetOperation::Intersection => loop {
+                let a = match try!(self.a.next_set_bit(from)) {
+                    Some(a) => a,
+                    None => return Ok(None),
+                };
+                match try!(self.b.next_set_bit(a)) {
+                    Some(b) if b == a => return Ok(Some(a)),
+                    Some(b) => from = b,
+                    None => return Ok(None),
+                }
+            },
+            SetOperation::Difference => loop {
+                let a = match try!(self.a.next_set_bit(from)) {
+                    Some(a) => a,
+                    None => return Ok(None),
+                };
+                if !try!(self.b.contains(a)) {
+                    return Ok(Some(a));
+                }
+                from = a + 1;
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for SetOperationIterator<'a> {
+    type Item = u64;
+    fn next(&mut self) -> Option<Self::Item> {
+        let from = match self.next {
+            Some(from) => from,
+            None => return None,
+        };
+        let bit = self.find(from).expect("can not read bit");
+        self.next = bit.and_then(|bit| bit.checked_add(1));
+        bit
     }
 }
 
@@ -368,3 +994,223 @@ fn bit_set_file_remove(){
     assert_eq!(metadata.len(), HEADER_BYTE_SIZE);
     remove_file(&path).unwrap();
 }
+
+#[test]
+fn bit_set_file_open(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/bitsetfile_open.test");
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+        let mut bit_set_file = BitSetFile::new(file);
+        bit_set_file.insert(3).unwrap();
+        bit_set_file.insert(17).unwrap();
+    }
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let bit_set_file = BitSetFile::open(file).unwrap();
+    assert_eq!(bit_set_file.size(), 2);
+    assert_eq!(bit_set_file.max_bit(), Some(17));
+    assert_eq!(bit_set_file.contains(3).unwrap(), true);
+    assert_eq!(bit_set_file.contains(4).unwrap(), false);
+    assert_eq!(bit_set_file.contains(17).unwrap(), true);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn bit_set_file_recover(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+    use std::mem;
+
+    let path = Path::new("./test_output/bitsetfile_recover.test");
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+        let mut bit_set_file = BitSetFile::new(file);
+        bit_set_file.insert(3).unwrap();
+        bit_set_file.sync().unwrap();
+        bit_set_file.insert(17).unwrap();
+        bit_set_file.insert(18).unwrap();
+        mem::forget(bit_set_file);
+    }
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    assert_eq!(BitSetFile::open(file).unwrap().size(), 1);
+
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let bit_set_file = BitSetFile::recover(file).unwrap();
+    assert_eq!(bit_set_file.size(), 3);
+    assert_eq!(bit_set_file.max_bit(), Some(18));
+    assert_eq!(bit_set_file.contains(17).unwrap(), true);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn bit_set_file_next_and_prev_set_bit(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/bitsetfile_next_and_prev_set_bit.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut bit_set_file = BitSetFile::new(file);
+    assert_eq!(bit_set_file.next_set_bit(0).unwrap(), None);
+    assert_eq!(bit_set_file.prev_set_bit(0).unwrap(), None);
+
+    let bits = vec!(3u64, 64, 65, 40000, 100001);
+    for bit in &bits {
+        bit_set_file.insert(*bit).unwrap();
+    }
+    assert_eq!(bit_set_file.next_set_bit(0).unwrap(), Some(3));
+    assert_eq!(bit_set_file.next_set_bit(3).unwrap(), Some(3));
+    assert_eq!(bit_set_file.next_set_bit(4).unwrap(), Some(64));
+    assert_eq!(bit_set_file.next_set_bit(66).unwrap(), Some(40000));
+    assert_eq!(bit_set_file.next_set_bit(40001).unwrap(), Some(100001));
+    assert_eq!(bit_set_file.next_set_bit(100002).unwrap(), None);
+
+    assert_eq!(bit_set_file.prev_set_bit(u64::max_value()).unwrap(), Some(100001));
+    assert_eq!(bit_set_file.prev_set_bit(100000).unwrap(), Some(40000));
+    assert_eq!(bit_set_file.prev_set_bit(39999).unwrap(), Some(65));
+    assert_eq!(bit_set_file.prev_set_bit(63).unwrap(), Some(3));
+    assert_eq!(bit_set_file.prev_set_bit(2).unwrap(), None);
+
+    assert_eq!(bit_set_file.next_clear_bit(0).unwrap(), 0);
+    assert_eq!(bit_set_file.next_clear_bit(3).unwrap(), 4);
+    assert_eq!(bit_set_file.next_clear_bit(64).unwrap(), 66);
+    assert_eq!(bit_set_file.next_clear_bit(100001).unwrap(), 100002);
+    assert_eq!(BitSet::next_clear_bit(&bit_set_file, 64).unwrap(), 66);
+
+    assert_eq!(bit_set_file.contains_iter().collect::<Vec<u64>>(), bits);
+    assert_eq!(bit_set_file.contains_iter().rev().collect::<Vec<u64>>(), bits.iter().cloned().rev().collect::<Vec<u64>>());
+    let mut iter = bit_set_file.contains_iter();
+    assert_eq!(iter.len(), 5);
+    assert_eq!(iter.next(), Some(3));
+    assert_eq!(iter.next_back(), Some(100001));
+    assert_eq!(iter.len(), 3);
+    assert_eq!(iter.collect::<Vec<u64>>(), vec!(64, 65, 40000));
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn bit_set_file_rank_and_select(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/bitsetfile_rank_and_select.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut bit_set_file = BitSetFile::new(file);
+    let bits: Vec<u64> = (0 .. 100).map(|i| i * 997).collect();
+    for bit in &bits {
+        bit_set_file.insert(*bit).unwrap();
+    }
+    for (position, bit) in bits.iter().enumerate() {
+        assert_eq!(bit_set_file.rank(*bit).unwrap(), position as u64);
+        assert_eq!(bit_set_file.rank(*bit + 1).unwrap(), position as u64 + 1);
+        assert_eq!(bit_set_file.select(position as u64).unwrap(), Some(*bit));
+    }
+    assert_eq!(bit_set_file.select(100).unwrap(), None);
+    assert_eq!(bit_set_file.count_range(997, 997 * 3).unwrap(), 2);
+    assert_eq!(bit_set_file.count_range(998, 999).unwrap(), 0);
+    assert_eq!(bit_set_file.count_range(0, u64::max_value()).unwrap(), 100);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn bit_set_file_set_operations(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    fn bit_set_file(path: &Path, bits: &[u64]) -> BitSetFile {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).expect("Test file not created");
+        let mut bit_set_file = BitSetFile::new(file);
+        for bit in bits {
+            bit_set_file.insert(*bit).unwrap();
+        }
+        bit_set_file
+    }
+
+    let a_path = Path::new("./test_output/bitsetfile_set_operations_a.test");
+    let b_path = Path::new("./test_output/bitsetfile_set_operations_b.test");
+    let a_bits = vec!(1u64, 5, 9, 40000);
+    let b_bits = vec!(5u64, 9, 10, 100000);
+    let mut a = bit_set_file(&a_path, &a_bits);
+    let b = bit_set_file(&b_path, &b_bits);
+    assert_eq!(a.union(&b).collect::<Vec<u64>>(), vec!(1, 5, 9, 10, 40000, 100000));
+    assert_eq!(a.intersection(&b).collect::<Vec<u64>>(), vec!(5, 9));
+    assert_eq!(a.difference(&b).collect::<Vec<u64>>(), vec!(1, 40000));
+    assert_eq!(b.difference(&a).collect::<Vec<u64>>(), vec!(10, 100000));
+
+    a.union_with(&b).unwrap();
+    assert_eq!(a.contains_iter().collect::<Vec<u64>>(), vec!(1, 5, 9, 10, 40000, 100000));
+    assert_eq!(a.size(), 6);
+
+    let mut a = bit_set_file(&a_path, &a_bits);
+    a.intersect_with(&b).unwrap();
+    assert_eq!(a.contains_iter().collect::<Vec<u64>>(), vec!(5, 9));
+    assert_eq!(a.max_bit(), Some(9));
+
+    let mut a = bit_set_file(&a_path, &a_bits);
+    a.difference_with(&b).unwrap();
+    assert_eq!(a.contains_iter().collect::<Vec<u64>>(), vec!(1, 40000));
+    assert_eq!(a.max_bit(), Some(40000));
+    a.difference_with(&b).unwrap();
+    assert_eq!(a.size(), 2);
+
+    let mut a = bit_set_file(&a_path, &[]);
+    a.intersect_with(&b).unwrap();
+    assert!(a.is_empty());
+    assert_eq!(a.max_bit(), None);
+    drop(a);
+    drop(b);
+    remove_file(&a_path).unwrap();
+    remove_file(&b_path).unwrap();
+}
+
+#[test]
+fn bit_set_file_remove_max_bit(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/bitsetfile_remove_max_bit.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut bit_set_file = BitSetFile::new(file);
+    bit_set_file.insert(0).unwrap();
+    bit_set_file.insert(1).unwrap();
+
+    let was_set = bit_set_file.remove(1).unwrap();
+    assert_eq!(was_set, true);
+    assert_eq!(bit_set_file.max_bit(), Some(0));
+    assert_eq!(bit_set_file.contains(1).unwrap(), false);
+
+    let was_not_set = bit_set_file.insert(1).unwrap();
+    assert_eq!(was_not_set, true);
+    assert_eq!(bit_set_file.size(), 2);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn bit_set_file_insert_and_remove_many(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/bitsetfile_insert_and_remove_many.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut bit_set_file = BitSetFile::new(file);
+    bit_set_file.insert(5).unwrap();
+    let far = 8 * 100_000;
+    assert_eq!(bit_set_file.insert_many(&[3, 5, 4, 3, far, 17]).unwrap(), 4);
+    assert_eq!(bit_set_file.size(), 5);
+    assert_eq!(bit_set_file.max_bit(), Some(far));
+    assert_eq!(bit_set_file.contains_iter().collect::<Vec<_>>(), vec![3, 4, 5, 17, far]);
+
+    assert_eq!(bit_set_file.remove_many(&[far, 4, 6, far + 1, 17]).unwrap(), 3);
+    assert_eq!(bit_set_file.size(), 2);
+    assert_eq!(bit_set_file.max_bit(), Some(5));
+    assert_eq!(bit_set_file.contains_iter().collect::<Vec<_>>(), vec![3, 5]);
+    assert_eq!(bit_set_file.file.len().unwrap(), HEADER_BYTE_SIZE + 1);
+
+    assert_eq!(bit_set_file.remove_many(&[3, 5]).unwrap(), 2);
+    assert!(bit_set_file.is_empty());
+    assert_eq!(bit_set_file.file.len().unwrap(), HEADER_BYTE_SIZE);
+    assert_eq!(bit_set_file.insert_many(&[9]).unwrap(), 1);
+    assert_eq!(bit_set_file.max_bit(), Some(9));
+    remove_file(&path).unwrap();
+}
@@ -1,240 +1,1294 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Seek, SeekFrom, Result as IoResult, Read, Write};
-use std::path::Path;
-use container::{Container, VacantEntry, OccupiedEntry, CloneContainer};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Result as IoResult};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::mem;
+use container::{Container, CloneContainer, SharedReadContainer, EntryContainer, Entry, VacantEntry, OccupiedEntry};
 use super::super::error::{ContainerError, Result};
 
-use super::bitsetfile::{BitSetFile, ContainsIterator};
+use super::bitsetfile::{BitSet, BitSetFile, BitSetKind, ContainsIterator, SetOperation, SetOperationIterator};
+use super::roaringfile::RoaringBitSetFile;
 use super::stackfile::StackFile;
+use super::header::{self, HEADER_BYTE_SIZE, CONTAINER_MAGIC, METADATA_MAGIC, WAL_MAGIC, COMPACTION_MAGIC};
+use super::storage::{RawAccess, StorageProvider, FileStorageProvider};
+#[cfg(feature = "mmap")]
+use super::storage::{MmapStorage, MmapStorageProvider};
+use super::wal::{WriteAheadLog, LogRecord};
 
 pub type Id = u64;
 pub type Block = Vec<u8>; //TODO: this should probably be [u8, N] when N is generic...
 
-// pub type BlockFileContainerEntry<'a> = Entry<OccupiedBlockFileContainerEntry<'a>,
-//                                              VacantBlockFileContainerEntry<'a>>;
-//
-// #[derive(Debug)]
-// pub struct VacantBlockFileContainerEntry<'a> {
-//     bfc: &'a mut BlockFileContainer,
-//     id: Id,
-// }
-//
-// impl<'a> VacantEntry for VacantBlockFileContainerEntry<'a> {
-//     type Id = Id;
-//     type Value = Block;
-//     fn insert(self, element: Block){
-//         write_block(&mut self.bfc.container_file, self.id as u64, &element, self.bfc.block_size);
-//     }
-//
-//     fn id(&self) -> Id {
-//         self.id
-//     }
-// }
-//
-// #[derive(Debug)]
-// pub struct OccupiedBlockFileContainerEntry<'a> {
-//     bfc: &'a mut BlockFileContainer,
-//     id: Id,
-//     block: Block,
-// }
-//
-// impl<'a> OccupiedEntry for OccupiedBlockFileContainerEntry<'a> {
-//     type Id = Id;
-//     type Value = Block;
-//
-//     fn id(&self) -> Id {
-//         self.id
-//     }
-//
-//     fn get(&self) -> &Block {
-//         &self.block
-//     }
-//     fn update(&mut self, element: Block) -> Block {
-//         let mut temp = element;
-//         mem::swap(&mut self.block, &mut temp);
-//         write_block(&mut self.bfc.container_file, self.id as u64, &self.block, self.bfc.block_size);
-//         temp
-//     }
-//     fn remove(self) -> Block {
-//         self.bfc.remove(self.id);
-//         self.block
-//     }
-// }
-
-fn write_block(file: &mut File, offset: u64, element: &Block, block_size: usize) -> IoResult<usize> {
+pub type BlockFileContainerEntry<'a, S = File> = Entry<OccupiedBlockFileContainerEntry<'a, S>,
+                                                      VacantBlockFileContainerEntry<'a, S>>;
+
+pub struct VacantBlockFileContainerEntry<'a, S: RawAccess + 'static = File> {
+    bfc: &'a mut BlockFileContainer<S>,
+    id: Id,
+}
+
+impl<'a, S: RawAccess + 'static> VacantEntry for VacantBlockFileContainerEntry<'a, S> {
+    type Id = Id;
+    type Value = Block;
+    type OccupiedEntry = OccupiedBlockFileContainerEntry<'a, S>;
+
+    fn insert(self, element: Block) -> Result<OccupiedBlockFileContainerEntry<'a, S>> {
+        {
+            let parts = self.bfc.parts_mut();
+            try!(check_block_len(&element, parts.block_size));
+            try!(parts.log(LogRecord::Update(self.id, Cow::Borrowed(&element))));
+            try!(write_block(&mut parts.container_file, self.id as u64, &element, parts.block_size));
+            try!(parts.used_bit_map.insert(self.id as u64));
+        }
+        Ok(OccupiedBlockFileContainerEntry{bfc: self.bfc, id: self.id, block: element})
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+}
+
+pub struct OccupiedBlockFileContainerEntry<'a, S: RawAccess + 'static = File> {
+    bfc: &'a mut BlockFileContainer<S>,
+    id: Id,
+    block: Block,
+}
+
+impl<'a, S: RawAccess + 'static> OccupiedEntry for OccupiedBlockFileContainerEntry<'a, S> {
+    type Id = Id;
+    type Value = Block;
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn get(&self) -> &Block {
+        &self.block
+    }
+
+    /// Changes a copy of the block, which replaces the cached block once it is written.
+    fn modify<F>(&mut self, f: F) -> Result<()> where F: FnOnce(&mut Block) {
+        let mut block = self.block.clone();
+        f(&mut block);
+        let _ = try!(self.update(block));
+        Ok(())
+    }
+
+    fn update(&mut self, element: Block) -> Result<Block> {
+        {
+            let parts = self.bfc.parts_mut();
+            try!(check_block_len(&element, parts.block_size));
+            try!(parts.log(LogRecord::Update(self.id, Cow::Borrowed(&element))));
+            try!(write_block(&mut parts.container_file, self.id as u64, &element, parts.block_size));
+        }
+        Ok(mem::replace(&mut self.block, element))
+    }
+
+    fn remove(self) -> Result<Block> {
+        let _ = try!(self.bfc.remove(self.id));
+        Ok(self.block)
+    }
+}
+
+/// The order in which `BlockFileContainer::reserve` hands out the ids of removed blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReusePolicy {
+    /// The most recently removed id first. Free ids are kept in the `.flt` stack.
+    Lifo,
+    /// The lowest free id first. Free ids are found in the `.rbm` bit set, which keeps the container file short.
+    LowestFirst,
+}
+
+impl ReusePolicy {
+    pub fn tag(&self) -> u8 {
+        match *self {
+            ReusePolicy::Lifo => 0,
+            ReusePolicy::LowestFirst => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<ReusePolicy> {
+        match tag {
+            0 => Some(ReusePolicy::Lifo),
+            1 => Some(ReusePolicy::LowestFirst),
+            _ => None,
+        }
+    }
+}
+
+/// The bookkeeping stored in the `.mtd` file of a `BlockFileContainer` after the file header.
+#[derive(Debug, PartialEq)]
+struct Metadata {
+    block_size: usize,
+    reserved: u64,
+    used: u64,
+    bit_set_kind: BitSetKind,
+    reuse_policy: ReusePolicy,
+}
+
+impl Metadata {
+    /// The number of bytes behind the file header.
+    const BYTE_SIZE: usize = 3 * 8 + 1 + 1;
+
+    fn read_from<S: RawAccess>(file: &S) -> Result<Metadata> {
+        try!(header::read_header(file, METADATA_MAGIC));
+        let mut bytes = [0u8; Metadata::BYTE_SIZE];
+        try!(file.read_exact_at(&mut bytes, HEADER_BYTE_SIZE));
+        let mut reader = &bytes[..];
+        let block_size = try!(reader.read_u64::<LittleEndian>());
+        let reserved = try!(reader.read_u64::<LittleEndian>());
+        let used = try!(reader.read_u64::<LittleEndian>());
+        let bit_set_kind = match BitSetKind::from_tag(try!(reader.read_u8())) {
+            Some(bit_set_kind) => bit_set_kind,
+            None => return Err(ContainerError::InvalidMetadata),
+        };
+        let reuse_policy = match ReusePolicy::from_tag(try!(reader.read_u8())) {
+            Some(reuse_policy) => reuse_policy,
+            None => return Err(ContainerError::InvalidMetadata),
+        };
+        Ok(Metadata{block_size: block_size as usize, reserved: reserved, used: used, bit_set_kind: bit_set_kind,
+                    reuse_policy: reuse_policy})
+    }
+
+    fn write_to<S: RawAccess>(&self, file: &mut S) -> IoResult<()> {
+        try!(header::write_header(file, METADATA_MAGIC));
+        let mut bytes = Vec::with_capacity(Metadata::BYTE_SIZE);
+        try!(bytes.write_u64::<LittleEndian>(self.block_size as u64));
+        try!(bytes.write_u64::<LittleEndian>(self.reserved));
+        try!(bytes.write_u64::<LittleEndian>(self.used));
+        try!(bytes.write_u8(self.bit_set_kind.tag()));
+        try!(bytes.write_u8(self.reuse_policy.tag()));
+        file.write_at(&bytes, HEADER_BYTE_SIZE)
+    }
+}
+
+/// A roaring bit set replaces its file atomically, so it needs a storage with a path.
+fn roaring_path<S: RawAccess>(provider: &StorageProvider<Storage = S>, extension: &str) -> IoResult<PathBuf> {
+    match provider.path(extension) {
+        Some(path) => Ok(path),
+        None => Err(Error::new(ErrorKind::InvalidInput, "roaring bit sets need a storage with a path")),
+    }
+}
+
+fn create_bit_set<S: RawAccess + 'static>(provider: &StorageProvider<Storage = S>, extension: &str, kind: BitSetKind) -> IoResult<Box<BitSet>> {
+    match kind {
+        BitSetKind::Plain => Ok(Box::new(BitSetFile::new(try!(provider.create(extension))))),
+        BitSetKind::Roaring => Ok(Box::new(try!(RoaringBitSetFile::new(&try!(roaring_path(provider, extension)))))),
+    }
+}
+
+/// Opens a bit set. With `recover` the stale header a crash may leave in a plain bit set is recomputed.
+fn open_bit_set<S: RawAccess + 'static>(provider: &StorageProvider<Storage = S>, extension: &str, kind: BitSetKind, recover: bool) -> Result<Box<BitSet>> {
+    match kind {
+        BitSetKind::Plain => {
+            let storage = try!(provider.open(extension));
+            if recover {
+                Ok(Box::new(try!(BitSetFile::recover(storage))))
+            }
+            else {
+                Ok(Box::new(try!(BitSetFile::open(storage))))
+            }
+        },
+        BitSetKind::Roaring => Ok(Box::new(try!(RoaringBitSetFile::open(&try!(roaring_path(provider, extension)))))),
+    }
+}
+
+/// Returns up to `len` values of `bit_set` starting with the one at `position`.
+fn page(bit_set: &BitSet, position: u64, len: usize) -> Result<Vec<Id>> {
+    let mut ids = Vec::with_capacity(len);
+    let mut next = try!(bit_set.select(position));
+    while let Some(id) = next {
+        if ids.len() == len {
+            break;
+        }
+        ids.push(id);
+        next = try!(bit_set.next_set_bit(id + 1));
+    }
+    Ok(ids)
+}
+
+/// The length of a container file whose last block is `blocks - 1`.
+fn container_file_len(blocks: u64, block_size: usize) -> u64 {
+    HEADER_BYTE_SIZE + blocks * block_size as u64
+}
+
+/// The length of a container file that ends with the last used block.
+pub(crate) fn used_file_len(used_bit_map: &BitSet, block_size: usize) -> u64 {
+    match used_bit_map.max_bit() {
+        Some(max_used_id) => container_file_len(max_used_id + 1, block_size),
+        None => HEADER_BYTE_SIZE,
+    }
+}
+
+pub(crate) fn check_block_len(element: &[u8], block_size: usize) -> IoResult<()> {
     if element.len() > block_size{
         return Err(Error::new(ErrorKind::InvalidInput, "Block size exceeds the containers block_size"));
     }
-    let position = offset * block_size as u64;
-    try!(file.seek(SeekFrom::Start(position)));
-    let bytes_written = try!(file.write(element));
-    //println!("write_block: [{}] = {}", offset, bytes_written);
-    assert!(bytes_written > 0);
-    assert!(bytes_written <= block_size);
-    Ok(bytes_written)
-}
-
-fn read_block(file: &mut File, offset: u64, block_size: usize) -> IoResult<Block> {
-    let block_pos = offset * block_size as u64;
-    try!(file.seek(SeekFrom::Start(block_pos)));
-    let mut buffer = vec![0;block_size];
-    let bytes_read = try!(file.read(&mut buffer));
-    assert!(bytes_read > 0);
-    assert!(bytes_read <= block_size);
+    Ok(())
+}
+
+/// Writes a block in full with a positional write, which is retried until all bytes are written. The bytes
+/// behind a block shorter than `block_size` are written as 0, so they never hold an older block and the
+/// file always covers every used block.
+pub(crate) fn write_block<S: RawAccess>(file: &mut S, offset: u64, element: &Block, block_size: usize) -> IoResult<()> {
+    try!(check_block_len(element, block_size));
+    if element.len() < block_size {
+        let mut padded = Vec::with_capacity(block_size);
+        padded.extend_from_slice(element);
+        padded.resize(block_size, 0);
+        return file.write_at(&padded, container_file_len(offset, block_size));
+    }
+    file.write_at(element, container_file_len(offset, block_size))
+}
+
+/// Reads a block with a positional read, so it only needs a shared reference to the file. Bytes behind
+/// the end of the file, e.g. of a block that was written shorter than `block_size`, are 0.
+pub(crate) fn read_block<S: RawAccess>(file: &S, offset: u64, block_size: usize) -> IoResult<Block> {
+    let mut buffer = vec![0; block_size];
+    let _ = try!(file.read_at(&mut buffer, container_file_len(offset, block_size)));
     Ok(buffer)
 }
 
-pub struct BlockFileContainer {
-    container_file: File,
-    metadata_file: File,
-    reserved_bit_map: BitSetFile,
-    used_bit_map: BitSetFile,
-    free_list_file: StackFile,
-    block_size: usize,
+/// The largest number of bytes `write_blocks` and `read_blocks` transfer at once.
+static RUN_MAX_BYTE_SIZE: usize = 1 << 22;
+
+/// Splits sorted `ids` into runs of adjacent ids whose blocks fit into one transfer.
+fn block_runs(ids: &[Id], block_size: usize) -> Vec<Range<usize>> {
+    let max_run_len = cmp::max(1, RUN_MAX_BYTE_SIZE / block_size);
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for end in 1 .. ids.len() + 1 {
+        if end == ids.len() || ids[end] != ids[end - 1] + 1 || end - start == max_run_len {
+            runs.push(start .. end);
+            start = end;
+        }
+    }
+    runs
+}
+
+/// Writes blocks sorted by id with one positional write per run of adjacent ids. Every block shorter than
+/// `block_size` is padded with zeros, like `write_block` does.
+fn write_blocks<S: RawAccess>(file: &mut S, blocks: &[(Id, Block)], block_size: usize) -> IoResult<()> {
+    let ids: Vec<Id> = blocks.iter().map(|&(id, _)| id).collect();
+    for run in block_runs(&ids, block_size) {
+        let mut buffer = Vec::with_capacity(run.len() * block_size);
+        for (i, (_, block)) in blocks[run.start .. run.end].iter().enumerate() {
+            try!(check_block_len(block, block_size));
+            buffer.resize(i * block_size, 0);
+            buffer.extend_from_slice(block);
+        }
+        buffer.resize(run.len() * block_size, 0);
+        try!(file.write_at(&buffer, container_file_len(ids[run.start], block_size)));
+    }
+    Ok(())
+}
+
+/// Reads the blocks of sorted, distinct `ids` with one positional read per run of adjacent ids.
+fn read_blocks<S: RawAccess>(file: &S, ids: &[Id], block_size: usize) -> IoResult<Vec<Block>> {
+    let mut blocks = Vec::with_capacity(ids.len());
+    for run in block_runs(ids, block_size) {
+        let mut buffer = vec![0; run.len() * block_size];
+        let _ = try!(file.read_at(&mut buffer, container_file_len(ids[run.start], block_size)));
+        blocks.extend(buffer.chunks(block_size).map(|block| block.to_vec()));
+    }
+    Ok(blocks)
+}
+
+fn has_duplicates(ids: &[Id]) -> bool {
+    let mut sorted = ids.to_vec();
+    sorted.sort();
+    sorted.windows(2).any(|pair| pair[0] == pair[1])
+}
+
+/// The free ids of a `BlockFileContainer`: the `.flt` free list and the order they are handed out in. The
+/// reserved ids are passed in, so a `ConcurrentBlockFileContainer` can lock them on their own.
+pub(crate) struct FreeIds<S: RawAccess> {
+    file: StackFile<S>,
+    policy: ReusePolicy,
+    /// No id below this one is free. Only used under `ReusePolicy::LowestFirst`.
+    lowest: Id,
+}
+
+impl<S: RawAccess> FreeIds<S> {
+    fn new(file: StackFile<S>, policy: ReusePolicy) -> FreeIds<S> {
+        FreeIds { file: file, policy: policy, lowest: 0 }
+    }
+
+    /// Returns the id the next `reserve` hands out. Free list entries that are reserved again or lie above
+    /// the largest reserved id are dropped; the latter are handed out anyway once the container grows.
+    pub(crate) fn next(&mut self, reserved: &BitSet) -> Result<Id> {
+        let max_id = match reserved.max_bit() {
+            Some(max_id) => max_id,
+            None => return Ok(0),
+        };
+        match self.policy {
+            ReusePolicy::Lifo => {
+                while let Some(free_id) = try!(self.file.next()) {
+                    if free_id < max_id && !try!(reserved.contains(free_id)) {
+                        return Ok(free_id);
+                    }
+                }
+                Ok(max_id + 1)
+            },
+            ReusePolicy::LowestFirst => Ok(try!(reserved.next_clear_bit(self.lowest))),
+        }
+    }
+
+    /// Returns the ids `n` calls of `reserve` would hand out.
+    fn next_n(&mut self, reserved: &BitSet, n: usize) -> Result<Vec<Id>> {
+        let max_id = match reserved.max_bit() {
+            Some(max_id) => max_id,
+            None => return Ok((0 .. n as Id).collect()),
+        };
+        let mut ids = Vec::with_capacity(n);
+        match self.policy {
+            ReusePolicy::Lifo => {
+                let mut taken = BTreeSet::new();
+                while ids.len() < n {
+                    let free_ids = try!(self.file.pop_many(n - ids.len()));
+                    if free_ids.is_empty() {
+                        break;
+                    }
+                    for free_id in free_ids {
+                        if free_id < max_id && !try!(reserved.contains(free_id)) && taken.insert(free_id) {
+                            ids.push(free_id);
+                        }
+                    }
+                }
+                let next_id = max_id + 1;
+                let missing = (n - ids.len()) as Id;
+                ids.extend(next_id .. next_id + missing);
+            },
+            ReusePolicy::LowestFirst => {
+                let mut from = self.lowest;
+                while ids.len() < n {
+                    let id = try!(reserved.next_clear_bit(from));
+                    ids.push(id);
+                    from = id + 1;
+                }
+            },
+        }
+        Ok(ids)
+    }
+
+    /// Records that `id`, the largest of the ids just handed out, is reserved now.
+    pub(crate) fn taken(&mut self, id: Id) {
+        if self.policy == ReusePolicy::LowestFirst {
+            self.lowest = id + 1;
+        }
+    }
+
+    /// Makes an id that was just removed from `reserved` available again.
+    pub(crate) fn release(&mut self, id: Id, reserved: &BitSet) -> Result<()> {
+        match reserved.max_bit() {
+            None => try!(self.clear()),
+            Some(max_id) => match self.policy {
+                ReusePolicy::Lifo if id < max_id => {
+                    let _ = try!(self.file.add(id));
+                },
+                ReusePolicy::Lifo => {},
+                ReusePolicy::LowestFirst => {
+                    if id < self.lowest {
+                        self.lowest = id;
+                    }
+                },
+            },
+        }
+        Ok(())
+    }
+
+    /// Like `release` for every id, pushing them onto the free list at once.
+    fn release_many(&mut self, ids: &[Id], reserved: &BitSet) -> Result<()> {
+        match reserved.max_bit() {
+            Some(max_id) if self.policy == ReusePolicy::Lifo => {
+                let free_ids: Vec<Id> = ids.iter().cloned().filter(|&id| id < max_id).collect();
+                let _ = try!(self.file.push_many(&free_ids));
+            },
+            _ => {
+                for &id in ids {
+                    try!(self.release(id, reserved));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        try!(self.file.clear());
+        self.lowest = 0;
+        Ok(())
+    }
+
+    fn compact(&mut self, reserved: &BitSet) -> Result<()> {
+        try!(self.clear());
+        if self.policy == ReusePolicy::LowestFirst {
+            return Ok(());
+        }
+        let mut free_ids = Vec::new();
+        let mut next_id = 0;
+        while let Some(id) = try!(reserved.next_set_bit(next_id)) {
+            free_ids.extend(next_id .. id);
+            next_id = id + 1;
+        }
+        free_ids.reverse();
+        let _ = try!(self.file.push_many(&free_ids));
+        Ok(())
+    }
+}
+
+/// The write-ahead log is checkpointed once its records exceed this size.
+pub(crate) static WAL_CHECKPOINT_BYTE_SIZE: u64 = 1 << 22;
+
+/// Stores blocks of a fixed size in a `.ctr` file. The reserved and used ids are tracked in the `.rbm` and
+/// `.ubm` bit sets and removed ids are kept in the `.flt` free list. The bit sets are either plain
+/// `BitSetFile`s or compressed `RoaringBitSetFile`s, chosen when the container is created.
+///
+/// Every free id below the largest reserved id is handed out again before the container grows. Under
+/// `ReusePolicy::Lifo` the free list may hold stale or duplicate entries, which `reserve` skips and
+/// `compact_free_list` drops.
+///
+/// In write-ahead log mode every change is logged to a `.wal` file and synced before the other files are
+/// touched. `open` replays the log, so a crash can not leave the files inconsistent.
+///
+/// `compact` moves the blocks with the highest ids into the holes left by removed ones, so the `.ctr`
+/// file shrinks to the live blocks. The moves are journaled in a `.cmp` file until they are done.
+///
+/// The files are opened by a `StorageProvider`, so besides regular files a container can live in memory
+/// or in memory mapped files. Only plain bit sets work without a file system path.
+pub struct BlockFileContainer<S: RawAccess + 'static = File> {
+    /// Only `None` while `into_parts` takes the container apart.
+    parts: Option<Parts<S>>,
+}
+
+static TAKEN_APART: &str = "block file container taken apart";
+
+/// The fields of a `BlockFileContainer` and the operations on them. A `ConcurrentBlockFileContainer`
+/// takes them apart to lock them one by one.
+pub(crate) struct Parts<S: RawAccess + 'static> {
+    pub(crate) provider: Box<StorageProvider<Storage = S>>,
+    pub(crate) container_file: S,
+    pub(crate) metadata_file: S,
+    pub(crate) reserved_bit_map: Box<BitSet>,
+    pub(crate) used_bit_map: Box<BitSet>,
+    pub(crate) bit_set_kind: BitSetKind,
+    pub(crate) free_ids: FreeIds<S>,
+    pub(crate) block_size: usize,
+    pub(crate) wal: Option<WriteAheadLog<S>>,
+    /// The moves of an interrupted compaction that `open` finished.
+    pub(crate) resumed_compaction: Option<BTreeMap<Id, Id>>,
 }
 
-impl BlockFileContainer {
+impl<S: RawAccess + 'static> Parts<S> {
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn size(&self) -> u64 {
+        self.reserved_bit_map.size()
+    }
+
+    fn reserve(&mut self) -> Result<Id> {
+        self.reserve_with(|_| Ok(()))
+    }
+
+    fn reserve_with<F>(&mut self, before: F) -> Result<Id> where F: FnOnce(Id) -> Result<()> {
+        let id = try!(self.free_ids.next(&*self.reserved_bit_map));
+        if let Err(err) = before(id).and_then(|_| self.log(LogRecord::Reserve(id))) {
+            try!(self.free_ids.release(id, &*self.reserved_bit_map));
+            return Err(err);
+        }
+        try!(self.reserved_bit_map.insert(id as u64));
+        self.free_ids.taken(id);
+        //println!("[reserve] reserved new ID {}", id);
+        Ok(id)
+    }
+
+    fn compact_free_list(&mut self) -> Result<()> {
+        self.free_ids.compact(&*self.reserved_bit_map)
+    }
+
+    fn free_list_len(&self) -> u64 {
+        self.free_ids.file.entries()
+    }
+
+    fn reuse_policy(&self) -> ReusePolicy {
+        self.free_ids.policy
+    }
+
+    fn set_reuse_policy(&mut self, reuse_policy: ReusePolicy) -> Result<()> {
+        if self.free_ids.policy != reuse_policy {
+            self.free_ids.policy = reuse_policy;
+            try!(self.compact_free_list());
+            try!(self.metadata().write_to(&mut self.metadata_file));
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: Id) -> Result<Option<Block>>{
+        try!(self.log(LogRecord::Remove(id)));
+
+        let reserved_was_set = try!(self.reserved_bit_map.remove(id as u64));
+        //println!("[remove] id: {}, reserved_was_set: {}",id, reserved_was_set);
+
+        if reserved_was_set {
+            try!(self.free_ids.release(id, &*self.reserved_bit_map));
+            let used_was_set = try!(self.used_bit_map.remove(id as u64));
+            if used_was_set {
+                let old_block = try!(read_block(&self.container_file, id as u64, self.block_size));
+
+                if self.used_bit_map.is_empty() {
+                    try!(self.container_file.set_len(HEADER_BYTE_SIZE));
+                }
+                else {
+                    if let Some(new_max_used_id) = self.used_bit_map.max_bit(){ //TODO: Investigate if setting the len of the container_file everytime a used block is removed is a bad idea.
+                        try!(self.container_file.set_len(container_file_len(new_max_used_id+1, self.block_size)));
+                    }
+                }
+                Ok(Some(old_block))
+            }
+            else{
+                //println!("[remove] reserved_was_set: {}, used_was_set: {}",reserved_was_set, used_was_set);
+                return Ok(None)
+            }
+        }
+        else{
+            Err(ContainerError::InvalidId)
+        }
+    }
+
+    fn insert_many(&mut self, elements: Vec<Block>) -> Result<Vec<Id>> {
+        if elements.is_empty() {
+            return Ok(Vec::new());
+        }
+        for element in &elements {
+            try!(check_block_len(element, self.block_size));
+        }
+        let ids = try!(self.free_ids.next_n(&*self.reserved_bit_map, elements.len()));
+        let logged = {
+            let mut records = Vec::with_capacity(2 * ids.len());
+            for (&id, element) in ids.iter().zip(&elements) {
+                records.push(LogRecord::Reserve(id));
+                records.push(LogRecord::Update(id, Cow::Borrowed(element)));
+            }
+            self.log_all(&records)
+        };
+        if let Err(err) = logged {
+            // The ids are pushed back in reverse, so the first one handed out is on top again.
+            let popped: Vec<Id> = ids.iter().rev().cloned().collect();
+            try!(self.free_ids.release_many(&popped, &*self.reserved_bit_map));
+            return Err(err);
+        }
+        try!(self.reserved_bit_map.insert_many(&ids));
+        try!(self.used_bit_map.insert_many(&ids));
+        self.free_ids.taken(ids[ids.len() - 1]);
+        let mut blocks: Vec<(Id, Block)> = ids.iter().cloned().zip(elements).collect();
+        blocks.sort_by_key(|&(id, _)| id);
+        try!(write_blocks(&mut self.container_file, &blocks, self.block_size));
+        Ok(ids)
+    }
+
+    fn update_many(&mut self, updates: Vec<(Id, Block)>) -> Result<Vec<Option<Block>>> {
+        let ids: Vec<Id> = updates.iter().map(|&(id, _)| id).collect();
+        if has_duplicates(&ids) {
+            let mut old_blocks = Vec::with_capacity(updates.len());
+            for (id, element) in updates {
+                old_blocks.push(try!(self.update(id, element)));
+            }
+            return Ok(old_blocks);
+        }
+        for &(id, ref element) in &updates {
+            if !try!(self.reserved_bit_map.contains(id)) {
+                return Err(ContainerError::InvalidId);
+            }
+            try!(check_block_len(element, self.block_size));
+        }
+        {
+            let records: Vec<_> = updates.iter().map(|&(id, ref element)| LogRecord::Update(id, Cow::Borrowed(&element[..]))).collect();
+            try!(self.log_all(&records));
+        }
+        let old_blocks = try!(self.get_many(&ids));
+        try!(self.used_bit_map.insert_many(&ids));
+        let mut blocks = updates;
+        blocks.sort_by_key(|&(id, _)| id);
+        try!(write_blocks(&mut self.container_file, &blocks, self.block_size));
+        Ok(old_blocks)
+    }
+
+    fn update(&mut self, id: Id, element: Block) -> Result<Option<Block>> { //TODO: reading the old value for every update my be a performance issue!
+        let reserved_was_set = try!(self.reserved_bit_map.contains(id as u64));
+
+        if reserved_was_set {
+            try!(self.log(LogRecord::Update(id, Cow::Borrowed(&element))));
+            let used_was_not_set = try!(self.used_bit_map.insert(id as u64));
+            //println!("[update] reserved_was_set: {}, used_was_not_set: {}",reserved_was_set, used_was_not_set);
+
+            let old_block;
+            if !used_was_not_set {
+                old_block = Some(try!(read_block(&self.container_file, id as u64, self.block_size)));
+            }
+            else{
+                old_block = None;
+            }
+            try!(write_block(&mut self.container_file, id as u64, &element, self.block_size));
+
+            return Ok(old_block);
+        }
+        Err(ContainerError::InvalidId)
+    }
+
+    fn remove_many(&mut self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        if has_duplicates(ids) {
+            let mut old_blocks = Vec::with_capacity(ids.len());
+            for &id in ids {
+                old_blocks.push(try!(self.remove(id)));
+            }
+            return Ok(old_blocks);
+        }
+        for &id in ids {
+            if !try!(self.reserved_bit_map.contains(id)) {
+                return Err(ContainerError::InvalidId);
+            }
+        }
+        let records: Vec<_> = ids.iter().map(|&id| LogRecord::Remove(id)).collect();
+        try!(self.log_all(&records));
+        let old_blocks = try!(self.get_many(ids));
+        try!(self.reserved_bit_map.remove_many(ids));
+        try!(self.free_ids.release_many(ids, &*self.reserved_bit_map));
+        if try!(self.used_bit_map.remove_many(ids)) > 0 {
+            let container_len = used_file_len(&*self.used_bit_map, self.block_size);
+            try!(self.container_file.set_len(container_len));
+        }
+        Ok(old_blocks)
+    }
+
+    fn get_many(&self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        let mut order = Vec::with_capacity(ids.len());
+        for (i, &id) in ids.iter().enumerate() {
+            if try!(self.contains(id)) && try!(self.used_bit_map.contains(id)) {
+                order.push(i);
+            }
+        }
+        order.sort_by_key(|&i| ids[i]);
+        let mut distinct_ids: Vec<Id> = order.iter().map(|&i| ids[i]).collect();
+        distinct_ids.dedup();
+        let mut blocks = try!(read_blocks(&self.container_file, &distinct_ids, self.block_size));
+
+        let mut results = vec![None; ids.len()];
+        let mut block = 0;
+        for (position, &i) in order.iter().enumerate() {
+            while distinct_ids[block] != ids[i] {
+                block += 1;
+            }
+            let last_request = order.get(position + 1).map_or(true, |&next| ids[next] != ids[i]);
+            results[i] = Some(if last_request { mem::take(&mut blocks[block]) } else { blocks[block].clone() });
+        }
+        Ok(results)
+    }
+
+    fn get(&self, id: Id) -> Result<Option<Block>>{
+        if try!(self.contains(id)) && try!(self.used_bit_map.contains(id as u64)) {
+            let old_block = try!(read_block(&self.container_file, id as u64, self.block_size));
+            return Ok(Some(old_block));
+        }
+        Ok(None)
+    }
+
+    fn contains(&self, id: Id) -> Result<bool> {
+        let contains = try!(self.reserved_bit_map.contains(id));
+        Ok(contains)
+    }
+
+    fn clear(&mut self) -> Result<()>{
+        try!(self.log(LogRecord::Clear));
+        self.clear_files()
+    }
+
+    fn clear_files(&mut self) -> Result<()>{
+        try!(self.free_ids.clear());
+        try!(self.used_bit_map.clear());
+        try!(self.reserved_bit_map.clear());
+        try!(self.container_file.set_len(HEADER_BYTE_SIZE));
+        Ok(())
+    }
+
+    fn reserved_ids(&self) -> ContainsIterator<'_> {
+        ContainsIterator::new(&*self.reserved_bit_map)
+    }
+
+    fn used_ids(&self) -> ContainsIterator<'_> {
+        ContainsIterator::new(&*self.used_bit_map)
+    }
+
+    fn unused_ids(&self) -> SetOperationIterator<'_> {
+        SetOperationIterator::new(SetOperation::Difference, &*self.reserved_bit_map, &*self.used_bit_map)
+    }
+
+    fn reserved_ids_page(&self, position: u64, len: usize) -> Result<Vec<Id>> {
+        page(&*self.reserved_bit_map, position, len)
+    }
+
+    fn used_ids_page(&self, position: u64, len: usize) -> Result<Vec<Id>> {
+        page(&*self.used_bit_map, position, len)
+    }
+
+    fn bit_set_kind(&self) -> BitSetKind {
+        self.bit_set_kind
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        try!(self.metadata().write_to(&mut self.metadata_file));
+        try!(self.metadata_file.sync());
+        try!(self.reserved_bit_map.sync());
+        try!(self.used_bit_map.sync());
+        try!(self.free_ids.file.sync());
+        try!(RawAccess::sync(&mut self.container_file));
+        if let Some(ref mut wal) = self.wal {
+            try!(wal.truncate());
+        }
+        Ok(())
+    }
+
+    fn enable_wal(&mut self) -> Result<()> {
+        if self.wal.is_none() {
+            try!(self.sync());
+            let wal_file = try!(self.provider.create("wal"));
+            self.wal = Some(try!(WriteAheadLog::new(wal_file, WAL_MAGIC)));
+        }
+        Ok(())
+    }
+
+    fn disable_wal(&mut self) -> Result<()> {
+        if self.wal.is_some() {
+            try!(self.sync());
+            self.wal = None;
+            try!(self.provider.remove("wal"));
+        }
+        Ok(())
+    }
+
+    fn has_wal(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    /// Logs a change before it is applied. Does nothing outside of write-ahead log mode.
+    fn log(&mut self, record: LogRecord) -> Result<()> {
+        self.log_all(&[record])
+    }
+
+    /// Logs the changes of a batch before any of them is applied and syncs the log once. A checkpoint is
+    /// only taken before the first record, so the log never drops records of changes not applied yet.
+    fn log_all(&mut self, records: &[LogRecord]) -> Result<()> {
+        if let Some(wal_len) = self.wal.as_ref().map(|wal| wal.len()) {
+            for record in records {
+                if let LogRecord::Update(_, ref element) = *record {
+                    try!(check_block_len(element, self.block_size));
+                }
+            }
+            if wal_len > WAL_CHECKPOINT_BYTE_SIZE {
+                try!(self.sync());
+            }
+            try!(self.wal.as_mut().expect("wal mode").append_all(records));
+        }
+        Ok(())
+    }
+
+    /// Applies all logged changes again and rebuilds the free list from the reserved ids.
+    fn replay(&mut self) -> Result<()> {
+        let records = try!(self.wal.as_mut().expect("wal mode").records());
+        for record in records {
+            match record {
+                LogRecord::Reserve(id) => {
+                    try!(self.reserved_bit_map.insert(id));
+                },
+                LogRecord::Update(id, element) => {
+                    try!(self.reserved_bit_map.insert(id));
+                    try!(self.used_bit_map.insert(id));
+                    try!(write_block(&mut self.container_file, id, &element.into_owned(), self.block_size));
+                },
+                LogRecord::Remove(id) => {
+                    try!(self.reserved_bit_map.remove(id));
+                    try!(self.used_bit_map.remove(id));
+                },
+                LogRecord::Clear => try!(self.clear_files()),
+            }
+        }
+
+        try!(self.compact_free_list());
+        let container_len = used_file_len(&*self.used_bit_map, self.block_size);
+        try!(self.container_file.set_len(container_len));
+        self.sync()
+    }
+
+    fn compact(&mut self) -> Result<BTreeMap<Id, Id>> {
+        try!(self.sync());
+        let moves = try!(self.plan_compaction());
+        try!(self.write_compaction_journal(&moves));
+        try!(self.apply_compaction(&moves));
+        Ok(moves)
+    }
+
+    fn take_resumed_compaction(&mut self) -> Option<BTreeMap<Id, Id>> {
+        self.resumed_compaction.take()
+    }
+
+    fn plan_compaction(&mut self) -> Result<BTreeMap<Id, Id>> {
+        let mut moves = BTreeMap::new();
+        let mut target = 0;
+        let mut next = try!(self.reserved_bit_map.next_set_bit(self.reserved_bit_map.size()));
+        while let Some(id) = next {
+            target = try!(self.reserved_bit_map.next_clear_bit(target));
+            moves.insert(id, target);
+            target += 1;
+            next = try!(self.reserved_bit_map.next_set_bit(id + 1));
+        }
+        Ok(moves)
+    }
+
+    fn write_compaction_journal(&mut self, moves: &BTreeMap<Id, Id>) -> Result<()> {
+        let file = try!(self.provider.create("cmp"));
+        let mut journal = try!(WriteAheadLog::new(file, COMPACTION_MAGIC));
+        for (&old, &new) in moves {
+            let mut payload = Vec::with_capacity(2 * 8);
+            try!(payload.write_u64::<LittleEndian>(old));
+            try!(payload.write_u64::<LittleEndian>(new));
+            try!(journal.push(&payload));
+        }
+        try!(journal.sync());
+        Ok(())
+    }
+
+    /// Reads the moves of the `.cmp` journal. A torn journal yields a prefix of the moves, which is a
+    /// valid compaction on its own since no move is applied before the journal is synced.
+    fn read_compaction_journal(&mut self) -> Result<BTreeMap<Id, Id>> {
+        let file = try!(self.provider.open("cmp"));
+        let mut journal = try!(WriteAheadLog::open(file, COMPACTION_MAGIC));
+        let mut moves = BTreeMap::new();
+        for payload in try!(journal.payloads()) {
+            let mut cursor = Cursor::new(payload);
+            let old = try!(cursor.read_u64::<LittleEndian>());
+            let new = try!(cursor.read_u64::<LittleEndian>());
+            moves.insert(old, new);
+        }
+        Ok(moves)
+    }
+
+    /// Copies a block to its new id. Does nothing if the old id was already removed by an earlier run.
+    fn copy_block(&mut self, old: Id, new: Id) -> Result<()> {
+        if try!(self.reserved_bit_map.contains(old)) {
+            if try!(self.used_bit_map.contains(old)) {
+                let block = try!(read_block(&self.container_file, old, self.block_size));
+                try!(write_block(&mut self.container_file, new, &block, self.block_size));
+                try!(self.used_bit_map.insert(new));
+            }
+            try!(self.reserved_bit_map.insert(new));
+        }
+        Ok(())
+    }
+
+    /// Applies journaled moves. Every step can be repeated, so an interrupted run is finished by applying
+    /// the same moves again. The copies are on disc before any old id is removed.
+    fn apply_compaction(&mut self, moves: &BTreeMap<Id, Id>) -> Result<()> {
+        for (&old, &new) in moves {
+            try!(self.copy_block(old, new));
+        }
+        try!(self.sync());
+        for &old in moves.keys() {
+            try!(self.reserved_bit_map.remove(old));
+            try!(self.used_bit_map.remove(old));
+        }
+        let container_len = used_file_len(&*self.used_bit_map, self.block_size);
+        try!(self.container_file.set_len(container_len));
+        try!(self.compact_free_list());
+        try!(self.sync());
+        try!(self.provider.remove("cmp"));
+        Ok(())
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            block_size: self.block_size,
+            reserved: self.reserved_bit_map.size(),
+            used: self.used_bit_map.size(),
+            bit_set_kind: self.bit_set_kind,
+            reuse_policy: self.free_ids.policy,
+        }
+    }
+
+    /// Writes the metadata when the container is dropped. In write-ahead log mode a checkpoint is taken.
+    fn close(&mut self) {
+        if self.wal.is_some() {
+            self.sync().expect("could not checkpoint the write-ahead log");
+            return;
+        }
+        self.metadata().write_to(&mut self.metadata_file).expect("could not write metadata_file");
+        self.metadata_file.sync().expect("could not write metadata_file");
+    }
+}
+
+impl<S: RawAccess + 'static> BlockFileContainer<S> {
+    fn parts(&self) -> &Parts<S> {
+        self.parts.as_ref().expect(TAKEN_APART)
+    }
+
+    fn parts_mut(&mut self) -> &mut Parts<S> {
+        self.parts.as_mut().expect(TAKEN_APART)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.parts().block_size()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.parts().size()
+    }
+
+    pub fn reserve(&mut self) -> Result<Id> {
+        self.parts_mut().reserve()
+    }
+
+    /// Like `reserve`, but hands the id to `before` before it is reserved. If `before` fails, the id stays
+    /// free. `Transaction` uses it to log an id before the container takes it.
+    pub fn reserve_with<F>(&mut self, before: F) -> Result<Id> where F: FnOnce(Id) -> Result<()> {
+        self.parts_mut().reserve_with(before)
+    }
+
+    /// Rebuilds the free list from the gaps in the reserved ids, dropping stale and duplicate entries. The
+    /// lowest free id ends up on top. Under `ReusePolicy::LowestFirst` the free list is only emptied.
+    pub fn compact_free_list(&mut self) -> Result<()> {
+        self.parts_mut().compact_free_list()
+    }
+
+    /// The number of entries in the free list, including stale ones that `compact_free_list` would drop.
+    pub fn free_list_len(&self) -> u64 {
+        self.parts().free_list_len()
+    }
+
+    pub fn reuse_policy(&self) -> ReusePolicy {
+        self.parts().reuse_policy()
+    }
+
+    /// Changes the order in which free ids are handed out. The policy is kept when the container is opened
+    /// again.
+    pub fn set_reuse_policy(&mut self, reuse_policy: ReusePolicy) -> Result<()> {
+        self.parts_mut().set_reuse_policy(reuse_policy)
+    }
+
+    pub fn remove(&mut self, id: Id) -> Result<Option<Block>> {
+        self.parts_mut().remove(id)
+    }
+
+    /// Inserts all blocks and returns their ids in the same order. The blocks are written with one write
+    /// per run of adjacent ids and the reserved and used bit sets are updated once for the whole batch.
+    pub fn insert_many(&mut self, elements: Vec<Block>) -> Result<Vec<Id>> {
+        self.parts_mut().insert_many(elements)
+    }
+
+    /// Like `update` for every pair, returning the replaced blocks in the same order. The blocks are read
+    /// and written with one transfer per run of adjacent ids and the used bit set is updated once. No block
+    /// is changed if an id is not reserved. If an id occurs twice, the updates are applied one by one.
+    pub fn update_many(&mut self, updates: Vec<(Id, Block)>) -> Result<Vec<Option<Block>>> {
+        self.parts_mut().update_many(updates)
+    }
+
+    pub fn update(&mut self, id: Id, element: Block) -> Result<Option<Block>> {
+        self.parts_mut().update(id, element)
+    }
+
+    /// Like `remove` for every id, returning the removed blocks in the same order. The blocks are read with
+    /// one read per run of adjacent ids and the bit sets and the `.ctr` file are updated once. No block is
+    /// removed if an id is not reserved. If an id occurs twice, the ids are removed one by one.
+    pub fn remove_many(&mut self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        self.parts_mut().remove_many(ids)
+    }
+
+    /// Like `get` for every id, returning the blocks in the same order. Blocks of adjacent ids are read
+    /// at once.
+    pub fn get_many(&self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        self.parts().get_many(ids)
+    }
+
+    pub fn get(&self, id: Id) -> Result<Option<Block>> {
+        self.parts().get(id)
+    }
+
+    pub fn contains(&self, id: Id) -> Result<bool> {
+        self.parts().contains(id)
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.parts_mut().clear()
+    }
+
+    pub fn reserved_ids(&self) -> ContainsIterator<'_> {
+        self.parts().reserved_ids()
+    }
+
+    pub fn used_ids(&self) -> ContainsIterator<'_> {
+        self.parts().used_ids()
+    }
+
+    /// Returns the ids that are reserved but hold no block, e.g. ids reserved by a process that crashed
+    /// before writing them.
+    pub fn unused_ids(&self) -> SetOperationIterator<'_> {
+        self.parts().unused_ids()
+    }
+
+    /// Returns up to `len` reserved ids in ascending order, starting with the one at `position`.
+    pub fn reserved_ids_page(&self, position: u64, len: usize) -> Result<Vec<Id>> {
+        self.parts().reserved_ids_page(position, len)
+    }
+
+    /// Returns up to `len` used ids in ascending order, starting with the one at `position`.
+    pub fn used_ids_page(&self, position: u64, len: usize) -> Result<Vec<Id>> {
+        self.parts().used_ids_page(position, len)
+    }
+
+    pub fn bit_set_kind(&self) -> BitSetKind {
+        self.parts().bit_set_kind()
+    }
+
+    /// Writes the metadata and the bookkeeping of all files to disc. In write-ahead log mode the log is
+    /// truncated afterwards.
+    pub fn sync(&mut self) -> Result<()> {
+        self.parts_mut().sync()
+    }
+
+    /// Switches to write-ahead log mode. The mode is kept when the container is opened again.
+    pub fn enable_wal(&mut self) -> Result<()> {
+        self.parts_mut().enable_wal()
+    }
+
+    /// Leaves write-ahead log mode and removes the `.wal` file.
+    pub fn disable_wal(&mut self) -> Result<()> {
+        self.parts_mut().disable_wal()
+    }
+
+    pub fn has_wal(&self) -> bool {
+        self.parts().has_wal()
+    }
 
-    pub fn block_size(&self) -> usize {
-        self.block_size
+    /// Moves every reserved id that is not lower than `size()` to the lowest free id and truncates the
+    /// `.ctr` file. Ids keep their relative order. Returns the moved ids, mapping old to new ones; callers
+    /// have to replace the ids they hold.
+    ///
+    /// The moves are written to the `.cmp` journal before any block is touched. If the compaction is
+    /// interrupted, `open` finishes it and `take_resumed_compaction` returns its moves.
+    ///
+    /// Only compact a container if every id that refers to it can be rewritten with the moves. Ids stored
+    /// inside the blocks are not: the chains of a `MultiBlockContainer` (the next block in every block
+    /// header and the heads in its `.hbm` file) and the child ids in the nodes of a `BPlusTree` would
+    /// point to other blocks afterwards. Never compact the container of either.
+    pub fn compact(&mut self) -> Result<BTreeMap<Id, Id>> {
+        self.parts_mut().compact()
     }
 
-    pub fn size(&self) -> u64 {
-        self.reserved_bit_map.size()
+    /// Returns the moves of an interrupted compaction that `open` finished, once.
+    pub fn take_resumed_compaction(&mut self) -> Option<BTreeMap<Id, Id>> {
+        self.parts_mut().take_resumed_compaction()
     }
 
-    pub fn reserve(&mut self) -> Result<Id> {
-        let mut id: Id;
-        if let Some(max_id) = self.reserved_bit_map.max_bit(){
-            id = max_id as Id + 1;
-            while let Some(free_id) = try!(self.free_list_file.next()) {
-                if free_id < (max_id) {
-                    id = free_id as Id;
-                    break;
-                }
+    /// Creates a new and empty container in the storages of `provider`. Existing storages are truncated and
+    /// an existing write-ahead log is removed.
+    pub fn create_in<P>(provider: P, block_size: usize, bit_set_kind: BitSetKind) -> IoResult<BlockFileContainer<S>>
+        where P: StorageProvider<Storage = S> + 'static {
+        for extension in &["wal", "cmp"] {
+            if provider.exists(extension) {
+                try!(provider.remove(extension));
             }
-            //println!("[reserve] new free id: {}", id);
-        }
-        else {
-            id = 0;
         }
-        try!(self.reserved_bit_map.insert(id as u64));
-        //println!("[reserve] reserved new ID {}", id);
-        Ok(id)
+        let mut container_file = try!(provider.create("ctr"));
+        try!(header::write_header(&mut container_file, CONTAINER_MAGIC));
+        let metadata_file = try!(provider.create("mtd"));
+        let free_list_file = try!(provider.create("flt"));
+        let reserved_bit_map = try!(create_bit_set(&provider, "rbm", bit_set_kind));
+        let used_bit_map = try!(create_bit_set(&provider, "ubm", bit_set_kind));
+
+        let mut parts = Parts {
+            provider: Box::new(provider),
+            container_file: container_file,
+            metadata_file: metadata_file,
+            reserved_bit_map: reserved_bit_map,
+            used_bit_map: used_bit_map,
+            bit_set_kind: bit_set_kind,
+            free_ids: FreeIds::new(StackFile::new(free_list_file), ReusePolicy::Lifo),
+            block_size: block_size,
+            wal: None,
+            resumed_compaction: None,
+        };
+        try!(parts.metadata().write_to(&mut parts.metadata_file));
+        Ok(BlockFileContainer::from_parts(parts))
     }
 
-    pub fn remove(&mut self, id: Id) -> Result<Option<Block>>{
+    /// Opens an existing container from the storages of `provider`. The block size is read from the `.mtd`
+    /// storage. If the container is in write-ahead log mode, the log is replayed. An interrupted `compact`
+    /// is finished. If the sizes in the `.mtd` storage and the bit sets differ, e.g. after a crash, the
+    /// sizes are recomputed from the bits and the `.mtd` storage is written again.
+    pub fn open_in<P>(provider: P) -> Result<BlockFileContainer<S>> where P: StorageProvider<Storage = S> + 'static {
+        let container_file = try!(provider.open("ctr"));
+        try!(header::read_header(&container_file, CONTAINER_MAGIC));
+        let metadata_file = try!(provider.open("mtd"));
+        let free_list_file = try!(provider.open("flt"));
 
-        let reserved_was_set = try!(self.reserved_bit_map.remove(id as u64));
-        //println!("[remove] id: {}, reserved_was_set: {}",id, reserved_was_set);
+        let metadata = try!(Metadata::read_from(&metadata_file));
+        if metadata.block_size == 0 {
+            return Err(ContainerError::InvalidMetadata);
+        }
 
-        if reserved_was_set {
-            let used_was_set = try!(self.used_bit_map.remove(id as u64));
-            if used_was_set {
-                let old_block = try!(read_block(&mut self.container_file, id as u64, self.block_size));
+        let wal = if provider.exists("wal") {
+            Some(try!(WriteAheadLog::open(try!(provider.open("wal")), WAL_MAGIC)))
+        }
+        else {
+            None
+        };
+        let interrupted_compaction = provider.exists("cmp");
 
-                if self.used_bit_map.is_empty() {
-                    try!(self.free_list_file.clear());
-                    try!(self.container_file.set_len(0));
-                }
-                else {
-                    let _ = try!(self.free_list_file.add(id as u64));
-                    //println!("[remove] free_elements: {}", free_elements);
-                    if let Some(new_max_used_id) = self.used_bit_map.max_bit(){ //TODO: Investigate if setting the len of the container_file everytime a used block is removed is a bad idea.
-                        try!(self.container_file.set_len((new_max_used_id+1)*self.block_size as u64));
-                    }
-                }
-                Ok(Some(old_block))
-            }
-            else{
-                //println!("[remove] reserved_was_set: {}, used_was_set: {}",reserved_was_set, used_was_set);
-                return Ok(None)
-            }
+        let recover = wal.is_some() || interrupted_compaction;
+        let mut reserved_bit_map = try!(open_bit_set(&provider, "rbm", metadata.bit_set_kind, recover));
+        let mut used_bit_map = try!(open_bit_set(&provider, "ubm", metadata.bit_set_kind, recover));
+        let stale = !recover && (reserved_bit_map.size() != metadata.reserved || used_bit_map.size() != metadata.used);
+        if stale {
+            // The bit sets write their stale headers when they are dropped, so they are closed before they
+            // are opened again.
+            drop(reserved_bit_map);
+            drop(used_bit_map);
+            reserved_bit_map = try!(open_bit_set(&provider, "rbm", metadata.bit_set_kind, true));
+            used_bit_map = try!(open_bit_set(&provider, "ubm", metadata.bit_set_kind, true));
         }
-        else{
-            Err(ContainerError::InvalidId)
+
+        let mut parts = Parts {
+            provider: Box::new(provider),
+            container_file: container_file,
+            metadata_file: metadata_file,
+            reserved_bit_map: reserved_bit_map,
+            used_bit_map: used_bit_map,
+            bit_set_kind: metadata.bit_set_kind,
+            free_ids: FreeIds::new(try!(StackFile::open(free_list_file)), metadata.reuse_policy),
+            block_size: metadata.block_size,
+            wal: wal,
+            resumed_compaction: None,
+        };
+        if parts.wal.is_some() {
+            try!(parts.replay());
+        }
+        if interrupted_compaction {
+            let moves = try!(parts.read_compaction_journal());
+            try!(parts.apply_compaction(&moves));
+            parts.resumed_compaction = Some(moves);
         }
+        if stale {
+            // The free list may have lost entries, too.
+            try!(parts.compact_free_list());
+            try!(parts.metadata().write_to(&mut parts.metadata_file));
+            try!(parts.metadata_file.sync());
+        }
+        Ok(BlockFileContainer::from_parts(parts))
     }
 
-    pub fn update(&mut self, id: Id, element: Block) -> Result<Option<Block>> { //TODO: reading the old value for every update my be a performance issue!
-        let reserved_was_set = try!(self.reserved_bit_map.contains(id as u64));
+    /// Opens an existing container and checks that it was created with `block_size`.
+    pub fn open_in_with_block_size<P>(provider: P, block_size: usize) -> Result<BlockFileContainer<S>>
+        where P: StorageProvider<Storage = S> + 'static {
+        let bfc = try!(BlockFileContainer::open_in(provider));
+        if bfc.block_size() != block_size {
+            return Err(ContainerError::BlockSizeMismatch(block_size, bfc.block_size()));
+        }
+        Ok(bfc)
+    }
+}
 
-        if reserved_was_set {
-            let used_was_not_set = try!(self.used_bit_map.insert(id as u64));
-            //println!("[update] reserved_was_set: {}, used_was_not_set: {}",reserved_was_set, used_was_not_set);
+impl BlockFileContainer {
+    /// Creates a new and empty container with plain bit sets. Existing files with the same prefix are
+    /// truncated and an existing write-ahead log is removed.
+    pub fn new_from_prefix_and_block_size(prefix: &Path, block_size: usize) -> IoResult<BlockFileContainer> {
+        BlockFileContainer::new_with_bit_set_kind(prefix, block_size, BitSetKind::Plain)
+    }
 
-            let old_block;
-            if !used_was_not_set {
-                old_block = Some(try!(read_block(&mut self.container_file, id as u64, self.block_size)));
-            }
-            else{
-                old_block = None;
-            }
-            try!(write_block(&mut self.container_file, id as u64, &element, self.block_size));
+    /// Creates a new and empty container whose reserved and used ids are stored in bit sets of `bit_set_kind`.
+    pub fn new_with_bit_set_kind(prefix: &Path, block_size: usize, bit_set_kind: BitSetKind) -> IoResult<BlockFileContainer> {
+        BlockFileContainer::create_in(FileStorageProvider::new(prefix), block_size, bit_set_kind)
+    }
 
-            return Ok(old_block);
-        }
-        Err(ContainerError::InvalidId)
+    /// Opens an existing container. The block size is read from the `.mtd` file. If the container is in
+    /// write-ahead log mode, the log is replayed. An interrupted `compact` is finished.
+    pub fn open(prefix: &Path) -> Result<BlockFileContainer> {
+        BlockFileContainer::open_in(FileStorageProvider::new(prefix))
     }
 
-    pub fn get(&mut self, id: Id) -> Result<Option<Block>>{
-        if try!(self.contains(id)) && try!(self.used_bit_map.contains(id as u64)) {
-            let old_block = try!(read_block(&mut self.container_file, id as u64, self.block_size));
-            return Ok(Some(old_block));
-        }
-        Ok(None)
+    /// Opens an existing container and checks that it was created with `block_size`.
+    pub fn open_with_block_size(prefix: &Path, block_size: usize) -> Result<BlockFileContainer> {
+        BlockFileContainer::open_in_with_block_size(FileStorageProvider::new(prefix), block_size)
     }
+}
 
-    pub fn contains(&mut self, id: Id) -> Result<bool> {
-        let contains = try!(self.reserved_bit_map.contains(id));
-        Ok(contains)
+/// Memory mapped containers hand out their blocks without copying them, which suits read-mostly use.
+#[cfg(feature = "mmap")]
+impl BlockFileContainer<MmapStorage> {
+    /// Creates a new and empty container whose files are memory mapped. Existing files with the same prefix
+    /// are truncated.
+    pub fn new_memory_mapped(prefix: &Path, block_size: usize, bit_set_kind: BitSetKind) -> IoResult<BlockFileContainer<MmapStorage>> {
+        BlockFileContainer::create_in(MmapStorageProvider::new(prefix), block_size, bit_set_kind)
     }
 
-    pub fn clear(&mut self) -> Result<()>{
-        try!(self.free_list_file.clear());
-        try!(self.used_bit_map.clear());
-        try!(self.reserved_bit_map.clear());
-        try!(self.container_file.set_len(0));
-        Ok(())
+    /// Opens an existing container and maps its files into memory.
+    pub fn open_memory_mapped(prefix: &Path) -> Result<BlockFileContainer<MmapStorage>> {
+        BlockFileContainer::open_in(MmapStorageProvider::new(prefix))
     }
 
-    pub fn reserved_ids(&mut self) -> ContainsIterator {
-        self.reserved_bit_map.contains_iter()
+    /// Returns the block of `id` as a slice of the mapped `.ctr` file. Unlike `get`, nothing is copied.
+    /// Blocks are written in full, so like `get` the slice has the block size and a block that was written
+    /// shorter ends with zeros.
+    pub fn get_ref(&self, id: Id) -> Result<Option<&[u8]>> {
+        let parts = self.parts();
+        if !try!(parts.used_bit_map.contains(id)) {
+            return Ok(None);
+        }
+        let blocks = parts.container_file.as_slice();
+        // Only a `.ctr` file cut off behind the used blocks yields a shorter slice.
+        let start = cmp::min(container_file_len(id, parts.block_size) as usize, blocks.len());
+        let end = cmp::min(start + parts.block_size, blocks.len());
+        Ok(Some(&blocks[start .. end]))
     }
 
-    pub fn used_ids(&mut self) -> ContainsIterator{
-        self.used_bit_map.contains_iter()
+    /// Writes the changed blocks back to the `.ctr` file with `msync` and waits for it. The bookkeeping
+    /// files are written by `sync`.
+    pub fn flush(&mut self) -> Result<()> {
+        try!(self.parts_mut().container_file.flush());
+        Ok(())
     }
 
-    pub fn new_from_prefix_and_block_size(prefix: &Path, block_size: usize) -> IoResult<BlockFileContainer> {
-        let container_file = try!(OpenOptions::new().read(true).write(true).create(true).open(prefix.with_extension("ctr")));
-        let metadata_file = try!(OpenOptions::new().read(true).write(true).create(true).open(prefix.with_extension("mtd")));
-        let reserved_bit_map_file = try!(OpenOptions::new().read(true).write(true).create(true).open(prefix.with_extension("rbm")));
-        let updated_bit_map_file = try!(OpenOptions::new().read(true).write(true).create(true).open(prefix.with_extension("ubm")));
-        let free_list_file = try!(OpenOptions::new().read(true).write(true).create(true).open(prefix.with_extension("flt")));
+    /// Starts writing the changed blocks back to the `.ctr` file without waiting for it.
+    pub fn flush_async(&mut self) -> Result<()> {
+        try!(self.parts_mut().container_file.flush_async());
+        Ok(())
+    }
+}
 
-        Ok(BlockFileContainer {
-            container_file: container_file,
-            metadata_file: metadata_file,
-            reserved_bit_map: BitSetFile::new(reserved_bit_map_file),
-            used_bit_map: BitSetFile::new(updated_bit_map_file),
-            free_list_file: StackFile::new(free_list_file),
-            block_size: block_size,
-        })
+impl<S: RawAccess + 'static> BlockFileContainer<S> {
+    /// Takes the container apart without writing the metadata. The container `from_parts` puts together
+    /// again writes it when it is dropped.
+    pub(crate) fn into_parts(mut self) -> Parts<S> {
+        self.parts.take().expect(TAKEN_APART)
+    }
+
+    pub(crate) fn from_parts(parts: Parts<S>) -> BlockFileContainer<S> {
+        BlockFileContainer { parts: Some(parts) }
     }
 }
 
-impl Drop for BlockFileContainer {
+impl<S: RawAccess + 'static> Drop for BlockFileContainer<S> {
     fn drop(&mut self){
-        self.metadata_file.sync_data().expect("could not write metadata_file");
+        if let Some(ref mut parts) = self.parts {
+            parts.close();
+        }
     }
 }
 
-impl<'a> Container<'a, Block> for BlockFileContainer {
+impl<'a, S: RawAccess + 'static> Container<'a, Block> for BlockFileContainer<S> {
     type I = Id;
     type IdIterator = ContainsIterator<'a>;
-    //type VacantEntry = VacantBlockFileContainerEntry<'a>;
-    //type OccupiedEntry = OccupiedBlockFileContainerEntry<'a>;
 
 
     fn reserve(&mut self) -> Result<Id> {
@@ -247,7 +1301,7 @@ impl<'a> Container<'a, Block> for BlockFileContainer {
 
 
     fn contains(&mut self, id: Id) -> Result<bool> {
-        self.contains(id)
+        BlockFileContainer::contains(self, id)
     }
 
     fn ids(&'a mut self) -> ContainsIterator<'a> {
@@ -258,30 +1312,65 @@ impl<'a> Container<'a, Block> for BlockFileContainer {
         self.update(id, new_element)
     }
 
-    /*
-    fn entry(&'a mut self, id: Id) -> Result<BlockFileContainerEntry<'a>> {
+    fn remove(&mut self, id: Id) -> Result<Option<Block>> {
+        self.remove(id)
+    }
+
+    fn insert_many(&mut self, elements: Vec<Block>) -> Result<Vec<Id>> {
+        BlockFileContainer::insert_many(self, elements)
+    }
+
+    fn update_many(&mut self, updates: Vec<(Id, Block)>) -> Result<Vec<Option<Block>>> {
+        BlockFileContainer::update_many(self, updates)
+    }
+
+    fn remove_many(&mut self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        BlockFileContainer::remove_many(self, ids)
+    }
+}
+
+impl<'a, S: RawAccess + 'static> EntryContainer<'a, Block> for BlockFileContainer<S> {
+    type VacantEntry = VacantBlockFileContainerEntry<'a, S>;
+    type OccupiedEntry = OccupiedBlockFileContainerEntry<'a, S>;
 
-        if try!(self.contains(id)) {
+    fn entry(&'a mut self, id: Id) -> Result<BlockFileContainerEntry<'a, S>> {
+        if !try!(self.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        if try!(self.parts().used_bit_map.contains(id as u64)) {
+            let block = try!(read_block(&self.parts().container_file, id as u64, self.parts().block_size));
             return Ok(Entry::Occupied(OccupiedBlockFileContainerEntry{
                 id: id,
-                block: try!(read_block(&mut self.container_file, id as u64, self.block_size)),
+                block: block,
                 bfc: self,
             }));
         }
 
         Ok(Entry::Vacant(VacantBlockFileContainerEntry {bfc: self, id: id }))
     }
-    */
+}
 
-    fn remove(&mut self, id: Id) -> Result<Option<Block>> {
-        self.remove(id)
+impl<'a, S: RawAccess + 'static> CloneContainer<'a, Block> for BlockFileContainer<S> {
+    fn get_clone(&mut self, id: Self::I) -> Result<Option<Block>>{
+        BlockFileContainer::get(self, id)
+    }
+
+    fn get_many(&mut self, ids: &[Id]) -> Result<Vec<Option<Block>>> {
+        BlockFileContainer::get_many(self, ids)
     }
 }
 
-impl<'a> CloneContainer<'a, Block> for BlockFileContainer {
-    fn get_clone(&mut self, id: Self::I) -> Result<Option<Block>>{
+impl<S: RawAccess + 'static> SharedReadContainer for BlockFileContainer<S> {
+    type I = Id;
+    type E = Block;
+
+    fn get_shared(&self, id: Id) -> Result<Option<Block>> {
         self.get(id)
     }
+
+    fn contains_shared(&self, id: Id) -> Result<bool> {
+        self.contains(id)
+    }
 }
 
 
@@ -313,7 +1402,7 @@ fn blockfilecontainer_insert() {
     assert_eq!(id, 0);
     assert_eq!(bfc.size(), 1);
     assert_eq!(bfc.contains(id).unwrap(), true);
-    let block = read_block(&mut bfc.container_file, 0, bfc.block_size).unwrap();
+    let block = read_block(&bfc.parts().container_file, 0, bfc.block_size()).unwrap();
     assert_eq!(block, vec!(1u8,2,3,4,5,6,7,8))
 }
 
@@ -325,7 +1414,7 @@ fn blockfilecontainer_insert_256() {
         let id = bfc.insert(vec!(i,i,i,i,i,i,i,i)).unwrap();
         assert_eq!(id, i as Id);
         assert_eq!(bfc.size(), id + 1);
-        let block = read_block(&mut bfc.container_file, id as u64, bfc.block_size).unwrap();
+        let block = read_block(&bfc.parts().container_file, id as u64, bfc.block_size()).unwrap();
         assert_eq!(block, vec!(i,i,i,i,i,i,i,i));
         assert_eq!(bfc.contains(i as Id).unwrap(), true);
     }
@@ -359,3 +1448,584 @@ fn blockfilecontainer_remove_256() {
     }
     assert_eq!(bfc.size(), 0);
 }
+
+#[test]
+fn blockfilecontainer_open() {
+    let prefix = Path::new("./test_output/bct_test_open");
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 8).unwrap();
+        for i in  0u8 .. 16 {
+            bfc.insert(vec!(i,i,i,i,i,i,i,i)).unwrap();
+        }
+        bfc.remove(3).unwrap();
+        bfc.reserve().unwrap();
+        bfc.reserve().unwrap();
+    }
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.block_size(), 8);
+    assert_eq!(bfc.size(), 17);
+    assert_eq!(bfc.get(3).unwrap(), None);
+    assert_eq!(bfc.get(5).unwrap(), Some(vec!(5u8,5,5,5,5,5,5,5)));
+    assert_eq!(bfc.get(16).unwrap(), None);
+    assert_eq!(bfc.contains(16).unwrap(), true);
+    assert_eq!(bfc.reserve().unwrap(), 17);
+}
+
+#[test]
+fn blockfilecontainer_open_block_size_mismatch() {
+    let prefix = Path::new("./test_output/bct_test_open_block_size_mismatch");
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 8).unwrap();
+        bfc.insert(vec!(1u8,2,3,4,5,6,7,8)).unwrap();
+    }
+    match BlockFileContainer::open_with_block_size(&prefix, 16) {
+        Err(ContainerError::BlockSizeMismatch(16, 8)) => {},
+        _ => panic!("opened a container with a mismatched block size"),
+    }
+    assert!(BlockFileContainer::open_with_block_size(&prefix, 8).is_ok());
+}
+
+#[test]
+fn blockfilecontainer_open_invalid_magic() {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    let prefix = Path::new("./test_output/bct_test_open_invalid_magic");
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 8).unwrap();
+        bfc.insert(vec!(1u8,2,3,4,5,6,7,8)).unwrap();
+    }
+    {
+        let mut file = OpenOptions::new().write(true).open(prefix.with_extension("ctr")).unwrap();
+        file.write_all(b"JUNK").unwrap();
+    }
+    match BlockFileContainer::open(&prefix) {
+        Err(ContainerError::InvalidMagic(found)) => assert_eq!(&found, b"JUNK"),
+        _ => panic!("opened a container file with a wrong magic number"),
+    }
+}
+
+#[test]
+fn blockfilecontainer_wal_recovery() {
+    let prefix = Path::new("./test_output/bct_test_wal_recovery");
+    let (kept, removed, updated);
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        bfc.enable_wal().unwrap();
+        kept = bfc.insert(vec!(1u8,1,1,1)).unwrap();
+        removed = bfc.insert(vec!(2u8,2,2,2)).unwrap();
+        updated = bfc.insert(vec!(3u8,3,3,3)).unwrap();
+        bfc.sync().unwrap();
+
+        bfc.remove(removed).unwrap();
+        bfc.update(updated, vec!(4u8,4,4,4)).unwrap();
+        let _ = bfc.insert(vec!(5u8,5,5,5)).unwrap();
+        mem::forget(bfc);
+    }
+
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert!(bfc.has_wal());
+    assert_eq!(bfc.size(), 3);
+    assert_eq!(bfc.get(kept).unwrap(), Some(vec!(1u8,1,1,1)));
+    assert_eq!(bfc.get(removed).unwrap(), Some(vec!(5u8,5,5,5)));
+    assert_eq!(bfc.get(updated).unwrap(), Some(vec!(4u8,4,4,4)));
+    assert_eq!(bfc.reserve().unwrap(), 3);
+
+    bfc.disable_wal().unwrap();
+    assert!(!prefix.with_extension("wal").exists());
+}
+
+#[test]
+fn blockfilecontainer_recovery_without_wal() {
+    let prefix = Path::new("./test_output/bct_test_recovery_without_wal");
+    let (kept, removed, inserted);
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        kept = bfc.insert(vec!(1u8,1,1,1)).unwrap();
+        removed = bfc.insert(vec!(2u8,2,2,2)).unwrap();
+        bfc.sync().unwrap();
+
+        bfc.remove(removed).unwrap();
+        inserted = bfc.insert(vec!(3u8,3,3,3)).unwrap();
+        let _ = bfc.insert(vec!(4u8,4,4,4)).unwrap();
+        // Crash after the headers of the bit sets but before the metadata were written.
+        bfc.parts_mut().reserved_bit_map.sync().unwrap();
+        bfc.parts_mut().used_bit_map.sync().unwrap();
+        mem::forget(bfc);
+    }
+
+    let bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.size(), 3);
+    assert_eq!(bfc.used_ids().count(), 3);
+    assert_eq!(bfc.get(kept).unwrap(), Some(vec!(1u8,1,1,1)));
+    assert_eq!(bfc.get(inserted).unwrap(), Some(vec!(3u8,3,3,3)));
+    // The `.mtd` file is written again when the container is opened, not only when it is dropped.
+    let metadata = Metadata::read_from(&FileStorageProvider::new(&prefix).open("mtd").unwrap()).unwrap();
+    assert_eq!(metadata.reserved, 3);
+    assert_eq!(metadata.used, 3);
+    mem::forget(bfc);
+    assert_eq!(BlockFileContainer::open(&prefix).unwrap().size(), 3);
+}
+
+#[test]
+fn blockfilecontainer_wal_replays_unapplied_records() {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    let prefix = Path::new("./test_output/bct_test_wal_unapplied");
+    let id;
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        bfc.enable_wal().unwrap();
+        id = bfc.insert(vec!(1u8,1,1,1)).unwrap();
+        bfc.parts_mut().wal.as_mut().unwrap().append_all(&[LogRecord::Update(id, Cow::Borrowed(&[9u8,9,9,9])), LogRecord::Reserve(id + 1)]).unwrap();
+        mem::forget(bfc);
+    }
+    {
+        let mut wal_file = OpenOptions::new().append(true).open(prefix.with_extension("wal")).unwrap();
+        wal_file.write_all(&[1u8, 2, 3]).unwrap();
+    }
+
+    let bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.get(id).unwrap(), Some(vec!(9u8,9,9,9)));
+    assert_eq!(bfc.contains(id + 1).unwrap(), true);
+    assert_eq!(bfc.get(id + 1).unwrap(), None);
+}
+
+#[test]
+fn blockfilecontainer_roaring() {
+    let prefix = Path::new("./test_output/bct_test_roaring");
+    {
+        let mut bfc = BlockFileContainer::new_with_bit_set_kind(&prefix, 4, BitSetKind::Roaring).unwrap();
+        for i in 0 .. 1000u32 {
+            let id = bfc.insert(vec!(i as u8, 0, 0, 0)).unwrap();
+            assert_eq!(id, i as u64);
+        }
+        for id in (0 .. 1000).filter(|id| id % 3 == 0) {
+            assert_eq!(bfc.remove(id).unwrap(), Some(vec!(id as u8, 0, 0, 0)));
+        }
+    }
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.bit_set_kind(), BitSetKind::Roaring);
+    assert_eq!(bfc.size(), 666);
+    assert_eq!(bfc.get(0).unwrap(), None);
+    assert_eq!(bfc.get(1).unwrap(), Some(vec!(1u8, 0, 0, 0)));
+    assert_eq!(bfc.used_ids().take(3).collect::<Vec<Id>>(), vec!(1, 2, 4));
+    assert_eq!(bfc.reserve().unwrap(), 996);
+}
+
+#[test]
+fn blockfilecontainer_unused_ids_and_pages() {
+    for (name, kind) in vec!(("plain", BitSetKind::Plain), ("roaring", BitSetKind::Roaring)) {
+        let prefix = Path::new("./test_output").join(format!("bct_test_unused_ids_{}", name));
+        let mut bfc = BlockFileContainer::new_with_bit_set_kind(&prefix, 4, kind).unwrap();
+        for i in 0 .. 100u64 {
+            if i % 10 == 0 {
+                assert_eq!(bfc.reserve().unwrap(), i);
+            }
+            else {
+                assert_eq!(bfc.insert(vec!(i as u8)).unwrap(), i);
+            }
+        }
+        assert_eq!(bfc.unused_ids().collect::<Vec<Id>>(), (0 .. 10).map(|i| i * 10).collect::<Vec<Id>>());
+        assert_eq!(bfc.reserved_ids_page(25, 3).unwrap(), vec!(25, 26, 27));
+        assert_eq!(bfc.reserved_ids_page(98, 5).unwrap(), vec!(98, 99));
+        assert_eq!(bfc.reserved_ids_page(100, 5).unwrap(), Vec::<Id>::new());
+        assert_eq!(bfc.used_ids_page(9, 2).unwrap(), vec!(11, 12));
+    }
+}
+
+#[test]
+fn blockfilecontainer_entry() {
+    let prefix = Path::new("./test_output/bct_test_entry");
+    let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+    let id = bfc.reserve().unwrap();
+    match bfc.entry(id).unwrap() {
+        Entry::Vacant(_) => {},
+        Entry::Occupied(_) => panic!("reserved id is occupied"),
+    }
+    assert_eq!(bfc.entry(id).unwrap().or_insert(vec!(1u8,1,1,1)).unwrap().get(), &vec!(1u8,1,1,1));
+    assert_eq!(bfc.entry(id).unwrap().or_insert(vec!(2u8,2,2,2)).unwrap().get(), &vec!(1u8,1,1,1));
+
+    let _ = bfc.entry(id).unwrap().and_modify(|block| block[0] = 9).unwrap();
+    assert_eq!(bfc.get(id).unwrap(), Some(vec!(9u8,1,1,1)));
+
+    // blocks that do not fit change neither the used ids nor the cached block.
+    let vacant = bfc.reserve().unwrap();
+    match bfc.entry(vacant).unwrap() {
+        Entry::Vacant(entry) => assert!(entry.insert(vec!(0u8; 5)).is_err()),
+        Entry::Occupied(_) => panic!("reserved id is occupied"),
+    }
+    match bfc.entry(vacant).unwrap() {
+        Entry::Vacant(_) => {},
+        Entry::Occupied(_) => panic!("a block that does not fit occupied the id"),
+    }
+    match bfc.entry(id).unwrap() {
+        Entry::Occupied(mut entry) => {
+            assert!(entry.modify(|block| block.push(5)).is_err());
+            assert_eq!(entry.get(), &vec!(9u8,1,1,1));
+        },
+        Entry::Vacant(_) => panic!("written id is vacant"),
+    }
+    assert_eq!(bfc.get(id).unwrap(), Some(vec!(9u8,1,1,1)));
+
+    {
+        let mut entry = bfc.entry(id).unwrap().or_insert_with(|| vec!(0u8,0,0,0)).unwrap();
+        assert_eq!(entry.update(vec!(3u8,3,3,3)).unwrap(), vec!(9u8,1,1,1));
+        assert_eq!(entry.remove().unwrap(), vec!(3u8,3,3,3));
+    }
+    assert_eq!(bfc.contains(id).unwrap(), false);
+    assert!(bfc.entry(id).is_err());
+}
+
+#[test]
+fn blockfilecontainer_reuse_lifo() {
+    let prefix = Path::new("./test_output/bct_test_reuse_lifo");
+    let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+    for i in 0 .. 10u64 {
+        assert_eq!(bfc.reserve().unwrap(), i);
+    }
+    bfc.remove(3).unwrap();
+    bfc.remove(5).unwrap();
+    bfc.remove(9).unwrap();
+    assert_eq!(bfc.free_list_len(), 2);
+    assert_eq!(bfc.reserve().unwrap(), 5);
+    assert_eq!(bfc.reserve().unwrap(), 3);
+    assert_eq!(bfc.reserve().unwrap(), 9);
+    assert_eq!(bfc.reserve().unwrap(), 10);
+}
+
+#[test]
+fn blockfilecontainer_reuse_lowest_first() {
+    let prefix = Path::new("./test_output/bct_test_reuse_lowest_first");
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        bfc.set_reuse_policy(ReusePolicy::LowestFirst).unwrap();
+        for i in 0 .. 10u64 {
+            assert_eq!(bfc.insert(vec!(i as u8)).unwrap(), i);
+        }
+        for id in vec!(7, 2, 5) {
+            bfc.remove(id).unwrap();
+        }
+        assert_eq!(bfc.free_list_len(), 0);
+        assert_eq!(bfc.reserve().unwrap(), 2);
+        bfc.remove(2).unwrap();
+    }
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.reuse_policy(), ReusePolicy::LowestFirst);
+    assert_eq!(bfc.reserve().unwrap(), 2);
+    assert_eq!(bfc.reserve().unwrap(), 5);
+    assert_eq!(bfc.reserve().unwrap(), 7);
+    assert_eq!(bfc.reserve().unwrap(), 10);
+}
+
+#[test]
+fn blockfilecontainer_compact_free_list() {
+    let prefix = Path::new("./test_output/bct_test_compact_free_list");
+    let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+    for _ in 0 .. 10 {
+        bfc.reserve().unwrap();
+    }
+    for id in vec!(8, 1, 4) {
+        bfc.remove(id).unwrap();
+    }
+    // 4 is reserved again behind the back of the free list, leaving a stale entry.
+    bfc.parts_mut().reserved_bit_map.insert(4).unwrap();
+    assert_eq!(bfc.free_list_len(), 3);
+    bfc.compact_free_list().unwrap();
+    assert_eq!(bfc.free_list_len(), 2);
+    assert_eq!(bfc.reserve().unwrap(), 1);
+    assert_eq!(bfc.reserve().unwrap(), 8);
+    assert_eq!(bfc.reserve().unwrap(), 10);
+
+    bfc.set_reuse_policy(ReusePolicy::LowestFirst).unwrap();
+    assert_eq!(bfc.free_list_len(), 0);
+    bfc.remove(6).unwrap();
+    bfc.set_reuse_policy(ReusePolicy::Lifo).unwrap();
+    assert_eq!(bfc.free_list_len(), 1);
+    assert_eq!(bfc.reserve().unwrap(), 6);
+}
+
+#[test]
+fn blockfilecontainer_steady_state() {
+    for policy in vec!(ReusePolicy::Lifo, ReusePolicy::LowestFirst) {
+        let prefix = Path::new("./test_output").join(format!("bct_test_steady_state_{}", policy.tag()));
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        bfc.set_reuse_policy(policy).unwrap();
+        let mut ids: Vec<Id> = (0 .. 64u8).map(|i| bfc.insert(vec!(i)).unwrap()).collect();
+        for round in 0 .. 1000usize {
+            let position = (round * 37) % ids.len();
+            let id = ids.swap_remove(position);
+            bfc.remove(id).unwrap();
+            if round % 2 == 0 {
+                // Reserved ids without a block are released as well.
+                let reserved = bfc.reserve().unwrap();
+                bfc.remove(reserved).unwrap();
+            }
+            ids.push(bfc.insert(vec!(round as u8)).unwrap());
+        }
+        assert_eq!(bfc.size(), 64);
+        assert_eq!(bfc.parts().reserved_bit_map.max_bit(), Some(63));
+        assert!(bfc.free_list_len() <= 64);
+    }
+}
+
+#[test]
+fn blockfilecontainer_compact() {
+    use std::fs;
+    let prefix = Path::new("./test_output/bct_test_compact");
+    let moves;
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        for i in 0 .. 20u8 {
+            bfc.insert(vec!(i, i, i, i)).unwrap();
+        }
+        bfc.reserve().unwrap();
+        for id in vec!(0, 3, 4, 9, 15, 16) {
+            bfc.remove(id).unwrap();
+        }
+        moves = bfc.compact().unwrap();
+        let expected: BTreeMap<Id, Id> = vec!((17, 0), (18, 3), (19, 4), (20, 9)).into_iter().collect();
+        assert_eq!(moves, expected);
+        assert_eq!(bfc.size(), 15);
+        assert_eq!(bfc.reserved_ids().collect::<Vec<Id>>(), (0 .. 15).collect::<Vec<Id>>());
+        assert_eq!(bfc.get(9).unwrap(), None);
+        assert_eq!(bfc.contains(20).unwrap(), false);
+        assert_eq!(bfc.free_list_len(), 0);
+        assert!(!prefix.with_extension("cmp").exists());
+        assert_eq!(bfc.compact().unwrap(), BTreeMap::new());
+    }
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.take_resumed_compaction(), None);
+    assert_eq!(fs::metadata(prefix.with_extension("ctr")).unwrap().len(), container_file_len(15, 4));
+    for (old, new) in moves {
+        assert_eq!(bfc.get(new).unwrap().map(|block| block[0] as Id), if old == 20 { None } else { Some(old) });
+    }
+    assert_eq!(bfc.get(5).unwrap(), Some(vec!(5u8, 5, 5, 5)));
+    assert_eq!(bfc.reserve().unwrap(), 15);
+}
+
+#[test]
+fn blockfilecontainer_compact_resume() {
+    let prefix = Path::new("./test_output/bct_test_compact_resume");
+    let moves;
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        for i in 0 .. 10u8 {
+            bfc.insert(vec!(i, i, i, i)).unwrap();
+        }
+        for id in vec!(1, 2, 5) {
+            bfc.remove(id).unwrap();
+        }
+        bfc.sync().unwrap();
+        {
+            let parts = bfc.parts_mut();
+            moves = parts.plan_compaction().unwrap();
+            parts.write_compaction_journal(&moves).unwrap();
+            // Crash after the first block was moved.
+            parts.copy_block(7, 1).unwrap();
+            parts.reserved_bit_map.remove(7).unwrap();
+            parts.used_bit_map.remove(7).unwrap();
+        }
+        mem::forget(bfc);
+    }
+
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.take_resumed_compaction(), Some(moves));
+    assert_eq!(bfc.take_resumed_compaction(), None);
+    assert!(!prefix.with_extension("cmp").exists());
+    assert_eq!(bfc.size(), 7);
+    assert_eq!(bfc.used_ids().collect::<Vec<Id>>(), (0 .. 7).collect::<Vec<Id>>());
+    let blocks: Vec<u8> = (0 .. 7).map(|id| bfc.get(id).unwrap().unwrap()[0]).collect();
+    assert_eq!(blocks, vec!(0u8, 7, 8, 3, 4, 9, 6));
+}
+
+#[cfg(test)]
+fn check_storage_provider<S, P>(provider: P) where S: RawAccess + 'static, P: StorageProvider<Storage = S> + Clone + 'static {
+    {
+        let mut bfc = BlockFileContainer::create_in(provider.clone(), 4, BitSetKind::Plain).unwrap();
+        bfc.enable_wal().unwrap();
+        for i in 0 .. 10u8 {
+            assert_eq!(bfc.insert(vec!(i, i, i, i)).unwrap(), i as Id);
+        }
+        bfc.remove(2).unwrap();
+        bfc.remove(7).unwrap();
+        assert_eq!(bfc.compact().unwrap().len(), 2);
+        bfc.update(3, vec!(9u8, 9, 9, 9)).unwrap();
+    }
+    assert!(provider.exists("wal"));
+    let mut bfc = BlockFileContainer::open_in_with_block_size(provider, 4).unwrap();
+    assert_eq!(bfc.size(), 8);
+    assert_eq!(bfc.get(2).unwrap(), Some(vec!(8u8, 8, 8, 8)));
+    assert_eq!(bfc.get(3).unwrap(), Some(vec!(9u8, 9, 9, 9)));
+    assert_eq!(bfc.get(7).unwrap(), Some(vec!(9u8, 9, 9, 9)));
+    assert_eq!(bfc.reserve().unwrap(), 8);
+}
+
+#[test]
+fn blockfilecontainer_memory_storage() {
+    use super::storage::MemoryStorageProvider;
+    check_storage_provider(MemoryStorageProvider::new());
+    assert!(BlockFileContainer::create_in(MemoryStorageProvider::new(), 4, BitSetKind::Roaring).is_err());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn blockfilecontainer_mmap_storage() {
+    use super::storage::MmapStorageProvider;
+    check_storage_provider(MmapStorageProvider::new(Path::new("./test_output/bct_test_mmap_storage")));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn blockfilecontainer_memory_mapped() {
+    let prefix = Path::new("./test_output/bct_test_memory_mapped");
+    {
+        let mut bfc = BlockFileContainer::new_memory_mapped(&prefix, 8, BitSetKind::Plain).unwrap();
+        for i in 0 .. 10000u64 {
+            assert_eq!(bfc.insert(vec!(i as u8; 8)).unwrap(), i);
+        }
+        let short = bfc.insert(vec!(1u8, 2)).unwrap();
+        assert_eq!(bfc.get_ref(short).unwrap(), Some(&[1u8, 2, 0, 0, 0, 0, 0, 0][..]));
+        let empty = bfc.insert(Vec::new()).unwrap();
+        assert_eq!(bfc.get_ref(empty).unwrap(), Some(&[0u8; 8][..]));
+        bfc.update(9999, vec!(7u8)).unwrap();
+        assert_eq!(bfc.get(9999).unwrap(), Some(vec!(7u8, 0, 0, 0, 0, 0, 0, 0)));
+        bfc.update(9999, vec!(15u8; 8)).unwrap();
+        bfc.remove(3).unwrap();
+        bfc.flush().unwrap();
+        bfc.flush_async().unwrap();
+    }
+    let bfc = BlockFileContainer::open_memory_mapped(&prefix).unwrap();
+    assert_eq!(bfc.get_ref(3).unwrap(), None);
+    assert_eq!(bfc.get_ref(9999).unwrap(), Some(&[15u8; 8][..]));
+    assert_eq!(bfc.get_ref(10000).unwrap(), Some(&[1u8, 2, 0, 0, 0, 0, 0, 0][..]));
+    assert_eq!(bfc.get(10000).unwrap(), Some(vec!(1u8, 2, 0, 0, 0, 0, 0, 0)));
+    assert_eq!(bfc.get_ref(20000).unwrap(), None);
+
+    let bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.get(9998).unwrap(), Some(vec!(14u8; 8)));
+}
+
+#[test]
+fn blockfilecontainer_shared_reads() {
+    let prefix = Path::new("./test_output/bct_test_shared_reads");
+    let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+    for i in 0 .. 10u8 {
+        bfc.insert(vec!(i, i, i, i)).unwrap();
+    }
+    let empty = bfc.insert(vec!()).unwrap();
+    let short = bfc.insert(vec!(7u8)).unwrap();
+
+    let reader = &bfc;
+    let other_reader = &bfc;
+    for id in reader.used_ids().take(10) {
+        assert_eq!(other_reader.get(id).unwrap(), Some(vec!(id as u8; 4)));
+        assert!(reader.contains(id).unwrap());
+    }
+    assert_eq!(reader.get(empty).unwrap(), Some(vec!(0u8; 4)));
+    assert_eq!(other_reader.get(short).unwrap(), Some(vec!(7u8, 0, 0, 0)));
+    assert_eq!(reader.unused_ids().count(), 0);
+    assert_eq!(other_reader.used_ids_page(10, 5).unwrap(), vec!(10, 11));
+}
+
+#[test]
+fn blockfilecontainer_batches() {
+    let prefix = Path::new("./test_output/bct_test_batches");
+    let ids;
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        assert_eq!(bfc.insert_many(Vec::new()).unwrap(), Vec::<Id>::new());
+        ids = bfc.insert_many((0 .. 10u8).map(|i| vec!(i, i, i, i)).collect()).unwrap();
+        assert_eq!(ids, (0 .. 10).collect::<Vec<_>>());
+        let unused = bfc.reserve().unwrap();
+
+        let blocks = bfc.get_many(&[7, 3, unused, 4, 7, 42]).unwrap();
+        assert_eq!(blocks, vec!(Some(vec!(7u8,7,7,7)), Some(vec!(3u8,3,3,3)), None, Some(vec!(4u8,4,4,4)), Some(vec!(7u8,7,7,7)), None));
+
+        let old_blocks = bfc.update_many(vec!((5, vec!(9u8,9,9,9)), (unused, vec!(8u8,8)), (4, vec!(7u8)))).unwrap();
+        assert_eq!(old_blocks, vec!(Some(vec!(5u8,5,5,5)), None, Some(vec!(4u8,4,4,4))));
+        assert_eq!(bfc.get_many(&[4, 5, unused]).unwrap(), vec!(Some(vec!(7u8,0,0,0)), Some(vec!(9u8,9,9,9)), Some(vec!(8u8,8,0,0))));
+
+        match bfc.remove_many(&[1, 42]) {
+            Err(ContainerError::InvalidId) => {},
+            other => panic!("expected an invalid id, got {:?}", other),
+        }
+        assert_eq!(bfc.get(1).unwrap(), Some(vec!(1u8,1,1,1)));
+
+        let removed = bfc.remove_many(&[unused, 2, 3, 9]).unwrap();
+        assert_eq!(removed, vec!(Some(vec!(8u8,8,0,0)), Some(vec!(2u8,2,2,2)), Some(vec!(3u8,3,3,3)), Some(vec!(9u8,9,9,9))));
+        assert_eq!(bfc.size(), 7);
+        assert_eq!(bfc.parts().container_file.len().unwrap(), container_file_len(9, 4));
+
+        let reused = bfc.insert_many(vec!(vec!(1u8), vec!(2u8), vec!(3u8))).unwrap();
+        assert_eq!(reused.iter().cloned().collect::<BTreeSet<_>>(), vec!(2, 3, 9).into_iter().collect());
+        assert_eq!(bfc.used_ids().collect::<Vec<_>>(), (0 .. 10).collect::<Vec<_>>());
+    }
+    let bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.size(), 10);
+    assert_eq!(bfc.get(0).unwrap(), Some(vec!(0u8,0,0,0)));
+    assert_eq!(bfc.get(8).unwrap(), Some(vec!(8u8,8,8,8)));
+}
+
+#[test]
+fn blockfilecontainer_batches_match_single_operations() {
+    let mut batched = BlockFileContainer::new_from_prefix_and_block_size(Path::new("./test_output/bct_test_batched"), 8).unwrap();
+    let mut single = BlockFileContainer::new_from_prefix_and_block_size(Path::new("./test_output/bct_test_single"), 8).unwrap();
+    for policy in vec!(ReusePolicy::Lifo, ReusePolicy::LowestFirst) {
+        batched.set_reuse_policy(policy).unwrap();
+        single.set_reuse_policy(policy).unwrap();
+        for round in 0 .. 4u8 {
+            let blocks: Vec<Block> = (0 .. 50u8).map(|i| vec!(round, i, 1, 2, 3, 4, 5, 6)).collect();
+            let ids = batched.insert_many(blocks.clone()).unwrap();
+            let single_ids: Vec<Id> = blocks.into_iter().map(|block| Container::insert(&mut single, block).unwrap()).collect();
+            assert_eq!(ids, single_ids);
+
+            let doomed: Vec<Id> = ids.iter().cloned().filter(|id| id % 3 == round as u64 % 3).collect();
+            let removed = batched.remove_many(&doomed).unwrap();
+            let single_removed: Vec<_> = doomed.iter().map(|&id| single.remove(id).unwrap()).collect();
+            assert_eq!(removed, single_removed);
+        }
+        let all: Vec<Id> = single.reserved_ids().collect();
+        assert_eq!(batched.reserved_ids().collect::<Vec<_>>(), all);
+        assert_eq!(batched.get_many(&all).unwrap(), all.iter().map(|&id| single.get(id).unwrap()).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn blockfilecontainer_batches_with_wal() {
+    let prefix = Path::new("./test_output/bct_test_batches_wal");
+    let ids;
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        bfc.enable_wal().unwrap();
+        ids = bfc.insert_many(vec!(vec!(1u8,1,1,1), vec!(2u8,2,2,2), vec!(3u8,3,3,3))).unwrap();
+        bfc.update_many(vec!((ids[0], vec!(4u8,4,4,4)), (ids[2], vec!(5u8,5,5,5)))).unwrap();
+        bfc.remove_many(&[ids[1]]).unwrap();
+        mem::forget(bfc);
+    }
+    let bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.get_many(&ids).unwrap(), vec!(Some(vec!(4u8,4,4,4)), None, Some(vec!(5u8,5,5,5))));
+    assert!(!bfc.contains(ids[1]).unwrap());
+}
+
+#[test]
+fn blockfilecontainer_batches_reuse_free_list() {
+    let prefix = Path::new("./test_output/bct_test_batches_reuse_free_list");
+    let removed: Vec<Id> = (0 .. 1500).filter(|id| id % 5 != 4).collect();
+    {
+        let mut bfc = BlockFileContainer::new_from_prefix_and_block_size(&prefix, 4).unwrap();
+        let ids = bfc.insert_many((0 .. 1500u64).map(|id| vec!(id as u8; 4)).collect()).unwrap();
+        assert_eq!(ids, (0 .. 1500).collect::<Vec<_>>());
+        bfc.remove_many(&removed).unwrap();
+        assert_eq!(bfc.free_list_len(), removed.len() as u64);
+    }
+    let mut bfc = BlockFileContainer::open(&prefix).unwrap();
+    assert_eq!(bfc.free_list_len(), removed.len() as u64);
+    // The free ids come back from the top of the free list, across its pages.
+    let reused = bfc.insert_many(vec!(vec!(7u8; 4); removed.len() - 2)).unwrap();
+    assert_eq!(reused, removed[2 ..].iter().rev().cloned().collect::<Vec<_>>());
+    let reused = bfc.insert_many(vec!(vec!(8u8; 4); 3)).unwrap();
+    assert_eq!(reused, vec!(1, 0, 1500));
+    assert_eq!(bfc.free_list_len(), 0);
+    assert_eq!(bfc.get(4).unwrap(), Some(vec!(4u8; 4)));
+    assert_eq!(bfc.get(3).unwrap(), Some(vec!(7u8; 4)));
+}
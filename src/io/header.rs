@@ -0,0 +1,72 @@
+use std::io::{Result as IoResult};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::super::error::{ContainerError, Result};
+use super::storage::RawAccess;
+
+/// Every file starts with a magic number identifying its kind followed by the format version.
+/// All numbers on disc are stored in little endian byte order.
+pub static HEADER_BYTE_SIZE: u64 = 4 + 4;
+pub static FORMAT_VERSION: u32 = 1;
+
+pub static CONTAINER_MAGIC: &'static [u8; 4] = b"XCTR";
+pub static METADATA_MAGIC: &'static [u8; 4] = b"XMTD";
+pub static BIT_SET_MAGIC: &'static [u8; 4] = b"XBSF";
+pub static STACK_MAGIC: &'static [u8; 4] = b"XSTK";
+pub static WAL_MAGIC: &'static [u8; 4] = b"XWAL";
+pub static TRANSACTION_MAGIC: &'static [u8; 4] = b"XTXN";
+pub static ROARING_MAGIC: &'static [u8; 4] = b"XRBM";
+pub static COMPACTION_MAGIC: &'static [u8; 4] = b"XCMP";
+
+pub fn write_header<S: RawAccess>(storage: &mut S, magic: &[u8; 4]) -> IoResult<()> {
+    let mut header = Vec::with_capacity(HEADER_BYTE_SIZE as usize);
+    header.extend_from_slice(magic);
+    try!(header.write_u32::<LittleEndian>(FORMAT_VERSION));
+    storage.write_at(&header, 0)
+}
+
+/// Checks the magic number and the format version at the start of `storage`.
+pub fn read_header<S: RawAccess>(storage: &S, magic: &[u8; 4]) -> Result<()> {
+    let mut header = [0u8; 8];
+    try!(storage.read_exact_at(&mut header, 0));
+    let mut found = [0u8; 4];
+    found.copy_from_slice(&header[..4]);
+    if found != *magic {
+        return Err(ContainerError::InvalidMagic(found));
+    }
+    let version = try!((&header[4..]).read_u32::<LittleEndian>());
+    if version != FORMAT_VERSION {
+        return Err(ContainerError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+#[test]
+fn header_roundtrip() {
+    use std::path::Path;
+    use std::fs::{File, OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/header_roundtrip.test");
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    write_header(&mut file, STACK_MAGIC).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), HEADER_BYTE_SIZE);
+    assert!(read_header(&file, STACK_MAGIC).is_ok());
+    match read_header(&file, BIT_SET_MAGIC) {
+        Err(ContainerError::InvalidMagic(found)) => assert_eq!(&found, STACK_MAGIC),
+        _ => panic!("accepted a wrong magic number"),
+    }
+
+    write_version(&mut file, FORMAT_VERSION + 1);
+    match read_header(&file, STACK_MAGIC) {
+        Err(ContainerError::UnsupportedVersion(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+        _ => panic!("accepted a wrong format version"),
+    }
+    remove_file(&path).unwrap();
+
+    fn write_version(file: &mut File, version: u32) {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(version).unwrap();
+        file.write_at(&bytes, 4).unwrap();
+    }
+}
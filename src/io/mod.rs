@@ -1,3 +1,9 @@
 pub mod blockfilecontainer;
-mod bitsetfile;
+pub mod multiblockcontainer;
+pub mod transaction;
+pub mod bitsetfile;
+pub mod roaringfile;
+pub mod storage;
 mod stackfile;
+mod header;
+pub(crate) mod wal;
@@ -0,0 +1,355 @@
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Result as IoResult};
+use std::path::Path;
+use std::cmp;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use container::{Container, CloneContainer};
+use super::super::error::{ContainerError, Result};
+
+use super::bitsetfile::{BitSetFile, BitSetKind, ContainsIterator};
+use super::blockfilecontainer::{BlockFileContainer, Block, Id};
+use super::storage::{RawAccess, StorageProvider, FileStorageProvider};
+
+/// Every block of a chain starts with the id of the next block followed by the number of payload bytes in the block.
+static BLOCK_HEADER_BYTE_SIZE: usize = 8 + 4;
+static END_OF_CHAIN: u64 = ::std::u64::MAX;
+
+pub type Record = Vec<u8>;
+
+/// A container for records of arbitrary length. Each record is stored as a chain of blocks in a
+/// `BlockFileContainer`. The id of a record is the id of the first block of its chain.
+///
+/// The chains link blocks by id, so the `BlockFileContainer` must never be compacted.
+///
+/// Like the `BlockFileContainer`, the files are opened by a `StorageProvider`. The first blocks of the
+/// chains are marked in the `.hbm` file.
+pub struct MultiBlockContainer<S: RawAccess + 'static = File> {
+    container: BlockFileContainer<S>,
+    head_bit_map: BitSetFile<S>,
+}
+
+impl<S: RawAccess + 'static> MultiBlockContainer<S> {
+
+    pub fn block_size(&self) -> usize {
+        self.container.block_size()
+    }
+
+    /// The number of payload bytes stored in each block of a chain.
+    pub fn payload_size(&self) -> usize {
+        self.container.block_size() - BLOCK_HEADER_BYTE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.head_bit_map.size()
+    }
+
+    pub fn reserve(&mut self) -> Result<Id> {
+        let id = try!(self.container.reserve());
+        try!(self.head_bit_map.insert(id));
+        Ok(id)
+    }
+
+    pub fn contains(&mut self, id: Id) -> Result<bool> {
+        let contains = try!(self.head_bit_map.contains(id));
+        Ok(contains)
+    }
+
+    pub fn get(&mut self, id: Id) -> Result<Option<Record>> {
+        if try!(self.contains(id)) {
+            if let Some((record, _)) = try!(self.read_chain(id)) {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn update(&mut self, id: Id, element: Record) -> Result<Option<Record>> {
+        if !try!(self.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        let (old_record, mut chain) = match try!(self.read_chain(id)) {
+            Some((record, chain)) => (Some(record), chain),
+            None => (None, vec![id]),
+        };
+
+        let payload_size = self.payload_size();
+        let blocks_needed = cmp::max(1, (element.len() + payload_size - 1) / payload_size);
+        while chain.len() < blocks_needed {
+            let block_id = try!(self.container.reserve());
+            chain.push(block_id);
+        }
+        while chain.len() > blocks_needed {
+            let block_id = chain.pop().expect("chain is not empty");
+            let _ = try!(self.container.remove(block_id));
+        }
+
+        for (index, &block_id) in chain.iter().enumerate() {
+            let start = index * payload_size;
+            let end = cmp::min(start + payload_size, element.len());
+            let next = chain.get(index + 1).cloned().unwrap_or(END_OF_CHAIN);
+            let block = try!(encode_block(next, &element[start..end]));
+            let _ = try!(self.container.update(block_id, block));
+        }
+        Ok(old_record)
+    }
+
+    /// Removes the record and the blocks of its chain. The id stays a record until the chain is read and
+    /// removed, so a failing read leaves the record in place.
+    pub fn remove(&mut self, id: Id) -> Result<Option<Record>> {
+        if !try!(self.contains(id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        let old_record = match try!(self.read_chain(id)) {
+            Some((record, chain)) => {
+                for block_id in chain {
+                    let _ = try!(self.container.remove(block_id));
+                }
+                Some(record)
+            },
+            None => {
+                let _ = try!(self.container.remove(id));
+                None
+            }
+        };
+        let _ = try!(self.head_bit_map.remove(id));
+        Ok(old_record)
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        try!(self.head_bit_map.clear());
+        self.container.clear()
+    }
+
+    pub fn ids(&mut self) -> ContainsIterator<'_> {
+        self.head_bit_map.contains_iter()
+    }
+
+    /// Writes the metadata and the bookkeeping of all files to disc.
+    pub fn sync(&mut self) -> Result<()> {
+        try!(self.head_bit_map.sync());
+        self.container.sync()
+    }
+
+    /// Reads the record starting at block `id`. Returns the record and the ids of all blocks of its chain
+    /// or `None` if the record was only reserved. A chain with more blocks than the container holds has a
+    /// cycle and gives `ContainerError::InvalidMetadata`.
+    fn read_chain(&mut self, id: Id) -> Result<Option<(Record, Vec<Id>)>> {
+        let mut record = Vec::new();
+        let mut chain = Vec::new();
+        let mut next = id;
+        while next != END_OF_CHAIN {
+            if chain.len() as u64 >= self.container.size() {
+                return Err(ContainerError::InvalidMetadata);
+            }
+            let block = match try!(self.container.get(next)) {
+                Some(block) => block,
+                None if chain.is_empty() => return Ok(None),
+                None => return Err(ContainerError::InvalidId),
+            };
+            chain.push(next);
+            next = try!(decode_block(&block, &mut record));
+        }
+        Ok(Some((record, chain)))
+    }
+
+    /// Creates a new and empty container in the storages of `provider`. Existing storages are truncated.
+    pub fn create_in<P>(provider: P, block_size: usize) -> IoResult<MultiBlockContainer<S>>
+        where P: StorageProvider<Storage = S> + 'static {
+        if block_size <= BLOCK_HEADER_BYTE_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, "block_size is too small to store the chain header"));
+        }
+        let head_bit_map_file = try!(provider.create("hbm"));
+        let container = try!(BlockFileContainer::create_in(provider, block_size, BitSetKind::Plain));
+        Ok(MultiBlockContainer {
+            container: container,
+            head_bit_map: BitSetFile::new(head_bit_map_file),
+        })
+    }
+
+    /// Opens an existing container from the storages of `provider`. The block size is read from the `.mtd`
+    /// storage.
+    pub fn open_in<P>(provider: P) -> Result<MultiBlockContainer<S>> where P: StorageProvider<Storage = S> + 'static {
+        let head_bit_map_file = try!(provider.open("hbm"));
+        let container = try!(BlockFileContainer::open_in(provider));
+        if container.block_size() <= BLOCK_HEADER_BYTE_SIZE {
+            return Err(ContainerError::InvalidMetadata);
+        }
+        Ok(MultiBlockContainer {
+            container: container,
+            head_bit_map: try!(BitSetFile::open(head_bit_map_file)),
+        })
+    }
+}
+
+impl MultiBlockContainer {
+    /// Creates a new and empty container. Existing files with the same prefix are truncated.
+    pub fn new_from_prefix_and_block_size(prefix: &Path, block_size: usize) -> IoResult<MultiBlockContainer> {
+        MultiBlockContainer::create_in(FileStorageProvider::new(prefix), block_size)
+    }
+
+    /// Opens an existing container. The block size is read from the `.mtd` file.
+    pub fn open(prefix: &Path) -> Result<MultiBlockContainer> {
+        MultiBlockContainer::open_in(FileStorageProvider::new(prefix))
+    }
+}
+
+fn encode_block(next: u64, payload: &[u8]) -> IoResult<Block> {
+    let mut block = Vec::with_capacity(BLOCK_HEADER_BYTE_SIZE + payload.len());
+    try!(block.write_u64::<LittleEndian>(next));
+    try!(block.write_u32::<LittleEndian>(payload.len() as u32));
+    block.extend_from_slice(payload);
+    Ok(block)
+}
+
+/// Appends the payload of `block` to `record` and returns the id of the next block.
+fn decode_block(block: &Block, record: &mut Record) -> Result<u64> {
+    let mut cursor = Cursor::new(block);
+    let next = try!(cursor.read_u64::<LittleEndian>());
+    let length = try!(cursor.read_u32::<LittleEndian>()) as usize;
+    if BLOCK_HEADER_BYTE_SIZE + length > block.len() {
+        return Err(ContainerError::InvalidMetadata);
+    }
+    record.extend_from_slice(&block[BLOCK_HEADER_BYTE_SIZE .. BLOCK_HEADER_BYTE_SIZE + length]);
+    Ok(next)
+}
+
+impl<'a, S: RawAccess + 'static> Container<'a, Record> for MultiBlockContainer<S> {
+    type I = Id;
+    type IdIterator = ContainsIterator<'a>;
+
+    fn reserve(&mut self) -> Result<Id> {
+        self.reserve()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.clear()
+    }
+
+    fn contains(&mut self, id: Id) -> Result<bool> {
+        self.contains(id)
+    }
+
+    fn ids(&'a mut self) -> ContainsIterator<'a> {
+        self.ids()
+    }
+
+    fn update(&mut self, id: Id, new_element: Record) -> Result<Option<Record>> {
+        self.update(id, new_element)
+    }
+
+    fn remove(&mut self, id: Id) -> Result<Option<Record>> {
+        self.remove(id)
+    }
+}
+
+impl<'a, S: RawAccess + 'static> CloneContainer<'a, Record> for MultiBlockContainer<S> {
+    fn get_clone(&mut self, id: Self::I) -> Result<Option<Record>> {
+        self.get(id)
+    }
+}
+
+
+#[test]
+fn multiblockcontainer_insert() {
+    let prefix = Path::new("./test_output/mbc_test_insert");
+    let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(&prefix, 16).unwrap();
+    let short = vec!(1u8, 2, 3);
+    let long: Record = (0 .. 100).collect();
+    let short_id = mbc.insert(short.clone()).unwrap();
+    let long_id = mbc.insert(long.clone()).unwrap();
+    let empty_id = mbc.insert(vec!()).unwrap();
+    assert_eq!(mbc.size(), 3);
+    assert_eq!(mbc.get(short_id).unwrap(), Some(short));
+    assert_eq!(mbc.get(long_id).unwrap(), Some(long));
+    assert_eq!(mbc.get(empty_id).unwrap(), Some(vec!()));
+    let ids: Vec<Id> = mbc.ids().collect();
+    assert_eq!(ids, vec!(short_id, long_id, empty_id));
+}
+
+#[test]
+fn multiblockcontainer_update() {
+    let prefix = Path::new("./test_output/mbc_test_update");
+    let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(&prefix, 16).unwrap();
+    let long: Record = (0 .. 100).collect();
+    let longer: Record = (0 .. 200).collect();
+    let short = vec!(7u8; 5);
+    let id = mbc.reserve().unwrap();
+    assert_eq!(mbc.get(id).unwrap(), None);
+    assert_eq!(mbc.update(id, long.clone()).unwrap(), None);
+    assert_eq!(mbc.update(id, longer.clone()).unwrap(), Some(long));
+    assert_eq!(mbc.update(id, short.clone()).unwrap(), Some(longer));
+    assert_eq!(mbc.get(id).unwrap(), Some(short));
+    assert_eq!(mbc.container.size(), 2);
+}
+
+#[test]
+fn multiblockcontainer_remove() {
+    let prefix = Path::new("./test_output/mbc_test_remove");
+    let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(&prefix, 16).unwrap();
+    let long: Record = (0 .. 100).collect();
+    let id = mbc.insert(long.clone()).unwrap();
+    let reserved = mbc.reserve().unwrap();
+    assert_eq!(mbc.remove(id).unwrap(), Some(long));
+    assert_eq!(mbc.remove(reserved).unwrap(), None);
+    assert_eq!(mbc.contains(id).unwrap(), false);
+    assert_eq!(mbc.size(), 0);
+    assert_eq!(mbc.container.size(), 0);
+    assert!(mbc.remove(id).is_err());
+}
+
+#[test]
+fn multiblockcontainer_cycle() {
+    let prefix = Path::new("./test_output/mbc_test_cycle");
+    let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(&prefix, 16).unwrap();
+    let long: Record = (0 .. 10).collect();
+    let id = mbc.insert(long).unwrap();
+    let _ = mbc.container.update(id, encode_block(id, &[1, 2, 3]).unwrap()).unwrap();
+    match mbc.get(id) {
+        Err(ContainerError::InvalidMetadata) => {},
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(mbc.remove(id).is_err());
+    assert_eq!(mbc.contains(id).unwrap(), true);
+}
+
+#[test]
+fn multiblockcontainer_open() {
+    let prefix = Path::new("./test_output/mbc_test_open");
+    let long: Record = (0 .. 100).collect();
+    let id;
+    {
+        let mut mbc = MultiBlockContainer::new_from_prefix_and_block_size(&prefix, 16).unwrap();
+        id = mbc.insert(long.clone()).unwrap();
+    }
+    let mut mbc = MultiBlockContainer::open(&prefix).unwrap();
+    assert_eq!(mbc.get(id).unwrap(), Some(long));
+}
+
+#[test]
+fn multiblockcontainer_memory_storage() {
+    use super::storage::MemoryStorageProvider;
+    let provider = MemoryStorageProvider::new();
+    let long: Record = (0 .. 100).collect();
+    let id;
+    {
+        let mut mbc = MultiBlockContainer::create_in(provider.clone(), 16).unwrap();
+        id = mbc.insert(long.clone()).unwrap();
+    }
+    let mut mbc = MultiBlockContainer::open_in(provider).unwrap();
+    assert_eq!(mbc.size(), 1);
+    assert_eq!(mbc.get(id).unwrap(), Some(long));
+}
+
+#[test]
+fn multiblockcontainer_open_small_blocks() {
+    let prefix = Path::new("./test_output/mbc_test_open_small_blocks");
+    drop(BlockFileContainer::new_from_prefix_and_block_size(&prefix, 8).unwrap());
+    drop(BitSetFile::new(File::create(prefix.with_extension("hbm")).unwrap()));
+    match MultiBlockContainer::open(&prefix) {
+        Err(ContainerError::InvalidMetadata) => {},
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("opened a container whose blocks can not hold the chain header"),
+    }
+}
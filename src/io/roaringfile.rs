@@ -0,0 +1,621 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Result, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::cmp;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::super::error::{ContainerError, Result as ContainerResult};
+use super::bitsetfile::{BitSet, ContainsIterator};
+use super::header::{self, HEADER_BYTE_SIZE, ROARING_MAGIC};
+
+/// An array chunk is turned into a bitmap chunk once it holds more values.
+static ARRAY_MAX_LEN: usize = 4096;
+/// A run chunk is turned into a bitmap chunk once it holds more runs.
+static RUNS_MAX_LEN: usize = 2048;
+static BITMAP_WORDS: usize = 1024;
+
+/// The values of a chunk, i.e. the lower 16 bits of the values sharing the same upper bits.
+#[derive(Debug, Clone, PartialEq)]
+enum Chunk {
+    /// The sorted values.
+    Array(Vec<u16>),
+    /// One bit per value.
+    Bitmap(Vec<u64>),
+    /// Sorted and disjoint runs of values, each given by its first and its last value.
+    Runs(Vec<(u16, u16)>),
+}
+
+impl Chunk {
+    fn contains(&self, value: u16) -> bool {
+        match *self {
+            Chunk::Array(ref values) => values.binary_search(&value).is_ok(),
+            Chunk::Bitmap(ref words) => words[value as usize / 64] & (1 << (value % 64)) != 0,
+            Chunk::Runs(ref runs) => run_index(runs, value).is_some(),
+        }
+    }
+
+    /// Adds a value. Returns `true` if the value was not already present.
+    fn insert(&mut self, value: u16) -> bool {
+        let inserted = match *self {
+            Chunk::Array(ref mut values) => match values.binary_search(&value) {
+                Ok(_) => false,
+                Err(position) => {
+                    values.insert(position, value);
+                    true
+                },
+            },
+            Chunk::Bitmap(ref mut words) => {
+                let mask = 1 << (value % 64);
+                let word = &mut words[value as usize / 64];
+                let inserted = *word & mask == 0;
+                *word |= mask;
+                inserted
+            },
+            Chunk::Runs(ref mut runs) => insert_into_runs(runs, value),
+        };
+        let too_large = match *self {
+            Chunk::Array(ref values) => values.len() > ARRAY_MAX_LEN,
+            Chunk::Runs(ref runs) => runs.len() > RUNS_MAX_LEN,
+            Chunk::Bitmap(_) => false,
+        };
+        if too_large {
+            *self = Chunk::Bitmap(self.to_bitmap());
+        }
+        inserted
+    }
+
+    /// Removes a value. Returns `true` if the value was present.
+    fn remove(&mut self, value: u16) -> bool {
+        let removed = match *self {
+            Chunk::Array(ref mut values) => match values.binary_search(&value) {
+                Ok(position) => {
+                    values.remove(position);
+                    true
+                },
+                Err(_) => false,
+            },
+            Chunk::Bitmap(ref mut words) => {
+                let mask = 1 << (value % 64);
+                let word = &mut words[value as usize / 64];
+                let removed = *word & mask != 0;
+                *word &= !mask;
+                removed
+            },
+            Chunk::Runs(ref mut runs) => remove_from_runs(runs, value),
+        };
+        let convert = match *self {
+            Chunk::Bitmap(_) => self.len() <= ARRAY_MAX_LEN,
+            Chunk::Runs(ref runs) => runs.len() > RUNS_MAX_LEN,
+            Chunk::Array(_) => false,
+        };
+        if convert {
+            *self = if self.len() <= ARRAY_MAX_LEN { Chunk::Array(self.values()) } else { Chunk::Bitmap(self.to_bitmap()) };
+        }
+        removed
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            Chunk::Array(ref values) => values.len(),
+            Chunk::Bitmap(ref words) => words.iter().map(|word| word.count_ones() as usize).sum(),
+            Chunk::Runs(ref runs) => runs.iter().map(|&(first, last)| (last - first) as usize + 1).sum(),
+        }
+    }
+
+    /// Returns the smallest value that is not smaller than `from`.
+    fn next(&self, from: u16) -> Option<u16> {
+        match *self {
+            Chunk::Array(ref values) => {
+                let position = match values.binary_search(&from) {
+                    Ok(position) | Err(position) => position,
+                };
+                values.get(position).cloned()
+            },
+            Chunk::Bitmap(ref words) => {
+                let mut index = from as usize / 64;
+                let mut word = words[index] & (!0u64 << (from % 64));
+                loop {
+                    if word != 0 {
+                        return Some((index * 64) as u16 + word.trailing_zeros() as u16);
+                    }
+                    index += 1;
+                    if index == BITMAP_WORDS {
+                        return None;
+                    }
+                    word = words[index];
+                }
+            },
+            Chunk::Runs(ref runs) => {
+                let position = match runs.binary_search_by(|&(first, _)| first.cmp(&from)) {
+                    Ok(position) => return Some(runs[position].0),
+                    Err(position) => position,
+                };
+                if position > 0 && runs[position - 1].1 >= from {
+                    return Some(from);
+                }
+                runs.get(position).map(|&(first, _)| first)
+            },
+        }
+    }
+
+    /// Returns the largest value that is not larger than `from`.
+    fn prev(&self, from: u16) -> Option<u16> {
+        match *self {
+            Chunk::Array(ref values) => match values.binary_search(&from) {
+                Ok(_) => Some(from),
+                Err(0) => None,
+                Err(position) => Some(values[position - 1]),
+            },
+            Chunk::Bitmap(ref words) => {
+                let mut index = from as usize / 64;
+                let mut word = words[index] & (!0u64 >> (63 - from % 64));
+                loop {
+                    if word != 0 {
+                        return Some((index * 64) as u16 + 63 - word.leading_zeros() as u16);
+                    }
+                    if index == 0 {
+                        return None;
+                    }
+                    index -= 1;
+                    word = words[index];
+                }
+            },
+            Chunk::Runs(ref runs) => {
+                let position = match runs.binary_search_by(|&(first, _)| first.cmp(&from)) {
+                    Ok(_) => return Some(from),
+                    Err(0) => return None,
+                    Err(position) => position,
+                };
+                Some(cmp::min(runs[position - 1].1, from))
+            },
+        }
+    }
+
+    fn values(&self) -> Vec<u16> {
+        match *self {
+            Chunk::Array(ref values) => values.clone(),
+            Chunk::Bitmap(_) | Chunk::Runs(_) => {
+                let mut values = Vec::with_capacity(self.len());
+                let mut next = self.next(0);
+                while let Some(value) = next {
+                    values.push(value);
+                    next = if value == u16::max_value() { None } else { self.next(value + 1) };
+                }
+                values
+            },
+        }
+    }
+
+    fn to_bitmap(&self) -> Vec<u64> {
+        let mut words = vec![0u64; BITMAP_WORDS];
+        for value in self.values() {
+            words[value as usize / 64] |= 1 << (value % 64);
+        }
+        words
+    }
+
+    fn to_runs(&self) -> Vec<(u16, u16)> {
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for value in self.values() {
+            match runs.last_mut() {
+                Some(run) if run.1 + 1 == value => {
+                    run.1 = value;
+                    continue;
+                },
+                _ => {},
+            }
+            runs.push((value, value));
+        }
+        runs
+    }
+
+    /// Switches to the encoding that needs the fewest bytes.
+    fn optimize(&mut self) {
+        let runs = self.to_runs();
+        let array_bytes = 2 * self.len();
+        let bitmap_bytes = 8 * BITMAP_WORDS;
+        let runs_bytes = 4 * runs.len();
+        let optimized = if runs_bytes <= array_bytes && runs_bytes <= bitmap_bytes {
+            Chunk::Runs(runs)
+        }
+        else if array_bytes <= bitmap_bytes {
+            Chunk::Array(self.values())
+        }
+        else {
+            Chunk::Bitmap(self.to_bitmap())
+        };
+        *self = optimized;
+    }
+}
+
+/// Returns the index of the run containing `value`.
+fn run_index(runs: &[(u16, u16)], value: u16) -> Option<usize> {
+    match runs.binary_search_by(|&(first, _)| first.cmp(&value)) {
+        Ok(position) => Some(position),
+        Err(0) => None,
+        Err(position) => if runs[position - 1].1 >= value { Some(position - 1) } else { None },
+    }
+}
+
+fn insert_into_runs(runs: &mut Vec<(u16, u16)>, value: u16) -> bool {
+    if run_index(runs, value).is_some() {
+        return false;
+    }
+    let position = match runs.binary_search_by(|&(first, _)| first.cmp(&value)) {
+        Ok(position) | Err(position) => position,
+    };
+    let joins_previous = position > 0 && runs[position - 1].1 as u32 + 1 == value as u32;
+    let joins_next = position < runs.len() && runs[position].0 as u32 == value as u32 + 1;
+    match (joins_previous, joins_next) {
+        (true, true) => {
+            runs[position - 1].1 = runs[position].1;
+            runs.remove(position);
+        },
+        (true, false) => runs[position - 1].1 = value,
+        (false, true) => runs[position].0 = value,
+        (false, false) => runs.insert(position, (value, value)),
+    }
+    true
+}
+
+fn remove_from_runs(runs: &mut Vec<(u16, u16)>, value: u16) -> bool {
+    let index = match run_index(runs, value) {
+        Some(index) => index,
+        None => return false,
+    };
+    let (first, last) = runs[index];
+    if first == last {
+        runs.remove(index);
+    }
+    else if value == first {
+        runs[index].0 = value + 1;
+    }
+    else if value == last {
+        runs[index].1 = value - 1;
+    }
+    else {
+        runs[index].1 = value - 1;
+        runs.insert(index + 1, (value + 1, last));
+    }
+    true
+}
+
+#[inline]
+fn split(bit: u64) -> (u64, u16) {
+    (bit >> 16, bit as u16)
+}
+
+/// A compressed bit set in the style of Roaring bitmaps. The values are split into chunks of 2^16 values
+/// sharing their upper bits, and each chunk is stored as a sorted array, a bitmap or a list of runs,
+/// whichever is smallest. Sparse sets with long runs of ids, like the ones of a `BlockFileContainer` after
+/// many removes, need a fraction of the space of a `BitSetFile`.
+///
+/// The set is kept in memory and `sync` replaces the file atomically with a temporary copy. The file
+/// holds the header, the size, the number of chunks and then for each chunk its key, its kind,
+/// its length and its values.
+#[derive(Debug)]
+pub struct RoaringBitSetFile {
+    path: PathBuf,
+    chunks: BTreeMap<u64, Chunk>,
+    size: u64,
+}
+
+impl RoaringBitSetFile {
+    /// Creates an empty set. An existing file is replaced.
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut rbf = RoaringBitSetFile{path: path.to_path_buf(), chunks: BTreeMap::new(), size: 0};
+        try!(rbf.sync());
+        Ok(rbf)
+    }
+
+    pub fn open(path: &Path) -> ContainerResult<Self> {
+        let mut file = try!(File::open(path));
+        try!(header::read_header(&file, ROARING_MAGIC));
+        try!(file.seek(SeekFrom::Start(HEADER_BYTE_SIZE)));
+        let mut reader = BufReader::new(file);
+        let size = try!(reader.read_u64::<LittleEndian>());
+        let chunk_count = try!(reader.read_u64::<LittleEndian>());
+        let mut chunks = BTreeMap::new();
+        for _ in 0 .. chunk_count {
+            let key = try!(reader.read_u64::<LittleEndian>());
+            let kind = try!(reader.read_u8());
+            let len = try!(reader.read_u32::<LittleEndian>()) as usize;
+            let chunk = match kind {
+                0 => {
+                    let mut values = Vec::with_capacity(len);
+                    for _ in 0 .. len {
+                        values.push(try!(reader.read_u16::<LittleEndian>()));
+                    }
+                    Chunk::Array(values)
+                },
+                1 => {
+                    let mut words = Vec::with_capacity(BITMAP_WORDS);
+                    for _ in 0 .. BITMAP_WORDS {
+                        words.push(try!(reader.read_u64::<LittleEndian>()));
+                    }
+                    Chunk::Bitmap(words)
+                },
+                2 => {
+                    let mut runs = Vec::with_capacity(len);
+                    for _ in 0 .. len {
+                        let first = try!(reader.read_u16::<LittleEndian>());
+                        let last = try!(reader.read_u16::<LittleEndian>());
+                        runs.push((first, last));
+                    }
+                    Chunk::Runs(runs)
+                },
+                _ => return Err(ContainerError::InvalidMetadata),
+            };
+            chunks.insert(key, chunk);
+        }
+        let rbf = RoaringBitSetFile{path: path.to_path_buf(), chunks: chunks, size: size};
+        if rbf.chunks.values().map(|chunk| chunk.len() as u64).sum::<u64>() != size {
+            return Err(ContainerError::InvalidMetadata);
+        }
+        Ok(rbf)
+    }
+
+    /// Adds a value to the set. Returns `true` if the value was not already present in the set.
+    pub fn insert(&mut self, bit: u64) -> Result<bool> {
+        let (key, value) = split(bit);
+        let inserted = self.chunks.entry(key).or_insert_with(|| Chunk::Array(Vec::new())).insert(value);
+        if inserted {
+            self.size += 1;
+        }
+        Ok(inserted)
+    }
+
+    pub fn remove(&mut self, bit: u64) -> Result<bool> {
+        let (key, value) = split(bit);
+        let (removed, empty) = match self.chunks.get_mut(&key) {
+            Some(chunk) => (chunk.remove(value), chunk.len() == 0),
+            None => (false, false),
+        };
+        if empty {
+            self.chunks.remove(&key);
+        }
+        if removed {
+            self.size -= 1;
+        }
+        Ok(removed)
+    }
+
+    pub fn contains(&self, bit: u64) -> Result<bool> {
+        let (key, value) = split(bit);
+        Ok(self.chunks.get(&key).map(|chunk| chunk.contains(value)).unwrap_or(false))
+    }
+
+    pub fn max_bit(&self) -> Option<u64> {
+        self.chunks.iter().next_back().and_then(|(key, chunk)| chunk.prev(u16::max_value()).map(|value| (key << 16) | value as u64))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.chunks.clear();
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Returns the smallest value in the set that is not smaller than `from`.
+    pub fn next_set_bit(&self, from: u64) -> Result<Option<u64>> {
+        let (from_key, from_value) = split(from);
+        for (key, chunk) in self.chunks.range(from_key ..) {
+            let start = if *key == from_key { from_value } else { 0 };
+            if let Some(value) = chunk.next(start) {
+                return Ok(Some((key << 16) | value as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the largest value in the set that is not larger than `from`.
+    pub fn prev_set_bit(&self, from: u64) -> Result<Option<u64>> {
+        let (from_key, from_value) = split(from);
+        for (key, chunk) in self.chunks.range(.. from_key + 1).rev() {
+            let start = if *key == from_key { from_value } else { u16::max_value() };
+            if let Some(value) = chunk.prev(start) {
+                return Ok(Some((key << 16) | value as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the number of values in `lo .. hi`. Chunks inside the range are counted as a whole.
+    pub fn count_range(&self, lo: u64, hi: u64) -> Result<u64> {
+        if lo >= hi {
+            return Ok(0);
+        }
+        let (lo_key, _) = split(lo);
+        let (hi_key, _) = split(hi - 1);
+        let mut count = 0;
+        for (key, chunk) in self.chunks.range(lo_key .. hi_key + 1) {
+            let first = key << 16;
+            let last = first | 0xFFFF;
+            if lo <= first && last < hi {
+                count += chunk.len() as u64;
+            }
+            else {
+                count += chunk.values().into_iter().map(|value| first | value as u64).filter(|bit| lo <= *bit && *bit < hi).count() as u64;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns the value at position `n` of the ascending values, starting with 0. Whole chunks are
+    /// skipped by their number of values.
+    pub fn select(&self, n: u64) -> Result<Option<u64>> {
+        let mut remaining = n;
+        for (key, chunk) in &self.chunks {
+            let len = chunk.len() as u64;
+            if remaining < len {
+                return Ok(Some((key << 16) | chunk.values()[remaining as usize] as u64));
+            }
+            remaining -= len;
+        }
+        Ok(None)
+    }
+
+    /// Writes the set to a temporary file and moves it over the old one.
+    pub fn sync(&mut self) -> Result<()> {
+        let mut temporary_path = OsString::from(self.path.as_os_str());
+        temporary_path.push(".tmp");
+        let temporary_path = PathBuf::from(temporary_path);
+        {
+            let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&temporary_path));
+            try!(header::write_header(&mut file, ROARING_MAGIC));
+            try!(file.seek(SeekFrom::Start(HEADER_BYTE_SIZE)));
+            let mut writer = BufWriter::new(file);
+            try!(writer.write_u64::<LittleEndian>(self.size));
+            try!(writer.write_u64::<LittleEndian>(self.chunks.len() as u64));
+            for (key, chunk) in &mut self.chunks {
+                chunk.optimize();
+                try!(writer.write_u64::<LittleEndian>(*key));
+                match *chunk {
+                    Chunk::Array(ref values) => {
+                        try!(writer.write_u8(0));
+                        try!(writer.write_u32::<LittleEndian>(values.len() as u32));
+                        for value in values {
+                            try!(writer.write_u16::<LittleEndian>(*value));
+                        }
+                    },
+                    Chunk::Bitmap(ref words) => {
+                        try!(writer.write_u8(1));
+                        try!(writer.write_u32::<LittleEndian>(words.len() as u32));
+                        for word in words {
+                            try!(writer.write_u64::<LittleEndian>(*word));
+                        }
+                    },
+                    Chunk::Runs(ref runs) => {
+                        try!(writer.write_u8(2));
+                        try!(writer.write_u32::<LittleEndian>(runs.len() as u32));
+                        for &(first, last) in runs {
+                            try!(writer.write_u16::<LittleEndian>(first));
+                            try!(writer.write_u16::<LittleEndian>(last));
+                        }
+                    },
+                }
+            }
+            try!(writer.flush());
+            let file = try!(writer.into_inner().map_err(|err| err.into_error()));
+            try!(file.sync_all());
+        }
+        fs::rename(&temporary_path, &self.path)
+    }
+
+    pub fn contains_iter(&self) -> ContainsIterator<'_> {
+        ContainsIterator::new(self)
+    }
+}
+
+impl BitSet for RoaringBitSetFile {
+    fn insert(&mut self, bit: u64) -> Result<bool> { RoaringBitSetFile::insert(self, bit) }
+    fn remove(&mut self, bit: u64) -> Result<bool> { RoaringBitSetFile::remove(self, bit) }
+    fn contains(&self, bit: u64) -> Result<bool> { RoaringBitSetFile::contains(self, bit) }
+    fn max_bit(&self) -> Option<u64> { RoaringBitSetFile::max_bit(self) }
+    fn size(&self) -> u64 { RoaringBitSetFile::size(self) }
+    fn clear(&mut self) -> Result<()> { RoaringBitSetFile::clear(self) }
+    fn sync(&mut self) -> Result<()> { RoaringBitSetFile::sync(self) }
+    fn next_set_bit(&self, from: u64) -> Result<Option<u64>> { RoaringBitSetFile::next_set_bit(self, from) }
+    fn prev_set_bit(&self, from: u64) -> Result<Option<u64>> { RoaringBitSetFile::prev_set_bit(self, from) }
+    fn count_range(&self, lo: u64, hi: u64) -> Result<u64> { RoaringBitSetFile::count_range(self, lo, hi) }
+    fn select(&self, n: u64) -> Result<Option<u64>> { RoaringBitSetFile::select(self, n) }
+}
+
+impl Drop for RoaringBitSetFile {
+    fn drop(&mut self) {
+        self.sync().expect("Could not write roaring bit set file");
+    }
+}
+
+#[test]
+fn roaring_chunk_conversions() {
+    let mut chunk = Chunk::Array(Vec::new());
+    for value in 0 .. ARRAY_MAX_LEN as u16 + 1 {
+        assert!(chunk.insert(value * 2));
+    }
+    match chunk {
+        Chunk::Bitmap(_) => {},
+        _ => panic!("array chunk was not converted"),
+    }
+    assert!(chunk.remove(0));
+    match chunk {
+        Chunk::Array(_) => {},
+        _ => panic!("bitmap chunk was not converted"),
+    }
+    assert_eq!(chunk.next(1), Some(2));
+    assert_eq!(chunk.prev(u16::max_value()), Some(ARRAY_MAX_LEN as u16 * 2));
+
+    let mut chunk = Chunk::Runs(Vec::new());
+    for value in 10 .. 20 {
+        assert!(chunk.insert(value));
+    }
+    assert!(!chunk.insert(15));
+    assert_eq!(chunk, Chunk::Runs(vec!((10, 19))));
+    assert!(chunk.remove(15));
+    assert_eq!(chunk, Chunk::Runs(vec!((10, 14), (16, 19))));
+    assert!(chunk.insert(15));
+    assert_eq!(chunk, Chunk::Runs(vec!((10, 19))));
+    assert_eq!(chunk.next(0), Some(10));
+    assert_eq!(chunk.next(12), Some(12));
+    assert_eq!(chunk.next(20), None);
+    assert_eq!(chunk.prev(9), None);
+    assert_eq!(chunk.prev(100), Some(19));
+    assert_eq!(chunk.len(), 10);
+
+    let mut chunk = Chunk::Array((0 .. 3000).collect());
+    chunk.optimize();
+    assert_eq!(chunk, Chunk::Runs(vec!((0, 2999))));
+}
+
+#[test]
+fn roaring_bit_set_file() {
+    use std::path::Path;
+    use std::fs::remove_file;
+
+    let path = Path::new("./test_output/roaring_bit_set_file.test");
+    let bits: Vec<u64> = (0 .. 5000).chain(70000 .. 70010).chain(vec!(1 << 40)).collect();
+    {
+        let mut rbf = RoaringBitSetFile::new(&path).unwrap();
+        assert_eq!(rbf.max_bit(), None);
+        for bit in &bits {
+            assert!(rbf.insert(*bit).unwrap());
+        }
+        assert!(!rbf.insert(70000).unwrap());
+        assert!(rbf.remove(2500).unwrap());
+        assert!(!rbf.remove(2500).unwrap());
+    }
+    let mut rbf = RoaringBitSetFile::open(&path).unwrap();
+    assert_eq!(rbf.size(), bits.len() as u64 - 1);
+    assert_eq!(rbf.max_bit(), Some(1 << 40));
+    assert_eq!(rbf.contains(2500).unwrap(), false);
+    assert_eq!(rbf.contains(2501).unwrap(), true);
+    assert_eq!(rbf.next_set_bit(5000).unwrap(), Some(70000));
+    assert_eq!(rbf.prev_set_bit((1 << 40) - 1).unwrap(), Some(70009));
+    assert_eq!(rbf.contains_iter().rev().take(2).collect::<Vec<u64>>(), vec!(1 << 40, 70009));
+
+    assert_eq!(rbf.count_range(0, 1 << 40).unwrap(), 5009);
+    assert_eq!(rbf.count_range(4990, 70005).unwrap(), 15);
+    assert_eq!(BitSet::rank(&rbf, 70000).unwrap(), 4999);
+    assert_eq!(rbf.select(0).unwrap(), Some(0));
+    assert_eq!(rbf.select(2500).unwrap(), Some(2501));
+    assert_eq!(rbf.select(5009).unwrap(), Some(1 << 40));
+    assert_eq!(rbf.select(5010).unwrap(), None);
+
+    assert!(rbf.remove(1 << 40).unwrap());
+    assert_eq!(rbf.max_bit(), Some(70009));
+    rbf.clear().unwrap();
+    assert_eq!(rbf.max_bit(), None);
+    assert_eq!(rbf.contains_iter().count(), 0);
+    drop(rbf);
+    remove_file(&path).unwrap();
+}
@@ -1,36 +1,78 @@
 use std::fs::{File};
-use std::io::{Result, Seek, SeekFrom};
+use std::io::{Result};
+use std::cmp;
 
-use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use super::super::error::{Result as ContainerResult};
+use super::header::{self, HEADER_BYTE_SIZE, STACK_MAGIC};
+use super::storage::RawAccess;
 
+
+/// The number of entries spilled to or refilled from the file at once.
+static PAGE_ENTRIES: usize = 512;
+
+/// A stack of `u64`s stored in a file. The top of the stack is kept in an in-memory buffer of up to two
+/// pages, which is spilled and refilled a page at a time. All entries are on disc after `sync`.
 #[derive(Debug)]
-pub struct StackFile{
-    file: File,
+pub struct StackFile<S: RawAccess = File>{
+    file: S,
     entries: u64,
+    persisted: u64,
+    buffer: Vec<u64>,
 }
 
-impl StackFile{
+impl<S: RawAccess> StackFile<S>{
 
     pub fn next(&mut self) -> Result<Option<u64>>{
-        if !self.is_empty() {
-            let offset = try!(self.file.seek(SeekFrom::End(-8))); //move one u64 from the end
-            let element  = try!(self.file.read_u64::<NativeEndian>()); // read one u64
-            try!(self.file.set_len(offset));   // "remove" the last u64
-            self.entries -= 1;
-            Ok(Some(element))
+        if self.is_empty() {
+            return Ok(None);
         }
-        else{
-            Ok(None)
+        if self.buffer.is_empty() {
+            try!(self.refill());
+        }
+        match self.buffer.pop() {
+            Some(element) => {
+                self.entries -= 1;
+                Ok(Some(element))
+            },
+            None => Ok(None),
         }
     }
+
     pub fn add(&mut self, element:u64) -> Result<u64>{
-        try!(self.file.seek(SeekFrom::End(0)));
-        try!(self.file.write_u64::<NativeEndian>(element));
-        self.entries += 1;
+        self.push_many(&[element])
+    }
+
+    /// Pushes all elements, the last one ends up on top. Returns the number of entries.
+    pub fn push_many(&mut self, elements: &[u64]) -> Result<u64>{
+        self.buffer.extend_from_slice(elements);
+        self.entries += elements.len() as u64;
+        if self.buffer.len() > 2 * PAGE_ENTRIES {
+            let spilled = self.buffer.len() - PAGE_ENTRIES;
+            try!(self.spill(spilled));
+        }
         Ok(self.entries)
     }
 
+    /// Pops up to `n` elements, starting with the top of the stack.
+    pub fn pop_many(&mut self, n: usize) -> Result<Vec<u64>>{
+        let mut elements = Vec::with_capacity(cmp::min(n as u64, self.entries) as usize);
+        while elements.len() < n && !self.is_empty() {
+            if self.buffer.is_empty() {
+                try!(self.refill());
+                if self.buffer.is_empty() {
+                    break;
+                }
+            }
+            let taken = cmp::min(n - elements.len(), self.buffer.len());
+            let start = self.buffer.len() - taken;
+            elements.extend(self.buffer.drain(start..).rev());
+            self.entries -= taken as u64;
+        }
+        Ok(elements)
+    }
+
     pub fn is_empty(&self) -> bool{
         self.entries() == 0
     }
@@ -41,24 +83,66 @@ impl StackFile{
 
     pub fn clear(&mut self) -> Result<()>{
         self.entries = 0;
-        self.file.set_len(0)
+        self.persisted = 0;
+        self.buffer.clear();
+        self.file.set_len(HEADER_BYTE_SIZE)
     }
 
 
-    pub fn new(file: File) -> Self {
-        StackFile{file: file, entries: 0}
+    /// Writes the buffered entries and flushes the stack to disc.
+    pub fn sync(&mut self) -> Result<()>{
+        let buffered = self.buffer.len();
+        try!(self.spill(buffered));
+        self.file.sync()
     }
 
-    pub fn open(file:File) -> Result<Self> {
-        let meta = try!(file.metadata());
-        let entries = meta.len() / 8;
-        Ok(StackFile{file: file, entries: entries})
+    /// Appends the `n` lowest buffered entries to the file.
+    fn spill(&mut self, n: usize) -> Result<()>{
+        if n == 0 {
+            return Ok(());
+        }
+        let mut bytes = Vec::with_capacity(n * 8);
+        for element in self.buffer.drain(..n) {
+            try!(bytes.write_u64::<LittleEndian>(element));
+        }
+        try!(self.file.write_at(&bytes, HEADER_BYTE_SIZE + self.persisted * 8));
+        self.persisted += n as u64;
+        Ok(())
+    }
+
+    /// Moves up to a page of entries from the end of the file into the empty buffer.
+    fn refill(&mut self) -> Result<()>{
+        let n = cmp::min(PAGE_ENTRIES as u64, self.persisted);
+        if n == 0 {
+            return Ok(());
+        }
+        let offset = HEADER_BYTE_SIZE + (self.persisted - n) * 8;
+        let mut bytes = vec![0u8; n as usize * 8];
+        try!(self.file.read_exact_at(&mut bytes, offset));
+        let mut reader = &bytes[..];
+        for _ in 0 .. n {
+            self.buffer.push(try!(reader.read_u64::<LittleEndian>()));
+        }
+        try!(self.file.set_len(offset));
+        self.persisted -= n;
+        Ok(())
+    }
+
+    pub fn new(mut file: S) -> Self {
+        header::write_header(&mut file, STACK_MAGIC).expect("Could not write file header");
+        StackFile{file: file, entries: 0, persisted: 0, buffer: Vec::new()}
+    }
+
+    pub fn open(file: S) -> ContainerResult<Self> {
+        try!(header::read_header(&file, STACK_MAGIC));
+        let entries = (try!(file.len()) - HEADER_BYTE_SIZE) / 8;
+        Ok(StackFile{file: file, entries: entries, persisted: entries, buffer: Vec::new()})
     }
 }
 
-impl Drop for StackFile{
+impl<S: RawAccess> Drop for StackFile<S>{
     fn drop(&mut self) {
-        let _ = self.file.sync_data();
+        let _ = self.sync();
     }
 }
 
@@ -68,13 +152,13 @@ fn stack_file_new(){
     use std::fs::{OpenOptions, remove_file};
 
     let path = Path::new("./test_output/stackfile_new.test");
-    let file = OpenOptions::new().read(true).write(true).create(true).open(&path).expect("Test file not created");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
     let stack_file = StackFile::new(file);
     assert!(stack_file.is_empty());
     assert_eq!(stack_file.entries(), 0);
     assert_eq!(stack_file.entries, 0);
     let metadata = stack_file.file.metadata().expect("No metadata for test file");
-    assert_eq!(metadata.len(), 0);
+    assert_eq!(metadata.len(), HEADER_BYTE_SIZE);
     remove_file(&path).unwrap();
 }
 
@@ -84,14 +168,15 @@ fn stack_file_add(){
     use std::fs::{OpenOptions, remove_file};
 
     let path = Path::new("./test_output/stackfile_add.test");
-    let file = OpenOptions::new().read(true).write(true).create(true).open(&path).expect("Test file not created");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
     let mut stack_file = StackFile::new(file);
     stack_file.add(8).unwrap();
     assert!(!stack_file.is_empty());
     assert_eq!(stack_file.entries(), 1);
     assert_eq!(stack_file.entries, 1);
+    stack_file.sync().unwrap();
     let metadata = stack_file.file.metadata().expect("No metadata for test file");
-    assert_eq!(metadata.len(), 8);
+    assert_eq!(metadata.len(), HEADER_BYTE_SIZE + 8);
     remove_file(&path).unwrap();
 }
 
@@ -101,7 +186,7 @@ fn stack_file_next(){
     use std::fs::{OpenOptions, remove_file};
 
     let path = Path::new("./test_output/stackfile_next.test");
-    let file = OpenOptions::new().read(true).write(true).create(true).open(&path).expect("Test file not created");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
     let mut stack_file = StackFile::new(file);
     let next = stack_file.next().expect("Could not read next value");
     assert_eq!(next, None);
@@ -112,6 +197,107 @@ fn stack_file_next(){
     assert_eq!(next, Some(8u64));
     assert_eq!(stack_file.entries, 0);
     let metadata = stack_file.file.metadata().expect("No metadata for test file");
-    assert_eq!(metadata.len(), 0);
+    assert_eq!(metadata.len(), HEADER_BYTE_SIZE);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn stack_file_open(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/stackfile_open.test");
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+        let mut stack_file = StackFile::new(file);
+        stack_file.add(3u64).unwrap();
+        stack_file.add(5u64).unwrap();
+    }
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let mut stack_file = StackFile::open(file).unwrap();
+    assert_eq!(stack_file.entries(), 2);
+    assert_eq!(stack_file.next().unwrap(), Some(5u64));
+    assert_eq!(stack_file.next().unwrap(), Some(3u64));
+    assert_eq!(stack_file.next().unwrap(), None);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn stack_file_push_and_pop_many(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/stackfile_push_and_pop_many.test");
+    let elements: Vec<u64> = (0 .. 5 * PAGE_ENTRIES as u64).collect();
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+        let mut stack_file = StackFile::new(file);
+        assert_eq!(stack_file.push_many(&elements).unwrap(), elements.len() as u64);
+        assert!(stack_file.buffer.len() <= 2 * PAGE_ENTRIES);
+        assert_eq!(stack_file.pop_many(3).unwrap(), vec!(2559, 2558, 2557));
+        stack_file.sync().unwrap();
+        assert!(stack_file.buffer.is_empty());
+        assert_eq!(stack_file.file.metadata().unwrap().len(), HEADER_BYTE_SIZE + 8 * (elements.len() as u64 - 3));
+    }
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let mut stack_file = StackFile::open(file).unwrap();
+    assert_eq!(stack_file.entries(), elements.len() as u64 - 3);
+    let popped = stack_file.pop_many(2 * PAGE_ENTRIES + 1).unwrap();
+    assert_eq!(popped.len(), 2 * PAGE_ENTRIES + 1);
+    assert_eq!(popped[0], 2556);
+    assert_eq!(popped[2 * PAGE_ENTRIES], 2556 - 2 * PAGE_ENTRIES as u64);
+    stack_file.add(7).unwrap();
+    assert_eq!(stack_file.next().unwrap(), Some(7));
+    let rest = stack_file.pop_many(usize::max_value()).unwrap();
+    assert_eq!(rest.len() as u64, elements.len() as u64 - 3 - popped.len() as u64);
+    assert_eq!(rest.last(), Some(&0));
+    assert!(stack_file.is_empty());
+    assert_eq!(stack_file.next().unwrap(), None);
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn stack_file_page_boundaries(){
+    use std::path::Path;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/stackfile_page_boundaries.test");
+    let pages = 2 * PAGE_ENTRIES as u64;
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+        let mut stack_file = StackFile::new(file);
+        for element in 0 .. pages {
+            stack_file.add(element).unwrap();
+        }
+        assert_eq!(stack_file.file.metadata().unwrap().len(), HEADER_BYTE_SIZE);
+        // Crossing two pages spills all but the top page.
+        stack_file.add(pages).unwrap();
+        assert_eq!(stack_file.buffer.len(), PAGE_ENTRIES);
+        assert_eq!(stack_file.file.metadata().unwrap().len(), HEADER_BYTE_SIZE + 8 * (PAGE_ENTRIES as u64 + 1));
+
+        // Popping past the buffered page refills the next one from the file.
+        for expected in (PAGE_ENTRIES as u64 + 1 .. pages + 1).rev() {
+            assert_eq!(stack_file.next().unwrap(), Some(expected));
+        }
+        assert!(stack_file.buffer.is_empty());
+        assert_eq!(stack_file.next().unwrap(), Some(PAGE_ENTRIES as u64));
+        assert_eq!(stack_file.buffer.len(), PAGE_ENTRIES - 1);
+        assert_eq!(stack_file.file.metadata().unwrap().len(), HEADER_BYTE_SIZE + 8);
+
+        // Across the boundary again with one push.
+        let elements: Vec<u64> = (PAGE_ENTRIES as u64 .. pages + 2).collect();
+        assert_eq!(stack_file.push_many(&elements).unwrap(), pages + 2);
+        assert_eq!(stack_file.buffer.len(), PAGE_ENTRIES);
+        assert_eq!(stack_file.pop_many(2).unwrap(), vec!(pages + 1, pages));
+    }
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let mut stack_file = StackFile::open(file).unwrap();
+    assert_eq!(stack_file.entries(), pages);
+    // Pops the page refilled first and one entry of the next.
+    let popped = stack_file.pop_many(PAGE_ENTRIES + 1).unwrap();
+    assert_eq!(popped, (PAGE_ENTRIES as u64 - 1 .. pages).rev().collect::<Vec<_>>());
+    let rest = stack_file.pop_many(usize::max_value()).unwrap();
+    assert_eq!(rest, (0 .. PAGE_ENTRIES as u64 - 1).rev().collect::<Vec<_>>());
+    assert!(stack_file.is_empty());
     remove_file(&path).unwrap();
 }
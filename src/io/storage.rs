@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "mmap")]
+use memmap2::{MmapMut, MmapOptions};
+
+/// Positional access to the bytes of a file or a file-like storage. There is no cursor, so reads only
+/// need `&self`. Storages can be moved between and shared by threads.
+pub trait RawAccess: Send + Sync {
+    /// Reads up to `buffer.len()` bytes starting at `offset`. Partial and interrupted reads are retried, so
+    /// fewer bytes are only read at the end of the storage.
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize>;
+
+    /// Writes all `bytes` starting at `offset`. The storage grows as needed and a gap is filled with zeros.
+    fn write_at(&mut self, bytes: &[u8], offset: u64) -> Result<()>;
+
+    fn set_len(&mut self, len: u64) -> Result<()>;
+
+    fn len(&self) -> Result<u64>;
+
+    /// Makes all writes durable.
+    fn sync(&mut self) -> Result<()>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(try!(self.len()) == 0)
+    }
+
+    /// Fills `buffer` with the bytes starting at `offset`.
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> Result<()> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match try!(self.read_at(&mut buffer[read..], offset + read as u64)) {
+                0 => return Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of the storage")),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RawAccess for File {
+    #[cfg(unix)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize> {
+        use std::os::unix::fs::FileExt;
+        let mut read = 0;
+        while read < buffer.len() {
+            match FileExt::read_at(self, &mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(read)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize> {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.seek_read(&mut buffer[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(read)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&mut self, bytes: &[u8], offset: u64) -> Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.write_all_at(bytes, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&mut self, bytes: &[u8], offset: u64) -> Result<()> {
+        use std::os::windows::fs::FileExt;
+        let mut written = 0;
+        while written < bytes.len() {
+            match self.seek_write(&bytes[written..], offset + written as u64) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write the whole buffer")),
+                Ok(n) => written += n,
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(try!(self.metadata()).len())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.sync_data()
+    }
+}
+
+/// A storage in memory. Clones share their bytes, so a `MemoryStorage` handed out by a
+/// `MemoryStorageProvider` sees the writes of earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Returns a copy of the stored bytes.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.bytes.lock().expect("memory storage poisoned").clone()
+    }
+}
+
+impl RawAccess for MemoryStorage {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize> {
+        let bytes = self.bytes.lock().expect("memory storage poisoned");
+        if offset >= bytes.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = cmp::min(buffer.len(), bytes.len() - start);
+        buffer[..len].copy_from_slice(&bytes[start .. start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&mut self, new_bytes: &[u8], offset: u64) -> Result<()> {
+        let mut bytes = self.bytes.lock().expect("memory storage poisoned");
+        let start = offset as usize;
+        let end = start + new_bytes.len();
+        if end > bytes.len() {
+            bytes.resize(end, 0);
+        }
+        bytes[start .. end].copy_from_slice(new_bytes);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        self.bytes.lock().expect("memory storage poisoned").resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.bytes.lock().expect("memory storage poisoned").len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The smallest memory map of a `MmapStorage`.
+#[cfg(feature = "mmap")]
+static MIN_MAP_BYTE_SIZE: u64 = 1 << 16;
+
+/// A file that is accessed through a shared memory map. The map may reach beyond the end of the file,
+/// its size is doubled when the file outgrows it, so appending does not remap every time. `flush` and
+/// `sync` write the mapped bytes back with `msync`.
+#[cfg(feature = "mmap")]
+#[derive(Debug)]
+pub struct MmapStorage {
+    file: File,
+    map: Option<MmapMut>,
+    len: u64,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapStorage {
+    /// Maps `file`, which has to be opened for reading and writing.
+    pub fn new(file: File) -> Result<MmapStorage> {
+        let len = try!(file.metadata()).len();
+        let mut storage = MmapStorage{file: file, map: None, len: len};
+        try!(storage.remap());
+        Ok(storage)
+    }
+
+    /// The bytes of the file.
+    pub fn as_slice(&self) -> &[u8] {
+        match self.map {
+            Some(ref map) => &map[.. self.len as usize],
+            None => &[],
+        }
+    }
+
+    /// Writes the changed pages back to the file and waits until they are on disc.
+    pub fn flush(&self) -> Result<()> {
+        match self.map {
+            Some(ref map) => map.flush_range(0, self.len as usize),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts writing the changed pages back to the file without waiting for it.
+    pub fn flush_async(&self) -> Result<()> {
+        match self.map {
+            Some(ref map) => map.flush_async_range(0, self.len as usize),
+            None => Ok(()),
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+
+    fn remap(&mut self) -> Result<()> {
+        self.map = None;
+        if self.len > 0 {
+            let capacity = cmp::max(self.len.next_power_of_two(), MIN_MAP_BYTE_SIZE);
+            // The file is only changed through this storage and no page behind its end is touched, so
+            // the map stays valid until it is replaced.
+            self.map = Some(try!(unsafe { MmapOptions::new().len(capacity as usize).map_mut(&self.file) }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl RawAccess for MmapStorage {
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> Result<usize> {
+        let bytes = self.as_slice();
+        if offset >= bytes.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let len = cmp::min(buffer.len(), bytes.len() - start);
+        buffer[..len].copy_from_slice(&bytes[start .. start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&mut self, bytes: &[u8], offset: u64) -> Result<()> {
+        let end = offset + bytes.len() as u64;
+        if end > self.len {
+            try!(RawAccess::set_len(self, end));
+        }
+        if let Some(ref mut map) = self.map {
+            map[offset as usize .. end as usize].copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> Result<()> {
+        if len < self.len {
+            // Bytes behind the new end have to read as zeros once the file grows again.
+            let old_len = self.len;
+            if let Some(ref mut map) = self.map {
+                for byte in &mut map[len as usize .. old_len as usize] {
+                    *byte = 0;
+                }
+            }
+        }
+        try!(self.file.set_len(len));
+        self.len = len;
+        if len > self.capacity() {
+            try!(self.remap());
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.len)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        try!(self.flush());
+        self.file.sync_data()
+    }
+}
+
+/// Creates and opens the storages of the files a `BlockFileContainer` consists of. Each file is named
+/// by its extension, e.g. `"ctr"`.
+pub trait StorageProvider: Send + Sync {
+    type Storage: RawAccess;
+
+    /// Creates an empty storage. An existing one is truncated.
+    fn create(&self, extension: &str) -> Result<Self::Storage>;
+
+    fn open(&self, extension: &str) -> Result<Self::Storage>;
+
+    fn exists(&self, extension: &str) -> bool;
+
+    fn remove(&self, extension: &str) -> Result<()>;
+
+    /// The path of the file, if the storage is a file. Needed by structures that replace their file
+    /// atomically, e.g. the `RoaringBitSetFile`.
+    fn path(&self, _extension: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Stores each file next to `prefix`, e.g. the `"ctr"` file at `prefix.ctr`.
+#[derive(Debug, Clone)]
+pub struct FileStorageProvider {
+    prefix: PathBuf,
+}
+
+impl FileStorageProvider {
+    pub fn new(prefix: &Path) -> FileStorageProvider {
+        FileStorageProvider{prefix: prefix.to_path_buf()}
+    }
+}
+
+impl StorageProvider for FileStorageProvider {
+    type Storage = File;
+
+    fn create(&self, extension: &str) -> Result<File> {
+        OpenOptions::new().read(true).write(true).create(true).truncate(true).open(self.prefix.with_extension(extension))
+    }
+
+    fn open(&self, extension: &str) -> Result<File> {
+        OpenOptions::new().read(true).write(true).open(self.prefix.with_extension(extension))
+    }
+
+    fn exists(&self, extension: &str) -> bool {
+        self.prefix.with_extension(extension).exists()
+    }
+
+    fn remove(&self, extension: &str) -> Result<()> {
+        fs::remove_file(self.prefix.with_extension(extension))
+    }
+
+    fn path(&self, extension: &str) -> Option<PathBuf> {
+        Some(self.prefix.with_extension(extension))
+    }
+}
+
+/// Keeps all files in memory. Clones share their files, so a container can be opened again from a clone
+/// of the provider it was created with.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorageProvider {
+    files: Arc<Mutex<HashMap<String, MemoryStorage>>>,
+}
+
+impl MemoryStorageProvider {
+    pub fn new() -> MemoryStorageProvider {
+        MemoryStorageProvider::default()
+    }
+}
+
+impl StorageProvider for MemoryStorageProvider {
+    type Storage = MemoryStorage;
+
+    fn create(&self, extension: &str) -> Result<MemoryStorage> {
+        let storage = MemoryStorage::new();
+        self.files.lock().expect("memory storage poisoned").insert(extension.to_string(), storage.clone());
+        Ok(storage)
+    }
+
+    fn open(&self, extension: &str) -> Result<MemoryStorage> {
+        match self.files.lock().expect("memory storage poisoned").get(extension) {
+            Some(storage) => Ok(storage.clone()),
+            None => Err(Error::new(ErrorKind::NotFound, "no such memory storage")),
+        }
+    }
+
+    fn exists(&self, extension: &str) -> bool {
+        self.files.lock().expect("memory storage poisoned").contains_key(extension)
+    }
+
+    fn remove(&self, extension: &str) -> Result<()> {
+        match self.files.lock().expect("memory storage poisoned").remove(extension) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::NotFound, "no such memory storage")),
+        }
+    }
+}
+
+/// Maps each file next to `prefix` into memory.
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone)]
+pub struct MmapStorageProvider {
+    files: FileStorageProvider,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapStorageProvider {
+    pub fn new(prefix: &Path) -> MmapStorageProvider {
+        MmapStorageProvider{files: FileStorageProvider::new(prefix)}
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl StorageProvider for MmapStorageProvider {
+    type Storage = MmapStorage;
+
+    fn create(&self, extension: &str) -> Result<MmapStorage> {
+        MmapStorage::new(try!(self.files.create(extension)))
+    }
+
+    fn open(&self, extension: &str) -> Result<MmapStorage> {
+        MmapStorage::new(try!(self.files.open(extension)))
+    }
+
+    fn exists(&self, extension: &str) -> bool {
+        self.files.exists(extension)
+    }
+
+    fn remove(&self, extension: &str) -> Result<()> {
+        self.files.remove(extension)
+    }
+
+    fn path(&self, extension: &str) -> Option<PathBuf> {
+        self.files.path(extension)
+    }
+}
+
+#[cfg(test)]
+fn check_raw_access<S: RawAccess>(storage: &mut S) {
+    assert_eq!(storage.len().unwrap(), 0);
+    storage.write_at(&[1u8, 2, 3], 4).unwrap();
+    assert_eq!(storage.len().unwrap(), 7);
+    let mut buffer = [9u8; 8];
+    assert_eq!(storage.read_at(&mut buffer, 0).unwrap(), 7);
+    assert_eq!(buffer, [0u8, 0, 0, 0, 1, 2, 3, 9]);
+    assert_eq!(storage.read_at(&mut buffer, 7).unwrap(), 0);
+    assert!(storage.read_exact_at(&mut buffer, 0).is_err());
+
+    storage.write_at(&[7u8; 4], 2).unwrap();
+    storage.read_exact_at(&mut buffer[..7], 0).unwrap();
+    assert_eq!(&buffer[..7], &[0u8, 0, 7, 7, 7, 7, 3]);
+    storage.set_len(2).unwrap();
+    assert_eq!(storage.len().unwrap(), 2);
+    storage.set_len(4).unwrap();
+    storage.read_exact_at(&mut buffer[..4], 0).unwrap();
+    assert_eq!(&buffer[..4], &[0u8; 4]);
+    storage.sync().unwrap();
+}
+
+#[test]
+fn storage_file() {
+    let provider = FileStorageProvider::new(Path::new("./test_output/storage_file"));
+    check_raw_access(&mut provider.create("test").unwrap());
+    assert!(provider.exists("test"));
+    assert_eq!(provider.open("test").unwrap().len().unwrap(), 4);
+    provider.remove("test").unwrap();
+    assert!(!provider.exists("test"));
+}
+
+#[test]
+fn storage_memory() {
+    let provider = MemoryStorageProvider::new();
+    check_raw_access(&mut provider.create("test").unwrap());
+    assert_eq!(provider.clone().open("test").unwrap().to_vec(), vec!(0u8; 4));
+    provider.remove("test").unwrap();
+    assert!(!provider.exists("test"));
+    assert!(provider.open("test").is_err());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn storage_mmap() {
+    let provider = MmapStorageProvider::new(Path::new("./test_output/storage_mmap"));
+    {
+        let mut storage = provider.create("test").unwrap();
+        check_raw_access(&mut storage);
+        storage.write_at(&[5u8; 2], 0).unwrap();
+        assert_eq!(storage.as_slice(), &[5u8, 5, 0, 0]);
+        assert_eq!(storage.capacity(), MIN_MAP_BYTE_SIZE);
+        storage.write_at(&[1u8], MIN_MAP_BYTE_SIZE).unwrap();
+        assert_eq!(storage.capacity(), 2 * MIN_MAP_BYTE_SIZE);
+        storage.set_len(4).unwrap();
+        storage.flush().unwrap();
+        storage.sync().unwrap();
+    }
+    let mut file = File::open(provider.path("test").unwrap()).unwrap();
+    let mut bytes = Vec::new();
+    ::std::io::Read::read_to_end(&mut file, &mut bytes).unwrap();
+    assert_eq!(bytes, vec!(5u8, 5, 0, 0));
+    provider.remove("test").unwrap();
+}
@@ -0,0 +1,456 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{OpenOptions};
+use std::io::{Cursor, Error, ErrorKind};
+use std::mem;
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::super::error::{ContainerError, Result};
+use super::blockfilecontainer::{BlockFileContainer, Block, Id};
+use super::header::{TRANSACTION_MAGIC};
+use super::wal::{WriteAheadLog, LogRecord};
+
+/// The payload that marks a transaction as committed.
+static COMMIT: &'static [u8] = &[];
+
+/// A record is logged with the name of its container, so the log does not depend on the order of the
+/// containers.
+fn encode(name: &str, record: &LogRecord) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.write_u32::<LittleEndian>(name.len() as u32).expect("can not write to a Vec");
+    payload.extend_from_slice(name.as_bytes());
+    record.encode_to(&mut payload);
+    payload
+}
+
+fn decode(payload: &[u8]) -> Result<(String, LogRecord<'static>)> {
+    if payload.len() < 4 {
+        return Err(ContainerError::CorruptLog);
+    }
+    let name_len = try!(Cursor::new(payload).read_u32::<LittleEndian>()) as usize;
+    if payload.len() < 4 + name_len {
+        return Err(ContainerError::CorruptLog);
+    }
+    let name = match String::from_utf8(payload[4 .. 4 + name_len].to_vec()) {
+        Ok(name) => name,
+        Err(_) => return Err(ContainerError::CorruptLog),
+    };
+    match LogRecord::decode(&payload[4 + name_len ..]) {
+        Some(record) => Ok((name, record)),
+        None => Err(ContainerError::CorruptLog),
+    }
+}
+
+/// Groups changes of one or more `BlockFileContainer`s, which are committed or aborted atomically.
+/// Containers are addressed by their position in the `Vec` passed to `begin`, the log refers to them by
+/// the names they are passed in with.
+///
+/// Updates and removes are kept in memory until `commit`, so the container files only ever hold committed
+/// blocks. Ids handed out by `reserve` are reserved in the container right away and are released again
+/// if the transaction is aborted or dropped without a commit.
+///
+/// The changes are written to a log file before they are applied. `begin` finishes a committed and rolls
+/// back an uncommitted transaction it finds in the log. Crash safety of the containers themselves needs
+/// their write-ahead log mode.
+pub struct Transaction<'a> {
+    log: WriteAheadLog,
+    names: Vec<String>,
+    containers: Vec<&'a mut BlockFileContainer>,
+    reserved: Vec<(usize, Id)>,
+    writes: Vec<BTreeMap<Id, Option<Block>>>,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    /// Starts a transaction logged to `log_path`. A transaction left in the log by a crash is finished
+    /// or rolled back first, so the containers it changed have to be passed in with the same names.
+    pub fn begin(log_path: &Path, containers: Vec<(&str, &'a mut BlockFileContainer)>) -> Result<Transaction<'a>> {
+        let (names, containers): (Vec<String>, Vec<_>) = containers.into_iter()
+            .map(|(name, container)| (name.to_string(), container))
+            .unzip();
+        if names.iter().enumerate().any(|(i, name)| names[..i].contains(name)) {
+            return Err(ContainerError::Io(Error::new(ErrorKind::InvalidInput, "the names of the containers are not distinct")));
+        }
+        let log = if log_path.exists() {
+            let file = try!(OpenOptions::new().read(true).write(true).open(log_path));
+            try!(WriteAheadLog::open(file, TRANSACTION_MAGIC))
+        }
+        else {
+            let file = try!(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(log_path));
+            try!(WriteAheadLog::new(file, TRANSACTION_MAGIC))
+        };
+        let writes = containers.iter().map(|_| BTreeMap::new()).collect();
+        let mut transaction = Transaction{log: log, names: names, containers: containers, reserved: Vec::new(), writes: writes, done: false};
+        if let Err(err) = transaction.recover() {
+            // The log is left as it is, so the next `begin` tries again.
+            transaction.done = true;
+            return Err(err);
+        }
+        Ok(transaction)
+    }
+
+    /// Reserves an id. The id is logged before the container reserves it, so a crash can not leak it.
+    pub fn reserve(&mut self, container: usize) -> Result<Id> {
+        let log = &mut self.log;
+        let name = &self.names[container];
+        let id = try!(self.containers[container].reserve_with(|id| {
+            try!(log.push(&encode(name, &LogRecord::Reserve(id))));
+            try!(log.sync());
+            Ok(())
+        }));
+        self.reserved.push((container, id));
+        Ok(id)
+    }
+
+    pub fn insert(&mut self, container: usize, element: Block) -> Result<Id> {
+        let id = try!(self.reserve(container));
+        let _ = try!(self.update(container, id, element));
+        Ok(id)
+    }
+
+    /// Returns the element as seen by this transaction.
+    pub fn get(&mut self, container: usize, id: Id) -> Result<Option<Block>> {
+        match self.writes[container].get(&id) {
+            Some(element) => Ok(element.clone()),
+            None => self.containers[container].get(id),
+        }
+    }
+
+    pub fn contains(&mut self, container: usize, id: Id) -> Result<bool> {
+        match self.writes[container].get(&id) {
+            Some(element) => Ok(element.is_some()),
+            None => self.containers[container].contains(id),
+        }
+    }
+
+    pub fn update(&mut self, container: usize, id: Id, element: Block) -> Result<Option<Block>> {
+        if element.len() > self.containers[container].block_size() {
+            return Err(ContainerError::Io(Error::new(ErrorKind::InvalidInput, "Block size exceeds the containers block_size")));
+        }
+        if !try!(self.contains(container, id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        let old = try!(self.get(container, id));
+        self.writes[container].insert(id, Some(element));
+        Ok(old)
+    }
+
+    pub fn remove(&mut self, container: usize, id: Id) -> Result<Option<Block>> {
+        if !try!(self.contains(container, id)) {
+            return Err(ContainerError::InvalidId);
+        }
+        let old = try!(self.get(container, id));
+        self.writes[container].insert(id, None);
+        Ok(old)
+    }
+
+    /// Logs all changes with a commit mark and applies them to the containers. Once the commit mark is
+    /// written, a failure leaves the log in place and the next `begin` applies the changes.
+    pub fn commit(mut self) -> Result<()> {
+        try!(self.write_log());
+        self.done = true;
+        let records = self.take_records();
+        try!(apply(&mut self.containers, records));
+        self.finish()
+    }
+
+    /// Discards all changes and releases the reserved ids.
+    pub fn abort(mut self) -> Result<()> {
+        try!(self.rollback());
+        self.finish()
+    }
+
+    fn write_log(&mut self) -> Result<()> {
+        for (container, writes) in self.writes.iter().enumerate() {
+            for (id, element) in writes {
+                let record = match *element {
+                    Some(ref element) => LogRecord::Update(*id, Cow::Borrowed(element)),
+                    None => LogRecord::Remove(*id),
+                };
+                try!(self.log.push(&encode(&self.names[container], &record)));
+            }
+        }
+        try!(self.log.push(COMMIT));
+        try!(self.log.sync());
+        Ok(())
+    }
+
+    fn take_records(&mut self) -> Vec<(usize, LogRecord<'static>)> {
+        let mut records = Vec::new();
+        for (container, writes) in self.writes.iter_mut().enumerate() {
+            for (id, element) in mem::replace(writes, BTreeMap::new()) {
+                let record = match element {
+                    Some(element) => LogRecord::Update(id, Cow::Owned(element)),
+                    None => LogRecord::Remove(id),
+                };
+                records.push((container, record));
+            }
+        }
+        records
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        for writes in &mut self.writes {
+            writes.clear();
+        }
+        // An id is only dropped once it is released, a failed rollback can be repeated.
+        while let Some(&(container, id)) = self.reserved.last() {
+            let container = &mut self.containers[container];
+            // Blocks are only written on commit, so an id that holds a block was released and reserved
+            // again by someone else after a crash and is kept.
+            if try!(container.contains(id)) && try!(container.get(id)).is_none() {
+                let _ = try!(container.remove(id));
+            }
+            let _ = self.reserved.pop();
+        }
+        Ok(())
+    }
+
+    /// Syncs the containers and empties the log. Only called once the logged changes are applied or
+    /// rolled back.
+    fn finish(&mut self) -> Result<()> {
+        for container in &mut self.containers {
+            try!(container.sync());
+        }
+        try!(self.log.truncate());
+        self.reserved.clear();
+        self.done = true;
+        Ok(())
+    }
+
+    fn recover(&mut self) -> Result<()> {
+        let payloads = try!(self.log.payloads());
+        if payloads.is_empty() {
+            return Ok(());
+        }
+        let committed = payloads.last().map(|payload| payload.is_empty()).unwrap_or(false);
+        let mut records = Vec::with_capacity(payloads.len());
+        for payload in payloads.iter().filter(|payload| !payload.is_empty()) {
+            let (name, record) = try!(decode(payload));
+            match self.names.iter().position(|known| *known == name) {
+                Some(container) => records.push((container, record)),
+                None => return Err(ContainerError::UnknownContainer(name)),
+            }
+        }
+
+        if committed {
+            try!(apply(&mut self.containers, records));
+        }
+        else {
+            for (container, record) in records {
+                if let LogRecord::Reserve(id) = record {
+                    self.reserved.push((container, id));
+                }
+            }
+            try!(self.rollback());
+        }
+        try!(self.finish());
+        self.done = false;
+        Ok(())
+    }
+}
+
+/// Applies logged changes. Applying them a second time leaves the containers unchanged.
+fn apply(containers: &mut [&mut BlockFileContainer], records: Vec<(usize, LogRecord)>) -> Result<()> {
+    for (container, record) in records {
+        let container = &mut containers[container];
+        match record {
+            LogRecord::Update(id, element) => {
+                let _ = try!(container.update(id, element.into_owned()));
+            },
+            LogRecord::Remove(id) => {
+                if try!(container.contains(id)) {
+                    let _ = try!(container.remove(id));
+                }
+            },
+            LogRecord::Reserve(_) | LogRecord::Clear => {},
+        }
+    }
+    Ok(())
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        // Errors can not be reported here. If the rollback fails, the log is kept and the next `begin`
+        // rolls the transaction back.
+        if !self.done && self.rollback().is_ok() {
+            let _ = self.finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use container::Container;
+    use io::blockfilecontainer::BlockFileContainer;
+
+    fn containers(name: &str) -> (BlockFileContainer, BlockFileContainer) {
+        let index = Path::new("./test_output").join(format!("{}_index", name));
+        let data = Path::new("./test_output").join(format!("{}_data", name));
+        let mut index = BlockFileContainer::new_from_prefix_and_block_size(&index, 4).unwrap();
+        let mut data = BlockFileContainer::new_from_prefix_and_block_size(&data, 4).unwrap();
+        index.enable_wal().unwrap();
+        data.enable_wal().unwrap();
+        (index, data)
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = Path::new("./test_output").join(format!("{}.txn", name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn commit() {
+        let (mut index, mut data) = containers("transaction_commit");
+        let old = data.insert(vec!(1u8,1,1,1)).unwrap();
+        let log = log_path("transaction_commit");
+        let (key, value);
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            value = transaction.insert(1, vec!(2u8,2,2,2)).unwrap();
+            key = transaction.insert(0, vec!(value as u8,0,0,0)).unwrap();
+            assert_eq!(transaction.remove(1, old).unwrap(), Some(vec!(1u8,1,1,1)));
+            assert_eq!(transaction.get(1, value).unwrap(), Some(vec!(2u8,2,2,2)));
+            assert_eq!(transaction.contains(1, old).unwrap(), false);
+            assert!(transaction.update(1, old, vec!(3u8)).is_err());
+            transaction.commit().unwrap();
+        }
+        assert_eq!(index.get(key).unwrap(), Some(vec!(value as u8,0,0,0)));
+        assert_eq!(data.get(value).unwrap(), Some(vec!(2u8,2,2,2)));
+        assert_eq!(data.contains(old).unwrap(), false);
+    }
+
+    #[test]
+    fn abort() {
+        let (mut index, mut data) = containers("transaction_abort");
+        let old = data.insert(vec!(1u8,1,1,1)).unwrap();
+        let log = log_path("transaction_abort");
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            let _ = transaction.insert(0, vec!(9u8,9,9,9)).unwrap();
+            let _ = transaction.update(1, old, vec!(2u8,2,2,2)).unwrap();
+            transaction.abort().unwrap();
+        }
+        assert_eq!(index.size(), 0);
+        assert_eq!(data.get(old).unwrap(), Some(vec!(1u8,1,1,1)));
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            let _ = transaction.insert(1, vec!(9u8,9,9,9)).unwrap();
+            let _ = transaction.remove(1, old).unwrap();
+        }
+        assert_eq!(data.size(), 1);
+        assert_eq!(data.get(old).unwrap(), Some(vec!(1u8,1,1,1)));
+    }
+
+    #[test]
+    fn recover_committed() {
+        let (mut index, mut data) = containers("transaction_recover_committed");
+        let log = log_path("transaction_recover_committed");
+        let (key, value);
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            value = transaction.insert(1, vec!(2u8,2,2,2)).unwrap();
+            key = transaction.insert(0, vec!(3u8,3,3,3)).unwrap();
+            transaction.write_log().unwrap();
+            mem::forget(transaction);
+        }
+        assert_eq!(data.get(value).unwrap(), None);
+        {
+            let transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            transaction.abort().unwrap();
+        }
+        assert_eq!(index.get(key).unwrap(), Some(vec!(3u8,3,3,3)));
+        assert_eq!(data.get(value).unwrap(), Some(vec!(2u8,2,2,2)));
+    }
+
+    #[test]
+    fn failed_recovery_keeps_the_log() {
+        let (mut index, mut data) = containers("transaction_failed_recovery");
+        let log = log_path("transaction_failed_recovery");
+        let value;
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            value = transaction.insert(1, vec!(2u8,2,2,2)).unwrap();
+            transaction.write_log().unwrap();
+            mem::forget(transaction);
+        }
+        match Transaction::begin(&log, vec!(("index", &mut index))) {
+            Err(ContainerError::UnknownContainer(ref name)) if name == "data" => (),
+            _ => panic!("recovered with a missing container"),
+        }
+        {
+            let transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            transaction.abort().unwrap();
+        }
+        assert_eq!(data.get(value).unwrap(), Some(vec!(2u8,2,2,2)));
+    }
+
+    #[test]
+    fn recover_uncommitted() {
+        let (mut index, mut data) = containers("transaction_recover_uncommitted");
+        let log = log_path("transaction_recover_uncommitted");
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            let _ = transaction.insert(1, vec!(2u8,2,2,2)).unwrap();
+            let _ = transaction.reserve(0).unwrap();
+            mem::forget(transaction);
+        }
+        assert_eq!(data.size(), 1);
+        {
+            let _ = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+        }
+        assert_eq!(index.size(), 0);
+        assert_eq!(data.size(), 0);
+    }
+
+    #[test]
+    fn recover_in_another_order() {
+        let (mut index, mut data) = containers("transaction_recover_order");
+        let log = log_path("transaction_recover_order");
+        let value;
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            value = transaction.insert(1, vec!(2u8,2,2,2)).unwrap();
+            transaction.write_log().unwrap();
+            mem::forget(transaction);
+        }
+        {
+            let transaction = Transaction::begin(&log, vec!(("data", &mut data), ("index", &mut index))).unwrap();
+            transaction.abort().unwrap();
+        }
+        assert_eq!(index.size(), 0);
+        assert_eq!(data.get(value).unwrap(), Some(vec!(2u8,2,2,2)));
+    }
+
+    #[test]
+    fn rollback_keeps_reused_ids() {
+        let (mut index, mut data) = containers("transaction_reused_ids");
+        let log = log_path("transaction_reused_ids");
+        let id;
+        {
+            let mut transaction = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+            id = transaction.reserve(1).unwrap();
+            mem::forget(transaction);
+        }
+        // The id is released and handed out again before the log is recovered.
+        assert_eq!(data.remove(id).unwrap(), None);
+        assert_eq!(data.insert(vec!(5u8,5,5,5)).unwrap(), id);
+        {
+            let _ = Transaction::begin(&log, vec!(("index", &mut index), ("data", &mut data))).unwrap();
+        }
+        assert_eq!(data.get(id).unwrap(), Some(vec!(5u8,5,5,5)));
+    }
+
+    #[test]
+    fn distinct_names() {
+        let (mut index, mut data) = containers("transaction_distinct_names");
+        let log = log_path("transaction_distinct_names");
+        assert!(Transaction::begin(&log, vec!(("index", &mut index), ("index", &mut data))).is_err());
+    }
+}
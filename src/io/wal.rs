@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+use std::fs::{File};
+use std::io::{Cursor, Result as IoResult};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::super::error::{ContainerError, Result};
+use super::header::{self, HEADER_BYTE_SIZE};
+use super::storage::RawAccess;
+
+/// The length of a record followed by its checksum.
+static RECORD_FRAME_BYTE_SIZE: u64 = 4 + 8;
+
+/// A logged change of a `BlockFileContainer`. Every record describes the state after the change instead of
+/// the change itself, so replaying a record that already reached the container files is harmless.
+#[derive(Debug, PartialEq)]
+pub enum LogRecord<'a> {
+    /// The id is reserved.
+    Reserve(u64),
+    /// The id is reserved and used and its block holds the data.
+    Update(u64, Cow<'a, [u8]>),
+    /// The id is neither reserved nor used.
+    Remove(u64),
+    /// The container is empty.
+    Clear,
+}
+
+impl<'a> LogRecord<'a> {
+    pub fn encode_to(&self, payload: &mut Vec<u8>) {
+        let (kind, id, data): (u8, u64, &[u8]) = match *self {
+            LogRecord::Reserve(id) => (0, id, &[]),
+            LogRecord::Update(id, ref data) => (1, id, data),
+            LogRecord::Remove(id) => (2, id, &[]),
+            LogRecord::Clear => (3, 0, &[]),
+        };
+        payload.push(kind);
+        payload.write_u64::<LittleEndian>(id).expect("can not write to a Vec");
+        payload.extend_from_slice(data);
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<LogRecord<'static>> {
+        if payload.len() < 1 + 8 {
+            return None;
+        }
+        let id = Cursor::new(&payload[1..]).read_u64::<LittleEndian>().expect("payload holds an id");
+        match payload[0] {
+            0 => Some(LogRecord::Reserve(id)),
+            1 => Some(LogRecord::Update(id, Cow::Owned(payload[1 + 8..].to_vec()))),
+            2 => Some(LogRecord::Remove(id)),
+            3 => Some(LogRecord::Clear),
+            _ => None,
+        }
+    }
+}
+
+/// FNV-1a, used to detect records that were only partially written.
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// An append-only log of the changes that are about to be applied to a `BlockFileContainer`.
+/// A record is on disc before `append` returns. The payloads of other logs, e.g. the one of a
+/// `Transaction`, are written with `push` and `sync`.
+#[derive(Debug)]
+pub struct WriteAheadLog<S: RawAccess = File> {
+    file: S,
+    len: u64,
+}
+
+impl<S: RawAccess> WriteAheadLog<S> {
+    pub fn new(mut file: S, magic: &[u8; 4]) -> IoResult<Self> {
+        try!(header::write_header(&mut file, magic));
+        try!(file.set_len(HEADER_BYTE_SIZE));
+        try!(file.sync());
+        Ok(WriteAheadLog{file: file, len: 0})
+    }
+
+    pub fn open(file: S, magic: &[u8; 4]) -> Result<Self> {
+        try!(header::read_header(&file, magic));
+        let len = try!(file.len()) - HEADER_BYTE_SIZE;
+        Ok(WriteAheadLog{file: file, len: len})
+    }
+
+    /// The number of bytes of all logged records.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Appends records and syncs the log once.
+    pub fn append_all(&mut self, records: &[LogRecord]) -> IoResult<()> {
+        let mut payload = Vec::new();
+        for record in records {
+            payload.clear();
+            record.encode_to(&mut payload);
+            try!(self.push(&payload));
+        }
+        self.sync()
+    }
+
+    /// Appends a payload without syncing the log.
+    pub fn push(&mut self, payload: &[u8]) -> IoResult<()> {
+        let mut frame = Vec::with_capacity(RECORD_FRAME_BYTE_SIZE as usize + payload.len());
+        try!(frame.write_u32::<LittleEndian>(payload.len() as u32));
+        frame.extend_from_slice(payload);
+        try!(frame.write_u64::<LittleEndian>(checksum(payload)));
+        try!(self.file.write_at(&frame, HEADER_BYTE_SIZE + self.len));
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> IoResult<()> {
+        self.file.sync()
+    }
+
+    /// Reads all complete records. A torn record at the end of the log is cut off.
+    pub fn records(&mut self) -> Result<Vec<LogRecord<'static>>> {
+        let payloads = try!(self.payloads());
+        let mut records = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            match LogRecord::decode(&payload) {
+                Some(record) => records.push(record),
+                None => return Err(ContainerError::CorruptLog),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Reads the payloads of all complete records. A torn record at the end of the log is cut off.
+    pub fn payloads(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut bytes = vec![0u8; (try!(self.file.len()) - HEADER_BYTE_SIZE) as usize];
+        try!(self.file.read_exact_at(&mut bytes, HEADER_BYTE_SIZE));
+
+        let mut payloads = Vec::new();
+        let mut position = 0usize;
+        while position + RECORD_FRAME_BYTE_SIZE as usize <= bytes.len() {
+            let len = Cursor::new(&bytes[position..]).read_u32::<LittleEndian>().expect("frame holds a length") as usize;
+            let end = position + RECORD_FRAME_BYTE_SIZE as usize + len;
+            if end > bytes.len() {
+                break;
+            }
+            let payload = &bytes[position + 4 .. position + 4 + len];
+            let stored = Cursor::new(&bytes[position + 4 + len ..]).read_u64::<LittleEndian>().expect("frame holds a checksum");
+            if stored != checksum(payload) {
+                break;
+            }
+            payloads.push(payload.to_vec());
+            position = end;
+        }
+
+        if position as u64 != self.len {
+            try!(self.file.set_len(HEADER_BYTE_SIZE + position as u64));
+            try!(self.file.sync());
+            self.len = position as u64;
+        }
+        Ok(payloads)
+    }
+
+    /// Drops all records. Called once their changes are on disc.
+    pub fn truncate(&mut self) -> IoResult<()> {
+        try!(self.file.set_len(HEADER_BYTE_SIZE));
+        try!(self.file.sync());
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[test]
+fn wal_records() {
+    use std::path::Path;
+    use super::header::WAL_MAGIC;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/wal_records.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut wal = WriteAheadLog::new(file, WAL_MAGIC).unwrap();
+    assert_eq!(wal.len(), 0);
+    wal.append_all(&[LogRecord::Reserve(3), LogRecord::Update(3, Cow::Borrowed(&[1u8, 2, 3]))]).unwrap();
+    wal.append_all(&[LogRecord::Remove(3), LogRecord::Clear]).unwrap();
+    let len = wal.len();
+
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let mut wal = WriteAheadLog::open(file, WAL_MAGIC).unwrap();
+    assert_eq!(wal.len(), len);
+    assert_eq!(wal.records().unwrap(), vec!(LogRecord::Reserve(3),
+                                            LogRecord::Update(3, Cow::Owned(vec!(1u8, 2, 3))),
+                                            LogRecord::Remove(3),
+                                            LogRecord::Clear));
+    wal.truncate().unwrap();
+    assert!(wal.records().unwrap().is_empty());
+    remove_file(&path).unwrap();
+}
+
+#[test]
+fn wal_torn_record() {
+    use std::path::Path;
+    use super::header::WAL_MAGIC;
+    use std::fs::{OpenOptions, remove_file};
+
+    let path = Path::new("./test_output/wal_torn_record.test");
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).expect("Test file not created");
+    let mut wal = WriteAheadLog::new(file, WAL_MAGIC).unwrap();
+    wal.append_all(&[LogRecord::Reserve(1)]).unwrap();
+    let len = wal.len();
+    wal.append_all(&[LogRecord::Update(1, Cow::Borrowed(&[7u8; 16]))]).unwrap();
+    wal.file.set_len(HEADER_BYTE_SIZE + wal.len() - 3).unwrap();
+
+    let file = OpenOptions::new().read(true).write(true).open(&path).expect("Test file not opened");
+    let mut wal = WriteAheadLog::open(file, WAL_MAGIC).unwrap();
+    assert_eq!(wal.records().unwrap(), vec!(LogRecord::Reserve(1)));
+    assert_eq!(wal.len(), len);
+    remove_file(&path).unwrap();
+}
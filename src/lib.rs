@@ -1,25 +1,78 @@
 
 extern crate byteorder;
-extern crate bincode;
 
-#[cfg(feature = "rustc_serialize")]
+#[cfg(feature = "rustc-serialize")]
 extern crate rustc_serialize;
 
+#[cfg(feature = "rustc-serialize")]
+extern crate bincode_legacy;
+
 #[cfg(feature = "serde")]
 extern crate serde;
 
+#[cfg(feature = "serde")]
+extern crate bincode;
+
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+
+#[cfg(feature = "json")]
+extern crate serde_json;
+
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+
+#[cfg(feature = "compression")]
+extern crate miniz_oxide;
+
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+
 pub mod container;
 pub mod error;
 pub mod io;
 pub mod mem {
     pub mod veccontainer;
 }
+pub mod concurrent {
+    pub mod asynccontainer;
+    pub mod blockfilecontainer;
+    pub mod sharedcontainer;
+}
+pub mod buffer {
+    pub mod bufferedcontainer;
+    pub mod replacement;
+}
+#[cfg(feature = "serde")]
+pub mod index {
+    pub mod bplustree;
+}
+#[cfg(feature = "serde")]
+pub mod operator {
+    pub mod externalsort;
+}
 pub mod adapter {
     pub mod converter;
 
-    #[cfg(feature = "rustc_serialize")]
+    #[cfg(feature = "rustc-serialize")]
     pub mod serialize;
 
+    #[cfg(feature = "serde")]
+    pub mod codec;
+
     #[cfg(feature = "serde")]
     pub mod serde;
+
+    #[cfg(feature = "serde")]
+    pub mod versioned;
+
+    #[cfg(feature = "compression")]
+    pub mod compression;
 }
@@ -1,4 +1,4 @@
-use container::{Container, CloneContainer};
+use container::{Container, CloneContainer, SharedReadContainer, EntryContainer, Entry, VacantEntry, OccupiedEntry};
 use std::{mem};
 use error::{Result, ContainerError};
 
@@ -153,25 +153,49 @@ impl <'a, E:'a> CloneContainer<'a, E> for VecContainer<E> where E: Clone {
     }
 }
 
+impl<E> SharedReadContainer for VecContainer<E> where E: Clone {
+    type I = Id;
+    type E = E;
+
+    fn get_shared(&self, id: Id) -> Result<Option<E>> {
+        Ok(self.get(id).cloned())
+    }
+
+    fn contains_shared(&self, id: Id) -> Result<bool> {
+        Ok(self.contains_element(id))
+    }
+}
+
+pub type VecContainerEntry<'a, E> = Entry<OccupiedVecContainerEntry<'a, E>, VacantVecContainerEntry<'a, E>>;
 
-/*
-pub struct VecContainerEntry<'a, E: 'a> {
+#[derive(Debug)]
+pub struct VacantVecContainerEntry<'a, E: 'a> {
     container: &'a mut VecContainer<E>,
     element_id: Id,
 }
 
-impl<'a, E> VacantEntry for VecContainerEntry<'a, E> {
+impl<'a, E> VacantEntry for VacantVecContainerEntry<'a, E> {
     type Id = Id;
     type Value = E;
+    type OccupiedEntry = OccupiedVecContainerEntry<'a, E>;
 
     fn id(&self) -> Id {
         self.element_id
     }
 
-    fn insert(self, value: Self::Value) {}
+    fn insert(self, value: E) -> Result<OccupiedVecContainerEntry<'a, E>> {
+        self.container.vec[self.element_id] = Slot::Occupied(value);
+        Ok(OccupiedVecContainerEntry{container: self.container, element_id: self.element_id})
+    }
+}
+
+#[derive(Debug)]
+pub struct OccupiedVecContainerEntry<'a, E: 'a> {
+    container: &'a mut VecContainer<E>,
+    element_id: Id,
 }
 
-impl<'a, E> OccupiedEntry for VecContainerEntry<'a, E> {
+impl<'a, E> OccupiedEntry for OccupiedVecContainerEntry<'a, E> {
     type Id = Id;
     type Value = E;
 
@@ -179,21 +203,38 @@ impl<'a, E> OccupiedEntry for VecContainerEntry<'a, E> {
         self.element_id
     }
 
-    fn get(&self) -> &Self::Value {
-        self.container.vec[self.element_id].as_ref().unwrap()
+    fn get(&self) -> &E {
+        self.container.get(self.element_id).expect("OccupiedEntry contained no value")
+    }
+
+    fn modify<F>(&mut self, f: F) -> Result<()> where F: FnOnce(&mut E) {
+        f(self.container.get_mut(self.element_id).expect("OccupiedEntry contained no value"));
+        Ok(())
     }
-    // fn get_mut(&mut self) -> &mut V;
-    // fn into_mut(self) -> &'a mut V;
-    fn update(&mut self, value: Self::Value) -> Self::Value {
-        let mut temp = Some(value);
-        mem::swap(&mut temp, &mut self.container.vec[self.element_id]);
-        temp.expect("OccupiedEntry contained no value")
+
+    fn update(&mut self, value: E) -> Result<E> {
+        let element = self.container.get_mut(self.element_id).expect("OccupiedEntry contained no value");
+        Ok(mem::replace(element, value))
+    }
+
+    fn remove(self) -> Result<E> {
+        let element = try!(self.container.remove(self.element_id));
+        Ok(element.expect("OccupiedEntry contained no value"))
     }
-    fn remove(self) -> Self::Value {
-        self.container.vec.remove(self.element_id).unwrap()
+}
+
+impl<'a, E: 'a> EntryContainer<'a, E> for VecContainer<E> {
+    type VacantEntry = VacantVecContainerEntry<'a, E>;
+    type OccupiedEntry = OccupiedVecContainerEntry<'a, E>;
+
+    fn entry(&'a mut self, id: Id) -> Result<VecContainerEntry<'a, E>> {
+        match self.vec.get(id) {
+            Some(&Slot::Occupied(_)) => Ok(Entry::Occupied(OccupiedVecContainerEntry{container: self, element_id: id})),
+            Some(&Slot::Reserved) => Ok(Entry::Vacant(VacantVecContainerEntry{container: self, element_id: id})),
+            _ => Err(ContainerError::InvalidId),
+        }
     }
 }
-*/
 
 //
 #[cfg(test)]
@@ -242,4 +283,31 @@ mod tests {
         assert_eq!(2, vec_c.vec.len());
     }
 
+    #[test]
+    fn entry() {
+        use container::{Container, EntryContainer, Entry, OccupiedEntry};
+        let mut vec_c = VecContainer::with_capacity(10);
+        let id = vec_c.reserve().unwrap();
+        match vec_c.entry(id).unwrap() {
+            Entry::Vacant(_) => {},
+            Entry::Occupied(_) => panic!("reserved id is occupied"),
+        }
+        assert_eq!(*vec_c.entry(id).unwrap().or_insert(1.1).unwrap().get(), 1.1);
+        assert_eq!(*vec_c.entry(id).unwrap().or_insert(2.2).unwrap().get(), 1.1);
+        assert_eq!(vec_c.get(id), Some(&1.1));
+
+        {
+            let mut entry = vec_c.entry(id).unwrap().and_modify(|e| *e += 1.0).unwrap().or_insert(0.0).unwrap();
+            assert_eq!(*entry.get(), 2.1);
+            assert_eq!(entry.update(3.3).unwrap(), 2.1);
+            assert_eq!(entry.remove().unwrap(), 3.3);
+        }
+        assert_eq!(vec_c.contains(id).unwrap(), false);
+        assert!(vec_c.entry(id).is_err());
+
+        let other = vec_c.reserve().unwrap();
+        let entry = vec_c.entry(other).unwrap().or_insert_with(|| 4.4).unwrap();
+        assert_eq!(*entry.get(), 4.4);
+    }
+
 }
@@ -0,0 +1,247 @@
+use serde;
+
+use std::cmp::Ordering;
+use std::{mem, vec};
+
+use container::{Container, CloneContainer};
+use error::{ContainerError, Result};
+use adapter::serde::SerdeAdapter;
+use io::blockfilecontainer::Id;
+
+/// A sorted run stored in consecutive ids of a container.
+#[derive(Debug, Clone, Copy)]
+struct Run {
+    first: Id,
+    len: u64,
+}
+
+/// Merges up to `fan_in` runs. On equal elements the run that was written first wins, which keeps the sort stable.
+struct Merger<E> {
+    runs: Vec<Run>,
+    heads: Vec<Option<E>>,
+}
+
+impl<E> Merger<E> {
+    fn new<'a, C>(mut runs: Vec<Run>, container: &mut C) -> Result<Self> where C: CloneContainer<'a, E, I=Id> {
+        let mut heads = Vec::with_capacity(runs.len());
+        for run in &mut runs {
+            heads.push(try!(read_next(run, container)));
+        }
+        Ok(Merger{runs: runs, heads: heads})
+    }
+
+    fn next<'a, C, F>(&mut self, container: &mut C, compare: &F) -> Result<Option<E>> where C: CloneContainer<'a, E, I=Id>, F: Fn(&E, &E) -> Ordering {
+        let mut min: Option<usize> = None;
+        for (index, head) in self.heads.iter().enumerate() {
+            if let Some(ref element) = *head {
+                min = match min {
+                    Some(min_index) if compare(self.heads[min_index].as_ref().expect("head is set"), element) != Ordering::Greater => Some(min_index),
+                    _ => Some(index),
+                };
+            }
+        }
+        match min {
+            Some(index) => {
+                let element = self.heads[index].take();
+                self.heads[index] = try!(read_next(&mut self.runs[index], container));
+                Ok(element)
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn read_next<'a, E, C>(run: &mut Run, container: &mut C) -> Result<Option<E>> where C: CloneContainer<'a, E, I=Id> {
+    if run.len == 0 {
+        return Ok(None);
+    }
+    match try!(container.get_clone(run.first)) {
+        Some(element) => {
+            run.first += 1;
+            run.len -= 1;
+            Ok(Some(element))
+        },
+        None => Err(ContainerError::InvalidId),
+    }
+}
+
+/// Sorts inputs that do not fit into memory. Whenever `memory_budget` elements are buffered, they are
+/// sorted and spilled as a run into a container. The runs are merged `fan_in` at a time until at most
+/// `fan_in` runs are left, which are merged while iterating over the output.
+///
+/// Two containers are used alternately for the runs of a merge pass. Both have to hand out consecutive
+/// ids after `clear`, like `BlockFileContainer` does, otherwise the sort fails with `NotConsecutive`. Each
+/// serialized element has to fit into them.
+pub struct ExternalSort<E, F, C> {
+    compare: F,
+    memory_budget: usize,
+    fan_in: usize,
+    runs: SerdeAdapter<E, C>,
+    merged: SerdeAdapter<E, C>,
+}
+
+impl<'a, E, F, C> ExternalSort<E, F, C> where E: serde::ser::Serialize + serde::de::DeserializeOwned, F: Fn(&E, &E) -> Ordering, C: CloneContainer<'a, Vec<u8>, I=Id> {
+    /// Creates a sort operator. `memory_budget` is the number of elements buffered in memory.
+    pub fn new(memory_budget: usize, fan_in: usize, compare: F, runs: C, merged: C) -> Self {
+        assert!(memory_budget > 0, "the memory budget has to hold at least one element");
+        assert!(fan_in >= 2, "a merge needs a fan-in of at least two");
+        ExternalSort {
+            compare: compare,
+            memory_budget: memory_budget,
+            fan_in: fan_in,
+            runs: SerdeAdapter::new(runs),
+            merged: SerdeAdapter::new(merged),
+        }
+    }
+
+    /// Sorts `input` and returns an iterator over the sorted elements. The sort is stable.
+    pub fn sort<I>(mut self, input: I) -> Result<Sorted<E, F, C>> where I: IntoIterator<Item=E> {
+        try!(self.runs.clear());
+        let mut buffer = Vec::with_capacity(self.memory_budget);
+        let mut runs = Vec::new();
+        for element in input {
+            buffer.push(element);
+            if buffer.len() == self.memory_budget {
+                runs.push(try!(self.spill(&mut buffer)));
+            }
+        }
+        if runs.is_empty() {
+            buffer.sort_by(|a, b| (self.compare)(a, b));
+            return Ok(Sorted{inner: SortedInner::Memory(buffer.into_iter())});
+        }
+        if !buffer.is_empty() {
+            runs.push(try!(self.spill(&mut buffer)));
+        }
+
+        while runs.len() > self.fan_in {
+            try!(self.merged.clear());
+            let mut merged_runs = Vec::with_capacity(runs.len().div_ceil(self.fan_in));
+            for group in runs.chunks(self.fan_in) {
+                let mut merger = try!(Merger::new(group.to_vec(), &mut self.runs));
+                let mut run = Run{first: 0, len: 0};
+                while let Some(element) = try!(merger.next(&mut self.runs, &self.compare)) {
+                    let id = try!(self.merged.insert(element));
+                    run = try!(append(run, id));
+                }
+                merged_runs.push(run);
+            }
+            mem::swap(&mut self.runs, &mut self.merged);
+            runs = merged_runs;
+        }
+
+        let merger = try!(Merger::new(runs, &mut self.runs));
+        Ok(Sorted{inner: SortedInner::Merge(merger, self.runs, self.compare)})
+    }
+
+    fn spill(&mut self, buffer: &mut Vec<E>) -> Result<Run> {
+        buffer.sort_by(|a, b| (self.compare)(a, b));
+        let mut run = Run{first: 0, len: 0};
+        for element in buffer.drain(..) {
+            let id = try!(self.runs.insert(element));
+            run = try!(append(run, id));
+        }
+        Ok(run)
+    }
+}
+
+fn append(run: Run, id: Id) -> Result<Run> {
+    if run.len == 0 {
+        return Ok(Run{first: id, len: 1});
+    }
+    if id != run.first + run.len {
+        return Err(ContainerError::NotConsecutive);
+    }
+    Ok(Run{first: run.first, len: run.len + 1})
+}
+
+enum SortedInner<E, F, C> {
+    Memory(vec::IntoIter<E>),
+    Merge(Merger<E>, SerdeAdapter<E, C>, F),
+}
+
+/// The sorted output of an `ExternalSort`. A run that can not be read ends the iteration with an error.
+pub struct Sorted<E, F, C> {
+    inner: SortedInner<E, F, C>,
+}
+
+impl<'a, E, F, C> Iterator for Sorted<E, F, C> where E: serde::ser::Serialize + serde::de::DeserializeOwned, F: Fn(&E, &E) -> Ordering, C: CloneContainer<'a, Vec<u8>, I=Id> {
+    type Item = Result<E>;
+
+    fn next(&mut self) -> Option<Result<E>> {
+        let result = match self.inner {
+            SortedInner::Memory(ref mut elements) => return elements.next().map(Ok),
+            SortedInner::Merge(ref mut merger, ref mut container, ref compare) => merger.next(container, compare),
+        };
+        match result {
+            Ok(element) => element.map(Ok),
+            Err(err) => {
+                self.inner = SortedInner::Memory(Vec::new().into_iter());
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use io::blockfilecontainer::BlockFileContainer;
+
+    fn containers(name: &str) -> (BlockFileContainer, BlockFileContainer) {
+        let runs = Path::new("./test_output").join(format!("{}_runs", name));
+        let merged = Path::new("./test_output").join(format!("{}_merged", name));
+        (BlockFileContainer::new_from_prefix_and_block_size(&runs, 32).unwrap(),
+         BlockFileContainer::new_from_prefix_and_block_size(&merged, 32).unwrap())
+    }
+
+    fn permutation(n: u64) -> Vec<u64> {
+        (0 .. n).map(|i| (i * 7919) % n).collect()
+    }
+
+    #[test]
+    fn in_memory() {
+        let (runs, merged) = containers("externalsort_in_memory");
+        let sort = ExternalSort::new(100, 2, |a: &u64, b: &u64| a.cmp(b), runs, merged);
+        let sorted: Vec<u64> = sort.sort(permutation(50)).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(sorted, (0 .. 50).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn single_merge() {
+        let (runs, merged) = containers("externalsort_single_merge");
+        let sort = ExternalSort::new(100, 16, |a: &u64, b: &u64| b.cmp(a), runs, merged);
+        let sorted: Vec<u64> = sort.sort(permutation(1000)).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(sorted, (0 .. 1000).rev().collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn multiple_passes() {
+        let (runs, merged) = containers("externalsort_multiple_passes");
+        let sort = ExternalSort::new(10, 3, |a: &u64, b: &u64| a.cmp(b), runs, merged);
+        let sorted: Vec<u64> = sort.sort(permutation(1000)).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(sorted, (0 .. 1000).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn stable() {
+        let (runs, merged) = containers("externalsort_stable");
+        let input: Vec<(u64, u64)> = permutation(500).into_iter().enumerate().map(|(position, key)| (key % 7, position as u64)).collect();
+        let sort = ExternalSort::new(16, 4, |a: &(u64, u64), b: &(u64, u64)| a.0.cmp(&b.0), runs, merged);
+        let sorted: Vec<(u64, u64)> = sort.sort(input.clone()).unwrap().collect::<Result<_>>().unwrap();
+        let mut expected = input;
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn consecutive_ids() {
+        let run = append(Run{first: 0, len: 0}, 4).unwrap();
+        let run = append(run, 5).unwrap();
+        assert_eq!((run.first, run.len), (4, 2));
+        match append(run, 7) {
+            Err(ContainerError::NotConsecutive) => {},
+            _ => panic!("appended a gap to a run"),
+        }
+    }
+}